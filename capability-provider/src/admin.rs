@@ -0,0 +1,573 @@
+//! # Admin API
+//! Operational requests served over NATS request/reply on `cc.admin.{operation}.{target}` subjects. Replies
+//! are JSON objects with a `success` flag and either a `result` or an `error`
+
+use async_nats::{
+    jetstream::{consumer::DeliverPolicy, Context},
+    Message,
+};
+use chrono::{DateTime, TimeZone, Utc};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, warn, Instrument};
+
+use crate::{
+    config::{ActorRole, InterestConstraint, InterestDeclaration},
+    consumers::ConsumerManager,
+    deadletter::{DeadLetterQueue, StoredDeadLetter},
+    natsclient::Namespace,
+    rehydrate::{rebuild_aggregate, RebuildSummary},
+    state::EntityState,
+    Result,
+};
+use wasmbus_rpc::error::RpcError;
+
+pub(crate) const ADMIN_TOPIC_PREFIX: &str = "cc.admin";
+/// Each admin request must only be handled once, however many provider instances are running
+const ADMIN_QUEUE_GROUP: &str = "concordance_admin";
+/// Requests every provider instance handles on behalf of the one serving an admin request, e.g.
+/// `cc.control.pause.PROJ_bankaccount_MAEYUH6M`
+pub(crate) const CONTROL_TOPIC_PREFIX: &str = "cc.control";
+/// Pauses a consumer's workers while another provider instance resets its durable consumer
+const PAUSE_OPERATION: &str = "pause";
+/// Resumes the workers paused for a reset
+const RESUME_OPERATION: &str = "resume";
+/// How long paused workers wait to be resumed before resuming on their own
+const PAUSE_LEASE: Duration = Duration::from_secs(30);
+/// How long to keep waiting for another provider instance to report that it paused a consumer
+const PAUSE_REPLY_WAIT: Duration = Duration::from_secs(1);
+
+/// Rebuilds all state for an aggregate from its events, e.g. `cc.admin.rebuild_state.bankaccount`
+const REBUILD_STATE_OPERATION: &str = "rebuild_state";
+/// Replays events to a projector, e.g. `cc.admin.replay.bankaccount` or `cc.admin.replay.PROJ_bankaccount_MAEYUH6M`
+const REPLAY_OPERATION: &str = "replay";
+/// Lists dead letters, e.g. `cc.admin.list_dead_letters.all` or `cc.admin.list_dead_letters.AGG_CMD_bankaccount`
+const LIST_DEAD_LETTERS_OPERATION: &str = "list_dead_letters";
+/// Returns a dead letter along with its payload, e.g. `cc.admin.get_dead_letter.42`
+const GET_DEAD_LETTER_OPERATION: &str = "get_dead_letter";
+/// Hands a dead letter to its consumer's worker again, removing it if the worker succeeds, e.g.
+/// `cc.admin.replay_dead_letter.42`
+const REPLAY_DEAD_LETTER_OPERATION: &str = "replay_dead_letter";
+/// Removes a dead letter, e.g. `cc.admin.discard_dead_letter.42`
+const DISCARD_DEAD_LETTER_OPERATION: &str = "discard_dead_letter";
+/// Target for listing the dead letters of every consumer
+const ALL_CONSUMERS: &str = "all";
+
+/// Where a replay starts. With neither field set, the replay starts from the beginning of the event stream
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ReplayRequest {
+    /// Event stream sequence to start from
+    start_sequence: Option<u64>,
+    /// RFC 3339 timestamp to start from, e.g. `2023-06-01T00:00:00Z`
+    start_time: Option<String>,
+}
+
+impl ReplayRequest {
+    fn deliver_policy(&self) -> Result<DeliverPolicy> {
+        match (self.start_sequence, self.start_time.as_deref()) {
+            (None, None) => Ok(DeliverPolicy::All),
+            (Some(start_sequence), None) => Ok(DeliverPolicy::ByStartSequence { start_sequence }),
+            (None, Some(start_time)) => {
+                let parsed = DateTime::parse_from_rfc3339(start_time).map_err(|e| {
+                    RpcError::InvalidParameter(format!("Invalid start_time '{start_time}': {e}"))
+                })?;
+                let start_time = time::OffsetDateTime::from_unix_timestamp_nanos(
+                    parsed.timestamp_nanos() as i128,
+                )
+                .map_err(|e| {
+                    RpcError::InvalidParameter(format!("Invalid start_time '{start_time}': {e}"))
+                })?;
+                Ok(DeliverPolicy::ByStartTime { start_time })
+            }
+            (Some(_), Some(_)) => Err(RpcError::InvalidParameter(
+                "Only one of start_sequence and start_time can be given".to_string(),
+            )),
+        }
+    }
+}
+
+/// Where the reset consumer actually starts, which is the start of the event stream when neither field is set
+#[derive(Debug, PartialEq, Serialize)]
+struct ReplaySummary {
+    /// The durable consumer that was reset
+    consumer: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    start_sequence: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    start_time: Option<String>,
+}
+
+impl ReplaySummary {
+    fn new(consumer: String, deliver_policy: DeliverPolicy) -> ReplaySummary {
+        let (start_sequence, start_time) = match deliver_policy {
+            DeliverPolicy::ByStartSequence { start_sequence } => (Some(start_sequence), None),
+            DeliverPolicy::ByStartTime { start_time } => (
+                None,
+                Some(
+                    Utc.timestamp_nanos(start_time.unix_timestamp_nanos() as i64)
+                        .to_rfc3339(),
+                ),
+            ),
+            _ => (None, None),
+        };
+        ReplaySummary {
+            consumer,
+            start_sequence,
+            start_time,
+        }
+    }
+}
+
+/// Identifies the provider instance that sent a control request, which handles the request for itself
+#[derive(Debug, Serialize, Deserialize)]
+struct ControlSender {
+    instance: String,
+}
+
+/// A dead letter along with its original payload
+#[derive(Debug, Serialize)]
+struct DeadLetterDetail {
+    #[serde(flatten)]
+    letter: StoredDeadLetter,
+    payload: serde_json::Value,
+}
+
+#[derive(Debug, Serialize)]
+struct AdminReply<T: Serialize> {
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<T>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl<T: Serialize> From<Result<T>> for AdminReply<T> {
+    fn from(res: Result<T>) -> Self {
+        match res {
+            Ok(result) => AdminReply {
+                success: true,
+                result: Some(result),
+                error: None,
+            },
+            Err(e) => AdminReply {
+                success: false,
+                result: None,
+                error: Some(e.to_string()),
+            },
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct AdminService {
+    pub nc: async_nats::Client,
+    pub js: Context,
+    pub namespace: Namespace,
+    pub state: EntityState,
+    pub consumer_manager: ConsumerManager,
+    pub dead_letters: DeadLetterQueue,
+    /// Tells this provider instance's control requests apart from those of other instances
+    pub instance: String,
+}
+
+impl AdminService {
+    /// Subscribes to the admin subjects and handles each request in its own task, since operations such as
+    /// rebuilding state can take a while. Only one provider instance handles and replies to each request, while
+    /// every instance handles the control requests that instance sends to the others
+    pub(crate) async fn serve(self) -> Result<JoinHandle<()>> {
+        let subject = format!("{}.>", self.namespace.admin_subject_prefix());
+        let mut sub = self
+            .nc
+            .queue_subscribe(subject.clone(), ADMIN_QUEUE_GROUP.to_string())
+            .await
+            .map_err(|e| RpcError::Nats(format!("Failed to subscribe to {subject}: {e}")))?;
+        let control_subject = format!("{}.>", self.namespace.control_subject_prefix());
+        let mut control = self
+            .nc
+            .subscribe(control_subject.clone())
+            .await
+            .map_err(|e| {
+                RpcError::Nats(format!("Failed to subscribe to {control_subject}: {e}"))
+            })?;
+        info!("Serving admin requests on {subject}");
+        Ok(tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    Some(msg) = sub.next() => {
+                        let admin = self.clone();
+                        let span = tracing::info_span!("admin_request", subject = %msg.subject);
+                        tokio::spawn(async move { admin.handle(msg).await }.instrument(span));
+                    }
+                    Some(msg) = control.next() => {
+                        let admin = self.clone();
+                        let span = tracing::info_span!("control_request", subject = %msg.subject);
+                        tokio::spawn(async move { admin.handle_control(msg).await }.instrument(span));
+                    }
+                    else => break,
+                }
+            }
+            debug!("Admin subscriptions closed");
+        }))
+    }
+
+    /// Handles a control request from another provider instance. Pause requests are only replied to by
+    /// instances that run the consumer, once its workers have stopped pulling messages
+    async fn handle_control(&self, msg: Message) {
+        let prefix = format!("{}.", self.namespace.control_subject_prefix());
+        let (operation, consumer) = msg
+            .subject
+            .strip_prefix(&prefix)
+            .and_then(|rest| rest.split_once('.'))
+            .unwrap_or_default();
+        let sender: ControlSender = match serde_json::from_slice(&msg.payload) {
+            Ok(sender) => sender,
+            Err(e) => {
+                warn!("Ignoring malformed control request: {e}");
+                return;
+            }
+        };
+        // The instance that sent the request handles its own consumers itself
+        if sender.instance == self.instance {
+            return;
+        }
+        match operation {
+            PAUSE_OPERATION => {
+                let paused = self
+                    .consumer_manager
+                    .pause_consumer(consumer, PAUSE_LEASE)
+                    .await;
+                if paused == 0 {
+                    return;
+                }
+                info!(
+                    consumer,
+                    "Paused consumer while another provider instance resets it"
+                );
+                if let Some(reply) = msg.reply {
+                    if let Err(e) = self.nc.publish(reply, Vec::new().into()).await {
+                        error!("Failed to reply to pause request: {e}");
+                    }
+                }
+            }
+            RESUME_OPERATION => self.consumer_manager.resume_consumer(consumer).await,
+            _ => warn!("Ignoring unknown control operation '{operation}'"),
+        }
+    }
+
+    /// Pauses the given consumer on every other provider instance, so that none of them recreates the durable
+    /// consumer from its initial starting point while it is being reset. Returns the number of instances that
+    /// reported pausing it
+    async fn pause_other_instances(&self, consumer: &str) -> Result<usize> {
+        let subject = format!(
+            "{}.{PAUSE_OPERATION}.{consumer}",
+            self.namespace.control_subject_prefix()
+        );
+        let inbox = self.nc.new_inbox();
+        let mut replies = self
+            .nc
+            .subscribe(inbox.clone())
+            .await
+            .map_err(|e| RpcError::Nats(format!("Failed to subscribe to {inbox}: {e}")))?;
+        self.nc
+            .publish_with_reply(subject, inbox, self.control_sender()?.into())
+            .await
+            .map_err(|e| RpcError::Nats(format!("Failed to pause {consumer} elsewhere: {e}")))?;
+        let mut paused = 0;
+        while let Ok(Some(_)) = tokio::time::timeout(PAUSE_REPLY_WAIT, replies.next()).await {
+            paused += 1;
+        }
+        Ok(paused)
+    }
+
+    /// Resumes the given consumer on the provider instances paused by
+    /// [`pause_other_instances`](Self::pause_other_instances). Instances that miss this resume on their own once
+    /// their pause runs out
+    async fn resume_other_instances(&self, consumer: &str) {
+        let subject = format!(
+            "{}.{RESUME_OPERATION}.{consumer}",
+            self.namespace.control_subject_prefix()
+        );
+        let res = match self.control_sender() {
+            Ok(body) => self
+                .nc
+                .publish(subject, body.into())
+                .await
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        if let Err(e) = res {
+            warn!(
+                consumer,
+                "Failed to resume consumer on other provider instances: {e}"
+            );
+        }
+    }
+
+    fn control_sender(&self) -> Result<Vec<u8>> {
+        serde_json::to_vec(&ControlSender {
+            instance: self.instance.clone(),
+        })
+        .map_err(|e| RpcError::Ser(format!("Failed to serialize control request: {e}")))
+    }
+
+    async fn handle(&self, msg: Message) {
+        let reply = match msg.reply.clone() {
+            Some(reply) => reply,
+            None => {
+                warn!("Ignoring admin request without a reply subject");
+                return;
+            }
+        };
+        let prefix = format!("{}.", self.namespace.admin_subject_prefix());
+        let (operation, target) = msg
+            .subject
+            .strip_prefix(&prefix)
+            .and_then(|rest| rest.split_once('.'))
+            .unwrap_or_default();
+
+        let body = match operation {
+            REBUILD_STATE_OPERATION => {
+                serde_json::to_vec(&AdminReply::from(self.rebuild_state(target).await))
+            }
+            REPLAY_OPERATION => {
+                serde_json::to_vec(&AdminReply::from(self.replay(target, &msg.payload).await))
+            }
+            LIST_DEAD_LETTERS_OPERATION => {
+                let consumer = (target != ALL_CONSUMERS).then_some(target);
+                serde_json::to_vec(&AdminReply::from(self.dead_letters.list(consumer).await))
+            }
+            GET_DEAD_LETTER_OPERATION => {
+                serde_json::to_vec(&AdminReply::from(self.get_dead_letter(target).await))
+            }
+            REPLAY_DEAD_LETTER_OPERATION => {
+                serde_json::to_vec(&AdminReply::from(self.replay_dead_letter(target).await))
+            }
+            DISCARD_DEAD_LETTER_OPERATION => {
+                serde_json::to_vec(&AdminReply::from(self.discard_dead_letter(target).await))
+            }
+            _ => serde_json::to_vec(&AdminReply::<()>::from(Err(RpcError::InvalidParameter(
+                format!("Unknown admin operation '{operation}'"),
+            )))),
+        };
+        let body = match body {
+            Ok(body) => body,
+            Err(e) => {
+                error!("Failed to serialize admin reply: {e}");
+                return;
+            }
+        };
+        if let Err(e) = self.nc.publish(reply, body.into()).await {
+            error!("Failed to send admin reply: {e}");
+        }
+    }
+
+    async fn rebuild_state(&self, aggregate: &str) -> Result<RebuildSummary> {
+        let interest = self.aggregate_interest(aggregate).await.ok_or_else(|| {
+            RpcError::InvalidParameter(format!(
+                "No aggregate named '{aggregate}' is linked to this provider"
+            ))
+        })?;
+        info!(aggregate, "Rebuilding aggregate state from event log");
+        rebuild_aggregate(&self.js, &self.state, &interest).await
+    }
+
+    /// Resets a projector's durable consumer to the requested starting point. The projector's workers on every
+    /// provider instance are paused while the consumer is reset and then receive every event from that point on
+    /// again
+    async fn replay(&self, projector: &str, payload: &[u8]) -> Result<ReplaySummary> {
+        let request: ReplayRequest = if payload.iter().all(u8::is_ascii_whitespace) {
+            ReplayRequest::default()
+        } else {
+            serde_json::from_slice(payload)
+                .map_err(|e| RpcError::InvalidParameter(format!("Invalid replay request: {e}")))?
+        };
+        let deliver_policy = request.deliver_policy()?;
+        let interest = self.projector_interest(projector).await?;
+        let consumer = interest.consumer_name();
+        info!(consumer, ?deliver_policy, "Replaying events to projector");
+        let paused = self.pause_other_instances(&consumer).await?;
+        debug!(
+            consumer,
+            paused, "Paused consumer on other provider instances"
+        );
+        let reset = self
+            .consumer_manager
+            .reset_consumer(&interest, deliver_policy)
+            .await;
+        self.resume_other_instances(&consumer).await;
+        let deliver_policy = reset
+            .map_err(|e| RpcError::Nats(format!("Failed to reset consumer {consumer}: {e}")))?;
+        Ok(ReplaySummary::new(consumer, deliver_policy))
+    }
+
+    async fn get_dead_letter(&self, sequence: &str) -> Result<DeadLetterDetail> {
+        let letter = self.dead_letters.get(parse_sequence(sequence)?).await?;
+        Ok(DeadLetterDetail {
+            payload: letter.payload_json(),
+            letter,
+        })
+    }
+
+    /// Hands a dead letter to the worker of the consumer it came from. The dead letter is only removed once the
+    /// worker has handled it successfully. Events can't be replayed to aggregates, since the aggregate's state
+    /// has usually moved past the event by then and applying it out of order would corrupt the state
+    async fn replay_dead_letter(&self, sequence: &str) -> Result<StoredDeadLetter> {
+        let letter = self.dead_letters.get(parse_sequence(sequence)?).await?;
+        let consumer = &letter.letter.consumer;
+        let interest = self
+            .consumer_manager
+            .consumers()
+            .await
+            .into_iter()
+            .find(|i| &i.consumer_name() == consumer)
+            .ok_or_else(|| {
+                RpcError::InvalidParameter(format!(
+                    "Consumer {consumer} isn't running on this provider"
+                ))
+            })?;
+        if interest.role == ActorRole::Aggregate
+            && interest.interest_constraint == InterestConstraint::Events
+        {
+            return Err(RpcError::InvalidParameter(format!(
+                "Events can't be replayed to aggregate {}. Send a request to {}.{REBUILD_STATE_OPERATION}.{} to rebuild its state from the event stream, then discard the dead letter",
+                interest.entity_name,
+                self.namespace.admin_subject_prefix(),
+                interest.entity_name
+            )));
+        }
+        info!(
+            consumer,
+            sequence = letter.sequence,
+            "Replaying dead letter"
+        );
+        self.consumer_manager
+            .redeliver(
+                &interest,
+                letter.payload.clone(),
+                letter.letter.stream_sequence,
+            )
+            .await
+            .map_err(|e| {
+                RpcError::Other(format!(
+                    "Dead letter {} failed again and was kept: {e}",
+                    letter.sequence
+                ))
+            })?;
+        self.dead_letters.remove(letter.sequence).await?;
+        Ok(letter)
+    }
+
+    async fn discard_dead_letter(&self, sequence: &str) -> Result<StoredDeadLetter> {
+        let letter = self.dead_letters.get(parse_sequence(sequence)?).await?;
+        self.dead_letters.remove(letter.sequence).await?;
+        info!(
+            consumer = letter.letter.consumer,
+            sequence = letter.sequence,
+            "Discarded dead letter"
+        );
+        Ok(letter)
+    }
+
+    /// Finds a linked projector by its durable consumer name or, if only one projector uses it, its entity name.
+    /// Only projectors can be replayed, since replaying events to other roles would apply them twice or send
+    /// their commands and notifications again
+    async fn projector_interest(&self, projector: &str) -> Result<InterestDeclaration> {
+        let mut matches: Vec<_> = self
+            .consumer_manager
+            .consumers()
+            .await
+            .into_iter()
+            .filter(|i| {
+                i.role == ActorRole::Projector
+                    && (i.consumer_name() == projector || i.entity_name == projector)
+            })
+            .collect();
+        match matches.len() {
+            1 => Ok(matches.remove(0)),
+            0 => Err(RpcError::InvalidParameter(format!(
+                "No projector named '{projector}' is linked to this provider"
+            ))),
+            _ => Err(RpcError::InvalidParameter(format!(
+                "Several projectors are named '{projector}', use one of their consumer names instead: {}",
+                matches
+                    .iter()
+                    .map(|i| i.consumer_name())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ))),
+        }
+    }
+
+    /// Finds the event interest of a linked aggregate, which is what applies events to its state
+    async fn aggregate_interest(&self, aggregate: &str) -> Option<InterestDeclaration> {
+        self.consumer_manager
+            .consumers()
+            .await
+            .into_iter()
+            .find(|i| {
+                i.role == ActorRole::Aggregate
+                    && i.interest_constraint == InterestConstraint::Events
+                    && i.entity_name == aggregate
+            })
+    }
+}
+
+fn parse_sequence(sequence: &str) -> Result<u64> {
+    sequence.parse().map_err(|_| {
+        RpcError::InvalidParameter(format!("Invalid dead letter sequence '{sequence}'"))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use async_nats::jetstream::consumer::DeliverPolicy;
+
+    use super::{ReplayRequest, ReplaySummary};
+
+    #[test]
+    fn parses_replay_starting_points() {
+        let parse = |json: &str| {
+            serde_json::from_str::<ReplayRequest>(json)
+                .unwrap()
+                .deliver_policy()
+        };
+        assert_eq!(DeliverPolicy::All, parse("{}").unwrap());
+        assert_eq!(
+            DeliverPolicy::ByStartSequence { start_sequence: 42 },
+            parse(r#"{"start_sequence": 42}"#).unwrap()
+        );
+        assert_eq!(
+            DeliverPolicy::ByStartTime {
+                start_time: time::OffsetDateTime::from_unix_timestamp(1685577600).unwrap()
+            },
+            parse(r#"{"start_time": "2023-06-01T00:00:00Z"}"#).unwrap()
+        );
+        assert!(parse(r#"{"start_time": "yesterday"}"#).is_err());
+        assert!(parse(r#"{"start_sequence": 42, "start_time": "2023-06-01T00:00:00Z"}"#).is_err());
+    }
+
+    #[test]
+    fn summarizes_actual_starting_point() {
+        let summary = |policy| {
+            serde_json::to_value(ReplaySummary::new("PROJ_bankaccount".to_string(), policy))
+                .unwrap()
+        };
+        assert_eq!(
+            serde_json::json!({"consumer": "PROJ_bankaccount"}),
+            summary(DeliverPolicy::All)
+        );
+        assert_eq!(
+            serde_json::json!({"consumer": "PROJ_bankaccount", "start_sequence": 42}),
+            summary(DeliverPolicy::ByStartSequence { start_sequence: 42 })
+        );
+        assert_eq!(
+            serde_json::json!({"consumer": "PROJ_bankaccount", "start_time": "2023-06-01T00:00:00+00:00"}),
+            summary(DeliverPolicy::ByStartTime {
+                start_time: time::OffsetDateTime::from_unix_timestamp(1685577600).unwrap()
+            })
+        );
+    }
+}
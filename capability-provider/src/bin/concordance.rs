@@ -22,12 +22,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     info!("Running Concordance provider version {version}");
     provider_run(
-        provider,
+        provider.clone(),
         host_data,
         Some("Concordance Event Sourcing".to_string()),
     )
     .await?;
 
+    // The host normally asks the provider to shut down before we get here, in which case this is a no-op
+    provider.drain().await;
+
     eprintln!("Concordance Event Sourcing provider exiting");
 
     Ok(())
@@ -4,15 +4,17 @@
 
 use case::CaseExt;
 use core::fmt;
-use std::{collections::HashMap, hash::Hash};
+use std::{collections::HashMap, hash::Hash, time::Duration};
 
 use crate::eventsourcing::Event as ConcordanceEvent;
-use crate::natsclient::SEND_TIMEOUT_DURATION;
+use crate::natsclient::{Namespace, DEFAULT_ACK_TIME, DEFAULT_MAX_DELIVER, SEND_TIMEOUT_DURATION};
 use crate::Result;
+use async_nats::jetstream::stream::{DiscardPolicy, Source, StorageType};
 use base64::{engine::general_purpose, Engine as _};
+use cloudevents::Event as CloudEvent;
 use serde::{Deserialize, Serialize};
 use tracing::Instrument;
-use tracing::{error, instrument};
+use tracing::{error, instrument, warn};
 use wasmbus_rpc::{core::LinkDefinition, wascap::prelude::KeyPair};
 
 const ROLE_KEY: &str = "role";
@@ -20,6 +22,14 @@ const INTEREST_KEY: &str = "interest";
 const ENTITY_NAME_KEY: &str = "name";
 const KEY_FIELD_KEY: &str = "key";
 const MAX_MESSAGES_PER_BATCH_KEY: &str = "max_messages_per_batch";
+const DELETE_CONSUMER_ON_UNLINK_KEY: &str = "delete_consumer_on_unlink";
+const DEAD_LETTER_REFUSALS_KEY: &str = "dead_letter_refusals";
+const CONSUMER_NAME_KEY: &str = "consumer_name";
+const CONCURRENCY_KEY: &str = "concurrency";
+const SNAPSHOT_INTERVAL_KEY: &str = "snapshot_interval";
+const MAX_DELIVER_KEY: &str = "max_deliver";
+const ACK_WAIT_KEY: &str = "ack_wait";
+const BACKOFF_KEY: &str = "backoff";
 
 const REQUIRED_KEYS: &[&str] = &["role", "interest", "name"];
 
@@ -28,7 +38,61 @@ const ROLE_PROJECTOR: &str = "projector";
 const ROLE_PROCESS_MANAGER: &str = "process_manager";
 const ROLE_NOTIFIER: &str = "notifier";
 
+const DEFAULT_LINK_NAME: &str = "default";
+/// Number of characters of the actor's public key used to make generated consumer names unique
+const ACTOR_ID_SUFFIX_LEN: usize = 8;
+
 const DEFAULT_BATCH_MAX: usize = 200; // this is the default set by the NATS client when you leave the value off
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 5;
+const DEFAULT_CONCURRENCY: usize = 1;
+
+/// How often and how quickly a consumer's messages are redelivered when they aren't acked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Number of deliveries before a message is moved to the dead letter stream
+    pub max_deliver: i64,
+    /// How long a worker has to ack a message before it is redelivered
+    pub ack_wait: Duration,
+    /// Delays before each redelivery, the last of which is used for any further redeliveries
+    pub backoff: Vec<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_deliver: DEFAULT_MAX_DELIVER,
+            ack_wait: DEFAULT_ACK_TIME,
+            backoff: Vec::new(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Ack timeouts for each delivery, to be used as the consumer's JetStream backoff. A message that isn't acked in
+    /// time waits the same delay on top of the ack wait as a message that failed. Empty without a backoff, in which
+    /// case the ack wait applies to every delivery
+    pub fn ack_timeouts(&self) -> Vec<Duration> {
+        self.backoff.iter().map(|b| self.ack_wait + *b).collect()
+    }
+
+    /// The ack wait to configure on the consumer, which JetStream requires to match the first ack timeout when
+    /// there is a backoff
+    pub fn initial_ack_wait(&self) -> Duration {
+        self.backoff
+            .first()
+            .map_or(self.ack_wait, |b| self.ack_wait + *b)
+    }
+
+    /// The delay before redelivering a message that failed on the given delivery (starting at 1), or `None` to
+    /// redeliver it right away
+    pub fn redelivery_delay(&self, delivered: i64) -> Option<Duration> {
+        let index = (delivered.max(1) - 1) as usize;
+        self.backoff
+            .get(index)
+            .or_else(|| self.backoff.last())
+            .copied()
+    }
+}
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct BaseConfiguration {
@@ -40,6 +104,144 @@ pub struct BaseConfiguration {
     pub user_seed: Option<String>,
     /// JetStream domain for the JS context used by this provider
     pub js_domain: Option<String>,
+    /// Optional prefix applied to all stream names, subjects, buckets and consumer names so that several
+    /// applications can share one NATS account, e.g. `bank` yields `bank_CC_EVENTS` and `bank.cc.events.>`
+    pub namespace: Option<String>,
+    /// Maximum number of seconds to wait for in-flight work to finish when the provider shuts down
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
+    /// Settings used when creating the event stream (`CC_EVENTS`)
+    #[serde(default)]
+    pub events_stream: StreamSettings,
+    /// Settings used when creating the command stream (`CC_COMMANDS`)
+    #[serde(default)]
+    pub commands_stream: StreamSettings,
+    /// Settings used when creating the state bucket (`CC_STATE`)
+    #[serde(default)]
+    pub state_bucket: BucketSettings,
+    /// Settings used when creating the snapshot bucket (`CC_SNAPSHOTS`)
+    #[serde(default)]
+    pub snapshot_bucket: BucketSettings,
+    /// Streams that the event stream sources events from, such as the outbound streams of disconnected leaf
+    /// nodes. The event stream continues to accept locally published events
+    #[serde(default)]
+    pub event_sources: Vec<Source>,
+    /// Stream that the event stream mirrors. A mirror is read-only, so it can't be combined with `event_sources`
+    /// and aggregates can't publish to it
+    #[serde(default)]
+    pub event_mirror: Option<Source>,
+    /// Optional outbound stream, used to hold the events a leaf node forwards upstream
+    #[serde(default)]
+    pub outbound_stream: Option<OutboundStreamSettings>,
+    /// Subject scheme used when publishing events
+    #[serde(default)]
+    pub event_subjects: EventSubjects,
+    /// Where to load the JSON schemas that commands and events are validated against. Nothing is validated
+    /// when no schemas are configured
+    #[serde(default)]
+    pub schemas: SchemaSettings,
+    /// Local address to serve Prometheus metrics on, e.g. `127.0.0.1:9090`. Metrics aren't served when unset
+    pub metrics_address: Option<String>,
+}
+
+/// The subject scheme used for events
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventSubjects {
+    /// Use keyed subjects unless the event stream was created for legacy subjects
+    #[default]
+    Auto,
+    /// Publish events on `cc.events.{stream}.{key}.{event_type}`, so the event log can be filtered by entity
+    /// and appends for the same key are checked against the last known sequence
+    Keyed,
+    /// Publish events on `cc.events.{event_type}`, the scheme used before keyed subjects
+    Legacy,
+}
+
+/// Settings for the optional outbound stream. In a DDIL (disconnected, intermittent, limited) topology, the
+/// outbound stream sources the events that should leave the leaf node from its event stream, and the upstream
+/// event stream lists it as one of its `event_sources`. Forwarded events keep their subjects, so the upstream
+/// provider's consumers receive them like locally published events
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OutboundStreamSettings {
+    /// Name of the stream. Defaults to `CC_OUTBOUND` with the namespace prefix, but leaf nodes sourced by the
+    /// same upstream stream will usually need unique names, e.g. `ROVER1_OUTBOUND`
+    pub name: Option<String>,
+    /// Types of the events forwarded upstream, e.g. `rover_landed`. Every event is forwarded when empty
+    pub event_types: Vec<String>,
+    #[serde(flatten)]
+    pub settings: StreamSettings,
+}
+
+/// Tunable settings for one of the provider's streams. These are only applied when the stream is created. If
+/// an existing stream's configuration differs, the differences are logged and reported by health checks
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StreamSettings {
+    /// Number of replicas to keep in a clustered JetStream
+    pub replicas: usize,
+    /// Either `file` or `memory`
+    pub storage: StorageType,
+    /// Maximum age of messages in seconds, 0 for unlimited
+    pub max_age_secs: u64,
+    /// Maximum size of the stream in bytes, -1 for unlimited
+    pub max_bytes: i64,
+    /// Window in seconds used to detect duplicate messages. The server default is used when not set
+    pub duplicate_window_secs: Option<u64>,
+    /// Either `old` to remove old messages when a limit is hit, or `new` to reject new messages
+    pub discard: DiscardPolicy,
+}
+
+impl Default for StreamSettings {
+    fn default() -> Self {
+        Self {
+            replicas: 1,
+            storage: StorageType::File,
+            max_age_secs: 0,
+            max_bytes: -1,
+            duplicate_window_secs: None,
+            discard: DiscardPolicy::Old,
+        }
+    }
+}
+
+/// Tunable settings for the state bucket. Like [`StreamSettings`], these only apply when the bucket is created
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BucketSettings {
+    /// Number of historical values to keep per key
+    pub history: i64,
+    /// Number of replicas to keep in a clustered JetStream
+    pub replicas: usize,
+    /// Either `file` or `memory`
+    pub storage: StorageType,
+}
+
+impl Default for BucketSettings {
+    fn default() -> Self {
+        Self {
+            history: 1,
+            replicas: 1,
+            storage: StorageType::File,
+        }
+    }
+}
+
+/// Sources of JSON schemas for commands and events. Each schema applies to the command or event type named by its
+/// `title`, or by its key in the bucket
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SchemaSettings {
+    /// Directory of an eventcatalog site. Every `schema.json` file beneath it is loaded
+    pub catalog_path: Option<String>,
+    /// Key value bucket holding one schema per command or event type. Schemas in the bucket take precedence over
+    /// those in the catalog
+    pub bucket: Option<String>,
+}
+
+fn default_shutdown_timeout_secs() -> u64 {
+    DEFAULT_SHUTDOWN_TIMEOUT_SECS
 }
 
 impl Default for BaseConfiguration {
@@ -49,6 +251,18 @@ impl Default for BaseConfiguration {
             user_jwt: None,
             user_seed: None,
             js_domain: None,
+            namespace: None,
+            shutdown_timeout_secs: DEFAULT_SHUTDOWN_TIMEOUT_SECS,
+            events_stream: StreamSettings::default(),
+            commands_stream: StreamSettings::default(),
+            state_bucket: BucketSettings::default(),
+            snapshot_bucket: BucketSettings::default(),
+            event_sources: Vec::new(),
+            event_mirror: None,
+            outbound_stream: None,
+            event_subjects: EventSubjects::default(),
+            schemas: SchemaSettings::default(),
+            metrics_address: None,
         }
     }
 }
@@ -87,6 +301,14 @@ impl BaseConfiguration {
                 })?,
         )
     }
+
+    pub fn namespace(&self) -> Result<Namespace> {
+        Namespace::new(self.namespace.as_deref())
+    }
+
+    pub fn shutdown_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.shutdown_timeout_secs)
+    }
 }
 
 /// All entities participating in an event sourced system must declare their interest.
@@ -94,6 +316,7 @@ impl BaseConfiguration {
 /// in an explicit list of event types.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct InterestDeclaration {
+    pub namespace: Namespace,
     pub actor_id: String,
     pub key_field: String,
     pub entity_name: String,
@@ -112,6 +335,15 @@ impl InterestDeclaration {
             .unwrap_or_default()
     }
 
+    /// Extracts the entity key from the JSON data of an event, returning an empty string if the event has no
+    /// JSON data or the key field isn't present
+    pub fn extract_key_value_from_event(&self, event: &CloudEvent) -> String {
+        match event.data() {
+            Some(cloudevents::Data::Json(payload)) => self.extract_key_value_from_payload(payload),
+            _ => String::new(),
+        }
+    }
+
     pub fn extract_max_messages_per_batch(&self) -> usize {
         self.link_definition
             .values
@@ -119,11 +351,102 @@ impl InterestDeclaration {
             .map(|s| s.parse::<usize>().unwrap_or(DEFAULT_BATCH_MAX))
             .unwrap_or(DEFAULT_BATCH_MAX)
     }
+
+    /// Number of messages that can be processed in parallel for this declaration. Messages are partitioned by
+    /// entity key so that messages for the same key are still handled in order. Defaults to 1 (sequential)
+    pub fn extract_concurrency(&self) -> usize {
+        self.link_definition
+            .values
+            .get(CONCURRENCY_KEY)
+            .and_then(|s| s.trim().parse::<usize>().ok())
+            .unwrap_or(DEFAULT_CONCURRENCY)
+            .max(1)
+    }
+
+    /// Number of events between snapshots of an aggregate's state, or `None` if snapshots are disabled, which is
+    /// the default
+    pub fn extract_snapshot_interval(&self) -> Option<u64> {
+        self.link_definition
+            .values
+            .get(SNAPSHOT_INTERVAL_KEY)
+            .and_then(|s| s.trim().parse::<u64>().ok())
+            .filter(|n| *n > 0)
+    }
+
+    /// How many times messages are delivered to this declaration's consumer and how long to wait between
+    /// deliveries. `MAX_DELIVER` and `ACK_WAIT` (in seconds) default to 3 deliveries and a 3 second ack wait.
+    /// `BACKOFF` is a comma separated list of seconds to wait before each redelivery, e.g. `5,30,300`, with the
+    /// last value repeated for any further redeliveries. Without a backoff, failed messages are redelivered
+    /// right away
+    pub fn extract_retry_policy(&self) -> RetryPolicy {
+        let values = &self.link_definition.values;
+        let max_deliver = values
+            .get(MAX_DELIVER_KEY)
+            .and_then(|s| s.trim().parse::<i64>().ok())
+            .filter(|n| *n > 0)
+            .unwrap_or(DEFAULT_MAX_DELIVER);
+        let ack_wait = values
+            .get(ACK_WAIT_KEY)
+            .and_then(|s| s.trim().parse::<u64>().ok())
+            .filter(|n| *n > 0)
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_ACK_TIME);
+        let mut backoff: Vec<_> = values
+            .get(BACKOFF_KEY)
+            .map(|s| {
+                s.split(',')
+                    .map(str::trim)
+                    .filter(|v| !v.is_empty())
+                    .filter_map(|v| match v.parse::<u64>() {
+                        Ok(secs) => Some(Duration::from_secs(secs)),
+                        Err(_) => {
+                            warn!("Ignoring invalid backoff value '{v}' for {self}");
+                            None
+                        }
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        // Only redeliveries wait, so there is one fewer delay than there are deliveries
+        let max_delays = (max_deliver - 1).max(0) as usize;
+        if backoff.len() > max_delays {
+            warn!(
+                "{self} has more backoff values than redeliveries, only the first {max_delays} are used"
+            );
+            backoff.truncate(max_delays);
+        }
+        RetryPolicy {
+            max_deliver,
+            ack_wait,
+            backoff,
+        }
+    }
+
+    /// Indicates whether the durable NATS consumer backing this declaration should be deleted when the
+    /// link is removed. Defaults to `false` so that redeploying an actor resumes where it left off
+    pub fn extract_delete_consumer_on_unlink(&self) -> bool {
+        self.link_definition
+            .values
+            .get(DELETE_CONSUMER_ON_UNLINK_KEY)
+            .map(|s| s.trim().eq_ignore_ascii_case("true"))
+            .unwrap_or(false)
+    }
+
+    /// Indicates whether commands an aggregate refuses should be moved to the dead letter stream. Defaults to
+    /// `false`, in which case they are dropped once the sender has been told
+    pub fn extract_dead_letter_refusals(&self) -> bool {
+        self.link_definition
+            .values
+            .get(DEAD_LETTER_REFUSALS_KEY)
+            .map(|s| s.trim().eq_ignore_ascii_case("true"))
+            .unwrap_or(false)
+    }
 }
 
 impl Hash for InterestDeclaration {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         self.actor_id.hash(state);
+        self.link_definition.link_name.hash(state);
         self.entity_name.hash(state);
         self.role.hash(state);
         self.interest.hash(state);
@@ -156,6 +479,7 @@ impl InterestDeclaration {
         ld: LinkDefinition,
     ) -> InterestDeclaration {
         InterestDeclaration {
+            namespace: Namespace::default(),
             actor_id: actor_id.to_string(),
             entity_name: entity_name.to_string(),
             role: ActorRole::Aggregate,
@@ -174,6 +498,7 @@ impl InterestDeclaration {
         ld: LinkDefinition,
     ) -> InterestDeclaration {
         InterestDeclaration {
+            namespace: Namespace::default(),
             actor_id: actor_id.to_string(),
             entity_name: entity_name.to_string(),
             role: ActorRole::Aggregate,
@@ -194,6 +519,7 @@ impl InterestDeclaration {
         ld: LinkDefinition,
     ) -> InterestDeclaration {
         InterestDeclaration {
+            namespace: Namespace::default(),
             actor_id: actor_id.to_string(),
             entity_name: entity_name.to_string(),
             role,
@@ -204,6 +530,12 @@ impl InterestDeclaration {
         }
     }
 
+    /// Places this declaration in the given namespace, which scopes the names of its consumer and the subjects
+    /// it publishes to
+    pub fn with_namespace(self, namespace: Namespace) -> InterestDeclaration {
+        InterestDeclaration { namespace, ..self }
+    }
+
     pub fn from_linkdefinition(
         source: LinkDefinition,
     ) -> std::result::Result<Vec<InterestDeclaration>, String> {
@@ -251,32 +583,74 @@ impl InterestDeclaration {
             .is_interested_in_event(&event.event_type, &event.stream)
     }
 
+    /// The name of the durable NATS consumer for this declaration. Aggregates own their entity name, so their
+    /// consumers are named after it. All other roles can share an entity name with other actors, so unless the
+    /// link definition supplies an explicit `consumer_name`, their generated names include the link name (when
+    /// not `default`) and a prefix of the actor's public key
     pub fn consumer_name(&self) -> String {
+        let name = self.entity_name.clone();
+        if let ActorRole::Aggregate = self.role {
+            return if let InterestConstraint::Commands = self.interest_constraint {
+                self.namespace.consumer_name(&format!("AGG_CMD_{name}"))
+            } else {
+                self.namespace.consumer_name(&format!("AGG_EVT_{name}"))
+            };
+        }
+        if let Some(explicit) = self.explicit_consumer_name() {
+            return self.namespace.consumer_name(&explicit);
+        }
+        match self.legacy_consumer_name() {
+            Some(legacy) => format!("{legacy}_{}", self.consumer_name_suffix()),
+            None => "".to_string(), // unknown decls can be used for publish-only entities or are filtered out via error early
+        }
+    }
+
+    /// The consumer name used before names were made unique per actor, e.g. `PROJ_bankaccount`. Event consumers
+    /// being created for the first time under their new name pick up from where this durable left off, if it exists
+    pub fn legacy_consumer_name(&self) -> Option<String> {
         let name = self.entity_name.clone();
         match self.role {
-            ActorRole::Aggregate => {
-                if let InterestConstraint::Commands = self.interest_constraint {
-                    format!("AGG_CMD_{name}")
-                } else {
-                    format!("AGG_EVT_{name}")
-                }
-            }
-            ActorRole::ProcessManager => {
-                format!("PM_{name}")
-            }
-            ActorRole::Notifier => {
-                format!("NOTIFIER_{name}")
-            }
-            ActorRole::Projector => {
-                format!("PROJ_{name}")
-            }
-            ActorRole::Unknown => {
-                "".to_string() // unknown decls can be used for publish-only entities or are filtered out via error early
-            }
+            ActorRole::ProcessManager => Some(format!("PM_{name}")),
+            ActorRole::Notifier => Some(format!("NOTIFIER_{name}")),
+            ActorRole::Projector => Some(format!("PROJ_{name}")),
+            ActorRole::Aggregate | ActorRole::Unknown => None,
+        }
+        .map(|n| self.namespace.consumer_name(&n))
+    }
+
+    fn explicit_consumer_name(&self) -> Option<String> {
+        self.link_definition
+            .values
+            .get(CONSUMER_NAME_KEY)
+            .map(|s| sanitize_consumer_name(s.trim()))
+            .filter(|s| !s.is_empty())
+    }
+
+    fn consumer_name_suffix(&self) -> String {
+        let actor: String = self.actor_id.chars().take(ACTOR_ID_SUFFIX_LEN).collect();
+        let link_name = self.link_definition.link_name.trim();
+        if link_name.is_empty() || link_name == DEFAULT_LINK_NAME {
+            actor
+        } else {
+            format!("{}_{actor}", sanitize_consumer_name(link_name))
         }
     }
 }
 
+/// Consumer names can't contain whitespace, wildcards, or separators, so replace anything unusual with `_`
+fn sanitize_consumer_name(input: &str) -> String {
+    input
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
 fn lowercase_ld_keys(ld: LinkDefinition) -> LinkDefinition {
     let mut values = HashMap::new();
     for (k, v) in ld.values.iter() {
@@ -477,10 +851,11 @@ fn to_snake_list(input: &str) -> Vec<String> {
 
 #[cfg(test)]
 mod test {
-    use super::InterestDeclaration;
+    use super::{BaseConfiguration, InterestDeclaration, RetryPolicy, StreamSettings};
     use crate::config::{ActorInterest, ActorRole, ProcessManagerLifetime};
     use crate::eventsourcing::Event as ConcordanceEvent;
-    use std::collections::HashMap;
+    use async_nats::jetstream::stream::{DiscardPolicy, StorageType};
+    use std::{collections::HashMap, time::Duration};
     use wasmbus_rpc::core::LinkDefinition;
 
     fn generate_ld(hm: HashMap<String, String>) -> LinkDefinition {
@@ -494,6 +869,63 @@ mod test {
         ld
     }
 
+    #[test]
+    fn parses_stream_settings() {
+        let config: BaseConfiguration = serde_json::from_str(
+            r#"{
+                "nats_url": "127.0.0.1:4222",
+                "events_stream": {
+                    "replicas": 3,
+                    "max_age_secs": 86400,
+                    "discard": "new"
+                },
+                "state_bucket": {
+                    "history": 5,
+                    "storage": "memory"
+                }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(3, config.events_stream.replicas);
+        assert_eq!(86400, config.events_stream.max_age_secs);
+        assert_eq!(DiscardPolicy::New, config.events_stream.discard);
+        assert_eq!(StorageType::File, config.events_stream.storage); // unspecified values use defaults
+        assert_eq!(StreamSettings::default(), config.commands_stream);
+        assert_eq!(5, config.state_bucket.history);
+        assert_eq!(1, config.state_bucket.replicas);
+        assert_eq!(StorageType::Memory, config.state_bucket.storage);
+    }
+
+    #[test]
+    fn parses_stream_topology() {
+        let config: BaseConfiguration = serde_json::from_str(
+            r#"{
+                "nats_url": "127.0.0.1:4222",
+                "event_sources": [
+                    { "name": "ROVER1_OUTBOUND", "domain": "rover1" },
+                    { "name": "ROVER2_OUTBOUND", "domain": "rover2" }
+                ],
+                "outbound_stream": {
+                    "name": "MOTHERSHIP_OUTBOUND",
+                    "event_types": ["rover_landed"],
+                    "max_age_secs": 3600
+                }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(2, config.event_sources.len());
+        assert_eq!("ROVER1_OUTBOUND", config.event_sources[0].name);
+        assert_eq!(Some("rover2".to_string()), config.event_sources[1].domain);
+        assert!(config.event_mirror.is_none());
+        let outbound = config.outbound_stream.unwrap();
+        assert_eq!(Some("MOTHERSHIP_OUTBOUND".to_string()), outbound.name);
+        assert_eq!(vec!["rover_landed"], outbound.event_types);
+        assert_eq!(3600, outbound.settings.max_age_secs);
+        assert_eq!(1, outbound.settings.replicas);
+    }
+
     #[test]
     fn rejects_empty_linkdefinition() {
         let ld = LinkDefinition::default();
@@ -551,6 +983,152 @@ mod test {
         );
     }
 
+    #[test]
+    fn accepts_delete_consumer_on_unlink() {
+        let mut hm = HashMap::new();
+        hm.insert("ROLE".to_string(), "projector".to_string());
+        hm.insert("INTEREST".to_string(), "account_created".to_string());
+        hm.insert("NAME".to_string(), "bankaccount".to_string());
+        let ld = generate_ld(hm.clone());
+        let decl = &InterestDeclaration::from_linkdefinition(ld).unwrap()[0];
+        assert!(!decl.extract_delete_consumer_on_unlink()); // default is to keep the durable
+
+        hm.insert("DELETE_CONSUMER_ON_UNLINK".to_string(), "True".to_string());
+        let ld = generate_ld(hm);
+        let decl = &InterestDeclaration::from_linkdefinition(ld).unwrap()[0];
+        assert!(decl.extract_delete_consumer_on_unlink());
+    }
+
+    #[test]
+    fn accepts_concurrency() {
+        let mut hm = HashMap::new();
+        hm.insert("ROLE".to_string(), "aggregate".to_string());
+        hm.insert("KEY".to_string(), "account_number".to_string());
+        hm.insert("NAME".to_string(), "bankaccount".to_string());
+        hm.insert("INTEREST".to_string(), "bankaccount".to_string());
+        let ld = generate_ld(hm.clone());
+        let decl = &InterestDeclaration::from_linkdefinition(ld).unwrap()[0];
+        assert_eq!(1, decl.extract_concurrency());
+
+        hm.insert("CONCURRENCY".to_string(), "8".to_string());
+        let ld = generate_ld(hm.clone());
+        let decls = InterestDeclaration::from_linkdefinition(ld).unwrap();
+        assert!(decls.iter().all(|d| d.extract_concurrency() == 8));
+
+        // Zero or garbage falls back to sequential processing
+        hm.insert("CONCURRENCY".to_string(), "0".to_string());
+        let ld = generate_ld(hm.clone());
+        let decl = &InterestDeclaration::from_linkdefinition(ld).unwrap()[0];
+        assert_eq!(1, decl.extract_concurrency());
+        hm.insert("CONCURRENCY".to_string(), "lots".to_string());
+        let ld = generate_ld(hm);
+        let decl = &InterestDeclaration::from_linkdefinition(ld).unwrap()[0];
+        assert_eq!(1, decl.extract_concurrency());
+    }
+
+    #[test]
+    fn accepts_snapshot_interval() {
+        let mut hm = HashMap::new();
+        hm.insert("ROLE".to_string(), "aggregate".to_string());
+        hm.insert("KEY".to_string(), "rover_id".to_string());
+        hm.insert("NAME".to_string(), "rover".to_string());
+        hm.insert("INTEREST".to_string(), "rover".to_string());
+        let ld = generate_ld(hm.clone());
+        let decl = &InterestDeclaration::from_linkdefinition(ld).unwrap()[0];
+        assert_eq!(None, decl.extract_snapshot_interval());
+
+        hm.insert("SNAPSHOT_INTERVAL".to_string(), "500".to_string());
+        let ld = generate_ld(hm.clone());
+        let decl = &InterestDeclaration::from_linkdefinition(ld).unwrap()[0];
+        assert_eq!(Some(500), decl.extract_snapshot_interval());
+
+        hm.insert("SNAPSHOT_INTERVAL".to_string(), "0".to_string());
+        let ld = generate_ld(hm);
+        let decl = &InterestDeclaration::from_linkdefinition(ld).unwrap()[0];
+        assert_eq!(None, decl.extract_snapshot_interval());
+    }
+
+    #[test]
+    fn parses_retry_policy() {
+        let mut hm = HashMap::new();
+        hm.insert("ROLE".to_string(), "notifier".to_string());
+        hm.insert("INTEREST".to_string(), "account_created".to_string());
+        hm.insert("NAME".to_string(), "bankaccount".to_string());
+        let ld = generate_ld(hm.clone());
+        let decl = &InterestDeclaration::from_linkdefinition(ld).unwrap()[0];
+        let retry = decl.extract_retry_policy();
+        assert_eq!(RetryPolicy::default(), retry);
+        assert_eq!(None, retry.redelivery_delay(1));
+
+        hm.insert("MAX_DELIVER".to_string(), "5".to_string());
+        hm.insert("ACK_WAIT".to_string(), "30".to_string());
+        hm.insert("BACKOFF".to_string(), "5, 60,bogus,300".to_string());
+        let ld = generate_ld(hm.clone());
+        let decl = &InterestDeclaration::from_linkdefinition(ld).unwrap()[0];
+        let retry = decl.extract_retry_policy();
+        assert_eq!(5, retry.max_deliver);
+        assert_eq!(Duration::from_secs(30), retry.ack_wait);
+        assert_eq!(
+            vec![
+                Duration::from_secs(5),
+                Duration::from_secs(60),
+                Duration::from_secs(300)
+            ],
+            retry.backoff
+        );
+        assert_eq!(Some(Duration::from_secs(5)), retry.redelivery_delay(1));
+        assert_eq!(Some(Duration::from_secs(300)), retry.redelivery_delay(3));
+        assert_eq!(Some(Duration::from_secs(300)), retry.redelivery_delay(4));
+
+        // JetStream requires more deliveries than backoff values
+        hm.insert("MAX_DELIVER".to_string(), "2".to_string());
+        let ld = generate_ld(hm);
+        let decl = &InterestDeclaration::from_linkdefinition(ld).unwrap()[0];
+        assert_eq!(
+            vec![Duration::from_secs(5)],
+            decl.extract_retry_policy().backoff
+        );
+    }
+
+    #[test]
+    fn consumer_names_are_unique_per_actor() {
+        let mut hm = HashMap::new();
+        hm.insert("ROLE".to_string(), "projector".to_string());
+        hm.insert("INTEREST".to_string(), "account_created".to_string());
+        hm.insert("NAME".to_string(), "bankaccount".to_string());
+        let ld = generate_ld(hm.clone());
+        let first = &InterestDeclaration::from_linkdefinition(ld).unwrap()[0];
+        assert_eq!("PROJ_bankaccount_MAEYUH6M", first.consumer_name());
+        assert_eq!(
+            Some("PROJ_bankaccount".to_string()),
+            first.legacy_consumer_name()
+        );
+
+        let mut ld = generate_ld(hm.clone());
+        ld.actor_id = "MBDKUNSBHW2UONQV4GEHKGBR4FBUHEVT2MOEJ2NQCSMQJXMTKCZ3NPWC".to_string();
+        ld.link_name = "audit.v2".to_string();
+        let second = &InterestDeclaration::from_linkdefinition(ld).unwrap()[0];
+        assert_eq!("PROJ_bankaccount_audit_v2_MBDKUNSB", second.consumer_name());
+
+        hm.insert("CONSUMER_NAME".to_string(), "ledger_projector".to_string());
+        let ld = generate_ld(hm);
+        let explicit = &InterestDeclaration::from_linkdefinition(ld).unwrap()[0];
+        assert_eq!("ledger_projector", explicit.consumer_name());
+    }
+
+    #[test]
+    fn aggregate_consumer_names_ignore_actor() {
+        let mut hm = HashMap::new();
+        hm.insert("ROLE".to_string(), "aggregate".to_string());
+        hm.insert("INTEREST".to_string(), "bankaccount".to_string());
+        hm.insert("NAME".to_string(), "bankaccount".to_string());
+        let ld = generate_ld(hm);
+        let decls = InterestDeclaration::from_linkdefinition(ld).unwrap();
+        assert_eq!("AGG_CMD_bankaccount", decls[0].consumer_name());
+        assert_eq!("AGG_EVT_bankaccount", decls[1].consumer_name());
+        assert_eq!(None, decls[0].legacy_consumer_name());
+    }
+
     #[test]
     fn rejects_bogus_linkdefinition() {
         let mut hm = HashMap::new();
@@ -634,6 +1212,7 @@ mod test {
             event_type: "player_moved".to_string(),
             payload: vec![],
             stream: "gameboard".to_string(),
+            metadata: None,
         };
         assert!(agg.is_interested_in_event(&ev));
 
@@ -642,6 +1221,7 @@ mod test {
             event_type: "player_died".to_string(),
             payload: vec![],
             stream: "match".to_string(),
+            metadata: None,
         };
         assert!(!agg.is_interested_in_event(&ev));
 
@@ -673,11 +1253,13 @@ mod test {
             event_type: "game_started".to_string(),
             stream: "gameboard".to_string(),
             payload: vec![],
+            metadata: None,
         };
         let event_unwanted = ConcordanceEvent {
             event_type: "player_profile_updated".to_string(),
             stream: "gameboard".to_string(),
             payload: vec![],
+            metadata: None,
         };
         assert!(agg.is_interested_in_event(&event_wanted));
         assert!(!agg.is_interested_in_event(&event_unwanted));
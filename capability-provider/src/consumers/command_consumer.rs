@@ -1,4 +1,4 @@
-use crate::config::{ActorRole, InterestDeclaration};
+use crate::config::{ActorRole, InterestDeclaration, RetryPolicy};
 use async_nats::{
     jetstream::{
         consumer::pull::{Config as PullConfig, Stream as MessageStream},
@@ -11,22 +11,34 @@ use futures::{Stream, TryStreamExt};
 use serde::{Deserialize, Serialize};
 use std::pin::Pin;
 use std::task::{Context, Poll};
-use tracing::{error, warn};
 
-use crate::natsclient::{AckableMessage, DEFAULT_ACK_TIME};
+use crate::natsclient::AckableMessage;
 
-use super::{impl_Stream, CreateConsumer};
+use super::{impl_Stream, update_retry_policy, CreateConsumer};
 
 /// The JSON command as pulled off of the stream by way of a command consumer
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct RawCommand {
     pub command_type: String,
     pub key: String,
     pub data: serde_json::Value,
+    /// Uniquely identifies the command, and is used to drop duplicates of it. Commands sent through the gateway
+    /// without one are given one, and other commands without one are identified by their position in the
+    /// command stream
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// Identifies the saga the command belongs to. Commands that start a saga use their own id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    /// The id of the event that caused the command, if it was issued by a process manager
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub causation_id: Option<String>,
 }
 
 pub struct CommandConsumer {
     stream: MessageStream,
+    interest: InterestDeclaration,
+    retry: RetryPolicy,
 }
 
 impl CommandConsumer {
@@ -51,6 +63,7 @@ impl CommandConsumer {
         }
         let friendly_name = interest.to_string();
         let agg_name = interest.entity_name.clone();
+        let retry = interest.extract_retry_policy();
 
         let consumer = stream
             .get_or_create_consumer(
@@ -60,22 +73,32 @@ impl CommandConsumer {
                     name: Some(consumer_name.clone()),
                     description: Some(format!("Durable command consumer for {friendly_name}")),
                     ack_policy: async_nats::jetstream::consumer::AckPolicy::Explicit,
-                    ack_wait: DEFAULT_ACK_TIME,
-                    // poison pill identified after 3 nacks
-                    max_deliver: 3,
+                    ack_wait: retry.initial_ack_wait(),
+                    // poison pill identified after max_deliver nacks, at which point it is moved to the dead
+                    // letter stream
+                    max_deliver: retry.max_deliver,
+                    backoff: retry.ack_timeouts(),
                     deliver_policy: async_nats::jetstream::consumer::DeliverPolicy::All,
-                    filter_subject: format!("cc.commands.{agg_name}"),
+                    filter_subject: format!(
+                        "{}.{agg_name}",
+                        interest.namespace.command_subject_prefix()
+                    ),
                     ..Default::default()
                 },
             )
             .await?;
+        let consumer = update_retry_policy(&stream, consumer, &retry).await?;
 
         let messages = consumer
             .stream()
             .max_messages_per_batch(interest.extract_max_messages_per_batch())
             .messages()
             .await?;
-        Ok(CommandConsumer { stream: messages })
+        Ok(CommandConsumer {
+            stream: messages,
+            interest,
+            retry,
+        })
     }
 }
 
@@ -96,6 +119,8 @@ impl CreateConsumer for CommandConsumer {
 
 #[cfg(test)]
 mod test {
+    use std::time::Duration;
+
     use futures::{Stream, TryStreamExt};
     use serde_json::json;
     use tokio::time::timeout;
@@ -104,10 +129,11 @@ mod test {
     use crate::{
         config::{ActorInterest, ActorRole, InterestConstraint, InterestDeclaration},
         consumers::{CommandConsumer, RawCommand},
+        deadletter::DeadLetterQueue,
         natsclient::{
             test::create_js_context,
             test::{clear_streams, publish_command},
-            AckableMessage, NatsClient, SEND_TIMEOUT_DURATION,
+            AckableMessage, Namespace, NatsClient, SEND_TIMEOUT_DURATION,
         },
     };
 
@@ -117,7 +143,7 @@ mod test {
         let js = create_js_context().await;
         clear_streams(js.clone()).await;
 
-        let client = NatsClient::new(js.clone());
+        let client = NatsClient::new(js.clone(), Namespace::default());
         let (_e, c) = client.ensure_streams().await.unwrap();
 
         let cmds = vec![
@@ -127,6 +153,7 @@ mod test {
                 data: json!({
                     "hello": "world"
                 }),
+                ..Default::default()
             },
             RawCommand {
                 command_type: "test_two".to_string(),
@@ -134,6 +161,7 @@ mod test {
                 data: json!({
                     "hello": "world2"
                 }),
+                ..Default::default()
             },
             RawCommand {
                 command_type: "test_three".to_string(),
@@ -141,6 +169,7 @@ mod test {
                 data: json!({
                     "hello": "world3"
                 }),
+                ..Default::default()
             },
         ];
 
@@ -179,12 +208,81 @@ mod test {
         clear_streams(js).await;
     }
 
+    #[tokio::test]
+    async fn command_consumer_quarantines_undecodable_commands() {
+        let nc = async_nats::connect("127.0.0.1").await.unwrap();
+        let js = create_js_context().await;
+        clear_streams(js.clone()).await;
+
+        let client = NatsClient::new(js.clone(), Namespace::default());
+        let (_e, c) = client.ensure_streams().await.unwrap();
+        client.ensure_dead_letter_stream().await.unwrap();
+
+        let agg = InterestDeclaration::aggregate_for_commands(
+            "Mxbob",
+            "order",
+            "order_id",
+            LinkDefinition::default(),
+        );
+        let consumer_name = agg.consumer_name();
+        let mut cc = CommandConsumer::try_new(c, agg).await.unwrap();
+
+        let mut headers = async_nats::HeaderMap::new();
+        headers.insert("Producer", "legacy-api");
+        nc.publish_with_headers(
+            "cc.commands.order".to_string(),
+            headers,
+            b"not a command".to_vec().into(),
+        )
+        .await
+        .unwrap();
+        publish_command(
+            &nc,
+            "order",
+            &RawCommand {
+                command_type: "test_one".to_string(),
+                key: "alfred".to_string(),
+                data: json!({}),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        // The bad command is moved aside and the consumer carries on with the next one
+        let mut cmd = wait_for_command(&mut cc).await;
+        assert_eq!(cmd.key, "alfred");
+        cmd.ack().await.expect("Should be able to ack message");
+
+        let quarantined = DeadLetterQueue::new(js.clone(), Namespace::default())
+            .list(Some(&consumer_name))
+            .await
+            .unwrap();
+        assert_eq!(1, quarantined.len());
+        let letter = &quarantined[0].letter;
+        assert_eq!("cc.commands.order", letter.subject);
+        assert_eq!(1, letter.stream_sequence);
+        assert_eq!(
+            Some(&vec!["legacy-api".to_string()]),
+            letter.headers.get("Producer")
+        );
+        assert!(letter
+            .error
+            .as_deref()
+            .unwrap()
+            .starts_with("Unable to decode message"));
+        assert_eq!(b"not a command".to_vec(), quarantined[0].payload);
+
+        clear_streams(js).await;
+    }
+
     #[tokio::test]
     async fn command_consumer_fails_for_non_agg() {
         let js = create_js_context().await;
-        let client = NatsClient::new(js.clone());
+        let client = NatsClient::new(js.clone(), Namespace::default());
         let (_e, c) = client.ensure_streams().await.unwrap();
         let not_an_aggregate = InterestDeclaration {
+            namespace: Namespace::default(),
             actor_id: "bob".to_string(),
             entity_name: "testbob".to_string(),
             key_field: "order_id".to_string(),
@@ -202,7 +300,7 @@ mod test {
     #[tokio::test]
     async fn command_consumer_creates_on_happy_path() {
         let js = create_js_context().await;
-        let client = NatsClient::new(js.clone());
+        let client = NatsClient::new(js.clone(), Namespace::default());
         let (_e, c) = client.ensure_streams().await.unwrap();
 
         let agg = InterestDeclaration::aggregate_for_commands(
@@ -217,6 +315,44 @@ mod test {
         clear_streams(js).await;
     }
 
+    #[tokio::test]
+    async fn command_consumer_updates_retry_settings() {
+        let js = create_js_context().await;
+        clear_streams(js.clone()).await;
+        let client = NatsClient::new(js.clone(), Namespace::default());
+        let (_e, c) = client.ensure_streams().await.unwrap();
+
+        let agg = InterestDeclaration::aggregate_for_commands(
+            "Mxbob",
+            "superbob",
+            "order_id",
+            LinkDefinition::default(),
+        );
+        CommandConsumer::try_new(c.clone(), agg.clone())
+            .await
+            .unwrap();
+        let info = c.consumer_info(agg.consumer_name()).await.unwrap();
+        assert_eq!(3, info.config.max_deliver);
+        assert!(info.config.backoff.is_empty());
+
+        let mut ld = LinkDefinition::default();
+        ld.values.insert("max_deliver".to_string(), "4".to_string());
+        ld.values.insert("ack_wait".to_string(), "10".to_string());
+        ld.values.insert("backoff".to_string(), "1,5".to_string());
+        let agg = InterestDeclaration::aggregate_for_commands("Mxbob", "superbob", "order_id", ld);
+        CommandConsumer::try_new(c.clone(), agg.clone())
+            .await
+            .unwrap();
+        let info = c.consumer_info(agg.consumer_name()).await.unwrap();
+        assert_eq!(4, info.config.max_deliver);
+        assert_eq!(
+            vec![Duration::from_secs(11), Duration::from_secs(15)],
+            info.config.backoff
+        );
+
+        clear_streams(js).await;
+    }
+
     async fn wait_for_command(
         mut stream: impl Stream<Item = Result<AckableMessage<RawCommand>, async_nats::Error>> + Unpin,
     ) -> AckableMessage<RawCommand> {
@@ -1,13 +1,16 @@
-use crate::config::InterestDeclaration;
+use crate::config::{ActorInterest, InterestDeclaration, RetryPolicy};
 
 use cloudevents::{AttributesWriter, Event as CloudEvent};
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
-use crate::natsclient::{AckableMessage, DEFAULT_ACK_TIME};
+use crate::natsclient::AckableMessage;
 use async_nats::{
     jetstream::{
-        consumer::pull::{Config as PullConfig, Stream as MessageStream},
+        consumer::{
+            pull::{Config as PullConfig, Stream as MessageStream},
+            DeliverPolicy, Info as ConsumerInfo,
+        },
         stream::Stream as JsStream,
     },
     Error as NatsError,
@@ -15,15 +18,16 @@ use async_nats::{
 use case::CaseExt;
 use cloudevents::AttributesReader;
 use futures::{Stream, TryStreamExt};
-use tracing::{error, warn};
+use tracing::info;
 
-use super::{impl_Stream, CreateConsumer};
+use super::{impl_Stream, update_retry_policy, CreateConsumer};
 
 #[allow(dead_code)]
 pub struct EventConsumer {
     stream: MessageStream,
     interest: InterestDeclaration,
     name: String,
+    retry: RetryPolicy,
 }
 
 impl EventConsumer {
@@ -39,6 +43,9 @@ impl EventConsumer {
     ) -> ::std::result::Result<EventConsumer, NatsError> {
         let consumer_name = interest.consumer_name();
         let friendly_name = interest.to_string();
+        let deliver_policy = initial_deliver_policy(&stream, &interest, &consumer_name).await;
+        let filters = event_filters(&interest);
+        let retry = interest.extract_retry_policy();
 
         let consumer = stream
             .get_or_create_consumer(
@@ -48,17 +55,29 @@ impl EventConsumer {
                     name: Some(consumer_name.clone()),
                     description: Some(format!("Durable event consumer for {friendly_name}")),
                     ack_policy: async_nats::jetstream::consumer::AckPolicy::Explicit,
-                    ack_wait: DEFAULT_ACK_TIME,
-                    // poison pill identified after 3 nacks
-                    max_deliver: 3,
-                    deliver_policy: async_nats::jetstream::consumer::DeliverPolicy::All,
-                    // TODO: when NATS server and async nats client support it, convert this
-                    // to declare explicit per-event interest rather than subscribing to all
-                    //filter_subject: "cc.events.a,cc.events.b,etc".to_string(),
+                    ack_wait: retry.initial_ack_wait(),
+                    // poison pill identified after max_deliver nacks, at which point it is moved to the dead
+                    // letter stream
+                    max_deliver: retry.max_deliver,
+                    backoff: retry.ack_timeouts(),
+                    deliver_policy,
+                    // A single filter is sent on its own so that servers older than 2.10 still accept it
+                    filter_subject: if filters.len() == 1 {
+                        filters[0].clone()
+                    } else {
+                        String::new()
+                    },
+                    filter_subjects: if filters.len() > 1 {
+                        filters.clone()
+                    } else {
+                        Vec::new()
+                    },
                     ..Default::default()
                 },
             )
             .await?;
+        log_if_unfiltered(consumer.cached_info(), &filters);
+        let consumer = update_retry_policy(&stream, consumer, &retry).await?;
 
         let info = consumer.cached_info();
         let messages = consumer
@@ -70,10 +89,84 @@ impl EventConsumer {
             stream: messages,
             interest,
             name: info.name.to_string(),
+            retry,
         })
     }
 }
 
+/// The subjects a consumer needs, so that the server only delivers the events an actor declared interest in.
+/// Workers still check each event's type and stream, since durables created before filtering was introduced
+/// keep receiving every event
+fn event_filters(interest: &InterestDeclaration) -> Vec<String> {
+    let namespace = &interest.namespace;
+    let event_types: Vec<&String> = match &interest.interest {
+        // Legacy subjects don't include the stream, so aggregates can only be filtered with keyed subjects
+        ActorInterest::AggregateStream(stream) => {
+            return namespace.stream_event_filter(stream).into_iter().collect()
+        }
+        ActorInterest::EventList(types) => types.iter().collect(),
+        ActorInterest::ProcessManager(lifetime) => std::iter::once(&lifetime.start)
+            .chain(lifetime.advance.iter())
+            .chain(lifetime.stop.iter())
+            .collect(),
+        ActorInterest::None => Vec::new(),
+    };
+
+    let mut filters: Vec<String> = Vec::new();
+    for event_type in event_types.into_iter().filter(|t| !t.is_empty()) {
+        for filter in namespace.event_type_filters(event_type) {
+            // The server rejects overlapping filters, so duplicates have to be removed
+            if !filters.contains(&filter) {
+                filters.push(filter);
+            }
+        }
+    }
+    filters
+}
+
+fn log_if_unfiltered(info: &ConsumerInfo, filters: &[String]) {
+    let mut existing: Vec<&String> = info.config.filter_subjects.iter().collect();
+    if !info.config.filter_subject.is_empty() {
+        existing.push(&info.config.filter_subject);
+    }
+    if existing.is_empty() && !filters.is_empty() {
+        info!(
+            consumer_name = info.name,
+            "Existing durable consumer was created without subject filters and will keep receiving every event. Delete it to have it recreated with filters"
+        );
+    }
+}
+
+/// Determines where a consumer should start if it has to be created. Consumers that previously ran under their
+/// legacy, entity-only name (e.g. `PROJ_bankaccount`) resume right after that durable's ack floor so that the
+/// rename doesn't cause every event to be replayed. The legacy durable is left in place, since other actors
+/// sharing the entity name may still need to migrate from it
+async fn initial_deliver_policy(
+    stream: &JsStream,
+    interest: &InterestDeclaration,
+    consumer_name: &str,
+) -> DeliverPolicy {
+    let Some(legacy_name) = interest.legacy_consumer_name() else {
+        return DeliverPolicy::All;
+    };
+    if legacy_name == consumer_name || stream.consumer_info(consumer_name).await.is_ok() {
+        return DeliverPolicy::All;
+    }
+    match stream.consumer_info(&legacy_name).await {
+        Ok(legacy) => {
+            let start_sequence = legacy.ack_floor.stream_sequence + 1;
+            info!(
+                legacy_name,
+                consumer_name,
+                start_sequence,
+                "Migrating legacy durable consumer. The legacy consumer can be deleted once all actors sharing it have migrated"
+            );
+            DeliverPolicy::ByStartSequence { start_sequence }
+        }
+        Err(_) => DeliverPolicy::All,
+    }
+}
+
 // Creates a futures::Stream for EventConsumer, pulling items of type CloudEvent
 impl_Stream!(EventConsumer; CloudEvent);
 
@@ -91,19 +184,22 @@ impl CreateConsumer for EventConsumer {
 
 #[cfg(test)]
 pub(crate) mod test {
-    use cloudevents::{Data, Event as CloudEvent};
+    use cloudevents::{AttributesReader, Data, Event as CloudEvent};
     use futures::{Stream, TryStreamExt};
     use tokio::time::timeout;
     use wasmbus_rpc::core::LinkDefinition;
 
     use crate::{
-        config::InterestDeclaration,
-        consumers::EventConsumer,
+        config::{ActorInterest, ActorRole, InterestDeclaration, ProcessManagerLifetime},
+        consumers::{event_consumer::event_filters, EventConsumer},
+        events::{last_event_sequence, publish_es_event, Causation},
+        eventsourcing::Event as ConcordanceEvent,
         natsclient::{
             test::create_js_context,
             test::{clear_streams, publish_event},
-            AckableMessage, NatsClient, SEND_TIMEOUT_DURATION,
+            AckableMessage, Namespace, NatsClient, SEND_TIMEOUT_DURATION,
         },
+        schemas::Schemas,
     };
 
     pub const EVENT1: &str = r##"
@@ -141,7 +237,7 @@ pub(crate) mod test {
         let js = create_js_context().await;
         clear_streams(js.clone()).await;
 
-        let client = NatsClient::new(js.clone());
+        let client = NatsClient::new(js.clone(), Namespace::default());
         let (e, _c) = client.ensure_streams().await.unwrap();
 
         let agg = InterestDeclaration::aggregate_for_events(
@@ -183,6 +279,199 @@ pub(crate) mod test {
         clear_streams(js.clone()).await;
     }
 
+    #[tokio::test]
+    async fn event_consumer_migrates_from_legacy_name() {
+        let nc = async_nats::connect("127.0.0.1").await.unwrap();
+        let js = create_js_context().await;
+        clear_streams(js.clone()).await;
+
+        let client = NatsClient::new(js.clone(), Namespace::default());
+        let (e, _c) = client.ensure_streams().await.unwrap();
+
+        // An explicit consumer name matching the old naming scheme stands in for a pre-existing durable
+        let mut legacy_ld = LinkDefinition::default();
+        legacy_ld
+            .values
+            .insert("consumer_name".to_string(), "PROJ_order".to_string());
+        let interest = ActorInterest::EventList(vec![
+            "amount_withdrawn".to_string(),
+            "amount_deposited".to_string(),
+        ]);
+        let legacy = InterestDeclaration::new(
+            "Mxbob",
+            "order",
+            ActorRole::Projector,
+            "order_id",
+            interest.clone(),
+            legacy_ld,
+        );
+        let mut ec = EventConsumer::try_new(e.clone(), legacy).await.unwrap();
+
+        publish_event(&nc, "amount_withdrawn", EVENT1)
+            .await
+            .unwrap();
+        publish_event(&nc, "amount_deposited", EVENT2)
+            .await
+            .unwrap();
+        wait_for_event(&mut ec).await.ack().await.unwrap();
+
+        let migrated = InterestDeclaration::new(
+            "Mxbob",
+            "order",
+            ActorRole::Projector,
+            "order_id",
+            interest,
+            LinkDefinition::default(),
+        );
+        assert_eq!("PROJ_order_Mxbob", migrated.consumer_name());
+        let mut ec = EventConsumer::try_new(e, migrated).await.unwrap();
+
+        // Only the event the legacy consumer never acked should be delivered
+        let evt = wait_for_event(&mut ec).await;
+        assert_eq!(evt.ty(), "amount_deposited");
+
+        clear_streams(js.clone()).await;
+    }
+
+    #[tokio::test]
+    async fn keyed_aggregate_consumer_filters_by_stream() {
+        let js = create_js_context().await;
+        clear_streams(js.clone()).await;
+
+        let ns = Namespace::default().with_keyed_events(true);
+        let client = NatsClient::new(js.clone(), ns.clone());
+        let (e, _c) = client.ensure_streams().await.unwrap();
+
+        let agg = InterestDeclaration::aggregate_for_events(
+            "Mxbob",
+            "order",
+            "order_id",
+            LinkDefinition::default(),
+        )
+        .with_namespace(ns.clone());
+        let mut ec = EventConsumer::try_new(e, agg).await.unwrap();
+
+        let event = |stream: &str, event_type: &str| ConcordanceEvent {
+            event_type: event_type.to_string(),
+            stream: stream.to_string(),
+            payload: br#"{"order_id": "ORD1"}"#.to_vec(),
+            metadata: None,
+        };
+        let first = publish_es_event(
+            &js,
+            &ns,
+            &Schemas::default(),
+            event("invoice", "invoice_created"),
+            "ORD1",
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        let expected = last_event_sequence(&js, &ns, "order", "ORD1")
+            .await
+            .unwrap();
+        assert_eq!(0, expected.sequence);
+        let causation = Causation {
+            correlation_id: "CMD1".to_string(),
+            causation_id: "CMD1".to_string(),
+        };
+        let second = publish_es_event(
+            &js,
+            &ns,
+            &Schemas::default(),
+            event("order", "order_created"),
+            "ORD1",
+            Some((&causation, 0)),
+            Some(&expected),
+        )
+        .await
+        .unwrap();
+        assert!(second > first);
+        // Publishing it again, as when a command is handled again, is dropped by the stream
+        let again = publish_es_event(
+            &js,
+            &ns,
+            &Schemas::default(),
+            event("order", "order_created"),
+            "ORD1",
+            Some((&causation, 0)),
+            Some(&expected),
+        )
+        .await
+        .unwrap();
+        assert_eq!(second, again);
+        assert_eq!(
+            second,
+            last_event_sequence(&js, &ns, "order", "ORD1")
+                .await
+                .unwrap()
+                .sequence
+        );
+
+        // The invoice event isn't on the aggregate's stream, so it's never delivered
+        let evt = wait_for_event(&mut ec).await;
+        assert_eq!(evt.ty(), "order_created");
+
+        clear_streams(js.clone()).await;
+    }
+
+    #[test]
+    fn derives_filters_from_interest() {
+        let projector = InterestDeclaration::new(
+            "Mxbob",
+            "order",
+            ActorRole::Projector,
+            "order_id",
+            ActorInterest::EventList(vec![
+                "amount_withdrawn".to_string(),
+                "amount_deposited".to_string(),
+                "amount_withdrawn".to_string(),
+            ]),
+            LinkDefinition::default(),
+        );
+        assert_eq!(
+            vec!["cc.events.amount_withdrawn", "cc.events.amount_deposited"],
+            event_filters(&projector)
+        );
+
+        let pm = InterestDeclaration::new(
+            "Mxbob",
+            "shipping",
+            ActorRole::ProcessManager,
+            "order_id",
+            ActorInterest::ProcessManager(ProcessManagerLifetime {
+                start: "order_created".to_string(),
+                advance: vec!["order_paid".to_string()],
+                stop: vec!["order_shipped".to_string()],
+            }),
+            LinkDefinition::default(),
+        )
+        .with_namespace(Namespace::default().with_keyed_events(true));
+        assert_eq!(
+            vec![
+                "cc.events.order_created",
+                "cc.events.*.*.order_created",
+                "cc.events.order_paid",
+                "cc.events.*.*.order_paid",
+                "cc.events.order_shipped",
+                "cc.events.*.*.order_shipped",
+            ],
+            event_filters(&pm)
+        );
+
+        // Aggregates can only be filtered once their stream is part of the subject
+        let agg = InterestDeclaration::aggregate_for_events(
+            "Mxbob",
+            "order",
+            "order_id",
+            LinkDefinition::default(),
+        );
+        assert!(event_filters(&agg).is_empty());
+        let agg = agg.with_namespace(Namespace::default().with_keyed_events(true));
+        assert_eq!(vec!["cc.events.order.>"], event_filters(&agg));
+    }
+
     #[tokio::test]
     async fn nack_and_rereceive() {
         //TODO
@@ -1,33 +1,188 @@
-use async_nats::jetstream::stream::Stream as NatsStream;
-use futures::{Stream, StreamExt};
-use std::{collections::HashMap, sync::Arc};
-use tokio::sync::RwLock;
-use tracing::{error, trace, Instrument};
+use async_nats::jetstream::{
+    consumer::{Config as ConsumerConfig, DeliverPolicy},
+    stream::Stream as NatsStream,
+};
+use futures::{FutureExt, Stream, StreamExt};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fmt::Debug,
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc, oneshot, watch, RwLock},
+    task::JoinHandle,
+    time::Instant,
+};
+use tracing::{debug, error, trace, warn, Instrument};
 
 use crate::{
     config::{InterestConstraint, InterestDeclaration},
-    natsclient::AckableMessage,
+    deadletter::FailureLog,
+    natsclient::{AckableMessage, InProgress},
 };
 
-use super::{CreateConsumer, WorkError, WorkHandles, WorkResult, Worker};
+use super::{CreateConsumer, DecodeMessage, WorkError, WorkHandles, WorkResult, Worker};
+
+/// Delay before the first attempt to recreate a consumer that has stopped
+const INITIAL_RESTART_BACKOFF: Duration = Duration::from_millis(500);
+/// Upper bound for the exponential backoff between consumer restarts
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(30);
+/// Number of back-to-back errors from a consumer's message stream before we give up on it and let
+/// the supervisor recreate it
+const MAX_CONSECUTIVE_STREAM_ERRORS: u32 = 10;
+/// Number of times in a row a consumer can stop, or fail to be recreated, before it is reported as failing even
+/// while it is running
+const FAILING_CONSECUTIVE_FAILURES: u32 = 3;
+/// Number of messages that can wait on each partition when a consumer processes messages concurrently. Waiting
+/// messages are reported as in progress so that they aren't redelivered, but their keys can't be worked on by
+/// another partition in the meantime, so the queue is kept small
+const PARTITION_QUEUE_DEPTH: usize = 4;
+
+/// The running task for a single consumer along with what its supervisor has been through keeping the
+/// underlying NATS consumer alive
+pub(crate) struct ConsumerHandle {
+    pub(crate) handle: JoinHandle<WorkResult<()>>,
+    pub(crate) supervision: Supervision,
+    pub(crate) control: mpsc::Sender<ControlRequest>,
+}
+
+/// What a consumer's supervisor has been through keeping the underlying NATS consumer alive
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SupervisorState {
+    /// Number of times the consumer has stopped and been recreated
+    pub restarts: u32,
+    /// Whether the supervisor is waiting to recreate a consumer that stopped
+    pub restarting: bool,
+    /// Number of times in a row the consumer stopped or couldn't be recreated without running healthily in
+    /// between
+    pub consecutive_failures: u32,
+    /// The error the consumer last stopped with, or that recreating it last failed with
+    pub last_error: Option<String>,
+    /// Number of times in a row the consumer's info couldn't be fetched from the server
+    pub consecutive_info_failures: u32,
+}
+
+impl SupervisorState {
+    /// Whether the consumer is down, keeps stopping soon after each time it is recreated, or the server keeps
+    /// failing to report on it
+    pub fn is_failing(&self) -> bool {
+        self.restarting
+            || self.consecutive_failures >= FAILING_CONSECUTIVE_FAILURES
+            || self.consecutive_info_failures >= FAILING_CONSECUTIVE_FAILURES
+    }
+
+    fn stopped(&mut self, error: String) {
+        self.restarts += 1;
+        self.restarting = true;
+        self.consecutive_failures += 1;
+        self.last_error = Some(error);
+    }
+
+    fn recreate_failed(&mut self, error: String) {
+        self.consecutive_failures += 1;
+        self.last_error = Some(error);
+    }
+
+    fn recreated(&mut self) {
+        self.restarting = false;
+    }
+
+    /// The consumer has run for long enough to be considered healthy again
+    fn settled(&mut self) {
+        self.consecutive_failures = 0;
+    }
+
+    fn info_fetched(&mut self) {
+        self.consecutive_info_failures = 0;
+    }
+
+    fn info_failed(&mut self) {
+        self.consecutive_info_failures += 1;
+    }
+}
+
+/// A consumer's [`SupervisorState`], updated by its supervisor and read for health reporting
+#[derive(Clone, Default)]
+pub(crate) struct Supervision(Arc<Mutex<SupervisorState>>);
+
+impl Supervision {
+    pub(crate) fn state(&self) -> SupervisorState {
+        self.0.lock().map(|s| s.clone()).unwrap_or_default()
+    }
+
+    fn update(&self, f: impl FnOnce(&mut SupervisorState)) {
+        if let Ok(mut state) = self.0.lock() {
+            f(&mut state);
+        }
+    }
+}
+
+/// Requests handled by a consumer's supervisor alongside its regular work
+pub(crate) enum ControlRequest {
+    /// Move the durable consumer to a new starting point
+    Reset {
+        deliver_policy: DeliverPolicy,
+        done: oneshot::Sender<Result<DeliverPolicy, async_nats::Error>>,
+    },
+    /// Hand a message that isn't in the consumer, such as a dead letter, to the consumer's worker
+    Redeliver {
+        payload: Vec<u8>,
+        stream_sequence: u64,
+        done: oneshot::Sender<WorkResult<()>>,
+    },
+    /// Stop pulling messages and leave the durable consumer alone while another provider instance resets it,
+    /// until resumed or until the lease runs out
+    Pause {
+        lease: Duration,
+        done: oneshot::Sender<()>,
+    },
+    /// Bind to the durable consumer again after a pause
+    Resume,
+}
+
+/// What a supervisor listens to besides its consumer
+struct Signals {
+    shutdown: watch::Receiver<bool>,
+    control: mpsc::Receiver<ControlRequest>,
+}
+
+/// A point-in-time view of a single consumer, used for health reporting
+#[derive(Debug, Clone)]
+pub struct ConsumerStatus {
+    pub interest: InterestDeclaration,
+    /// Whether the consumer's worker task is still alive
+    pub running: bool,
+    pub supervisor: SupervisorState,
+    /// Number of messages in the stream not yet delivered to this consumer, if the consumer info could be fetched
+    pub pending: Option<u64>,
+    /// Number of messages delivered but not yet acknowledged, if the consumer info could be fetched
+    pub ack_pending: Option<usize>,
+}
 
 #[derive(Clone)]
 pub struct ConsumerManager {
     handles: WorkHandles,
     evt_stream: NatsStream,
     cmd_stream: NatsStream,
+    shutdown: Arc<watch::Sender<bool>>,
+    /// Errors from the final delivery of messages, kept until they are copied into their dead letters
+    failures: FailureLog,
 }
 
 impl ConsumerManager {
     pub fn new(evt_stream: NatsStream, cmd_stream: NatsStream) -> ConsumerManager {
+        let (shutdown, _) = watch::channel(false);
         ConsumerManager {
             handles: Arc::new(RwLock::new(HashMap::default())),
             evt_stream,
             cmd_stream,
+            shutdown: Arc::new(shutdown),
+            failures: FailureLog::default(),
         }
     }
 
-    #[allow(unused)]
     pub async fn consumers(&self) -> Vec<InterestDeclaration> {
         let keys = {
             let lock = self.handles.read().await;
@@ -45,24 +200,44 @@ impl ConsumerManager {
         W: Worker + Send + Sync + 'static,
         C: Stream<Item = Result<AckableMessage<W::Message>, async_nats::Error>>
             + CreateConsumer<Output = C>
+            + DecodeMessage<Message = W::Message>
             + Send
             + Unpin
             + 'static,
     {
         let i = interest.clone();
         if !self.has_consumer(&interest).await {
-            let consumer = if interest.interest_constraint == InterestConstraint::Commands {
-                C::create(self.cmd_stream.clone(), interest.clone()).await?
-            } else {
-                C::create(self.evt_stream.clone(), interest.clone()).await?
-            };
+            let stream = self.stream_for(&interest).clone();
+            // The first consumer is created up front so that configuration problems are reported to
+            // the caller rather than retried forever by the supervisor
+            let consumer = C::create(stream.clone(), interest.clone()).await?;
 
+            let supervision = Supervision::default();
+            let (control, control_requests) = mpsc::channel(1);
             let handle = tokio::spawn(
-                work_fn(consumer, worker, interest)
-                    .instrument(tracing::info_span!("consumer_worker", %i)),
+                supervise::<C, W>(
+                    consumer,
+                    stream,
+                    worker,
+                    interest,
+                    supervision.clone(),
+                    Signals {
+                        shutdown: self.shutdown.subscribe(),
+                        control: control_requests,
+                    },
+                    self.failures.clone(),
+                )
+                .instrument(tracing::info_span!("consumer_worker", %i)),
             );
             let mut handles = self.handles.write().await;
-            handles.insert(i.clone(), handle);
+            handles.insert(
+                i.clone(),
+                ConsumerHandle {
+                    handle,
+                    supervision,
+                    control,
+                },
+            );
         }
         Ok(())
     }
@@ -74,57 +249,752 @@ impl ConsumerManager {
             .read()
             .await
             .get(interest)
-            .map(|handle| !handle.is_finished())
+            .map(|ch| !ch.handle.is_finished())
             .unwrap_or(false)
     }
+
+    /// Returns the number of times the consumer for the given interest declaration has been recreated
+    /// by its supervisor, or `None` if there is no such consumer. Health checks get this from
+    /// [`consumer_statuses`](Self::consumer_statuses) instead
+    #[cfg(test)]
+    pub async fn restart_count(&self, interest: &InterestDeclaration) -> Option<u32> {
+        self.handles
+            .read()
+            .await
+            .get(interest)
+            .map(|ch| ch.supervision.state().restarts)
+    }
+
+    /// Reports the liveness of every consumer task and the state of its supervisor, along with the pending counts
+    /// the NATS server has for its durable consumer
+    pub async fn consumer_statuses(&self) -> Vec<ConsumerStatus> {
+        let tasks: Vec<_> = {
+            let handles = self.handles.read().await;
+            handles
+                .iter()
+                .map(|(decl, ch)| {
+                    (
+                        decl.clone(),
+                        !ch.handle.is_finished(),
+                        ch.supervision.clone(),
+                    )
+                })
+                .collect()
+        };
+
+        let mut statuses = Vec::with_capacity(tasks.len());
+        for (interest, running, supervision) in tasks {
+            let info = self
+                .stream_for(&interest)
+                .consumer_info(interest.consumer_name())
+                .await
+                .map_err(|e| warn!(error = %e, %interest, "Failed to fetch consumer info"))
+                .ok();
+            // A single failure is usually transient, so only several in a row count against the consumer
+            supervision.update(if info.is_some() {
+                SupervisorState::info_fetched
+            } else {
+                SupervisorState::info_failed
+            });
+            statuses.push(ConsumerStatus {
+                running,
+                supervisor: supervision.state(),
+                pending: info.as_ref().map(|i| i.num_pending),
+                ack_pending: info.as_ref().map(|i| i.num_ack_pending),
+                interest,
+            });
+        }
+        statuses
+    }
+
+    /// Stops and removes every consumer belonging to the given actor, returning the interest declarations
+    /// that were removed. If a declaration's link definition asks for it, the durable NATS consumer is
+    /// deleted as well, otherwise it is left in place so a redeployed actor resumes where it left off
+    pub async fn remove_consumers(&self, actor_id: &str) -> Vec<InterestDeclaration> {
+        let removed: Vec<_> = {
+            let mut handles = self.handles.write().await;
+            let decls: Vec<_> = handles
+                .keys()
+                .filter(|decl| decl.actor_id == actor_id)
+                .cloned()
+                .collect();
+            decls
+                .into_iter()
+                .filter_map(|decl| handles.remove(&decl).map(|handle| (decl, handle)))
+                .collect()
+        };
+
+        let mut decls = Vec::with_capacity(removed.len());
+        for (decl, ch) in removed {
+            ch.handle.abort();
+            // Wait for the task to actually stop so that any in-flight message is released (and nacked)
+            // before we potentially delete the consumer out from under it
+            let _ = ch.handle.await;
+            debug!(%decl, "Stopped consumer worker");
+
+            if decl.extract_delete_consumer_on_unlink() {
+                self.delete_durable(&decl).await;
+            }
+            decls.push(decl);
+        }
+        decls
+    }
+
+    /// Moves the durable consumer for the given interest declaration to a new starting point, e.g. to replay
+    /// a projector from the start of the event stream. The consumer's worker is paused while this happens: it
+    /// stops pulling messages, finishes the message it is working on, and resumes from the new starting point
+    /// once the durable consumer has been recreated. Returns the starting point the recreated consumer has
+    pub async fn reset_consumer(
+        &self,
+        interest: &InterestDeclaration,
+        deliver_policy: DeliverPolicy,
+    ) -> Result<DeliverPolicy, async_nats::Error> {
+        let control = self.control_for(interest).await?;
+        let (done, result) = oneshot::channel();
+        control
+            .send(ControlRequest::Reset {
+                deliver_policy,
+                done,
+            })
+            .await
+            .map_err(|_| format!("Consumer for {interest} stopped before it could be reset"))?;
+        result
+            .await
+            .map_err(|_| format!("Consumer for {interest} stopped while being reset"))?
+    }
+
+    /// Hands a raw message payload, such as a dead letter, to the worker of the consumer for the given interest
+    /// declaration and waits for the worker to finish with it. The message is decoded the same way the consumer
+    /// decodes the messages it pulls, and keeps the sequence it originally had in the consumer's stream
+    pub async fn redeliver(
+        &self,
+        interest: &InterestDeclaration,
+        payload: Vec<u8>,
+        stream_sequence: u64,
+    ) -> Result<(), async_nats::Error> {
+        let control = self.control_for(interest).await?;
+        let (done, result) = oneshot::channel();
+        control
+            .send(ControlRequest::Redeliver {
+                payload,
+                stream_sequence,
+                done,
+            })
+            .await
+            .map_err(|_| {
+                format!("Consumer for {interest} stopped before the message was delivered")
+            })?;
+        result
+            .await
+            .map_err(|_| format!("Consumer for {interest} stopped while handling the message"))?
+            .map_err(|e| e.to_string().into())
+    }
+
+    /// Pauses the workers of every consumer bound to the durable consumer with the given name while another
+    /// provider instance resets it. The workers stop pulling messages right away and don't bind to the durable
+    /// consumer again until they are resumed or the lease runs out, so they can't recreate it from their initial
+    /// starting point in the meantime. Returns the number of workers paused
+    pub async fn pause_consumer(&self, consumer_name: &str, lease: Duration) -> usize {
+        let mut paused = 0;
+        for control in self.controls_named(consumer_name).await {
+            let (done, result) = oneshot::channel();
+            if control
+                .send(ControlRequest::Pause { lease, done })
+                .await
+                .is_ok()
+                && result.await.is_ok()
+            {
+                paused += 1;
+            }
+        }
+        paused
+    }
+
+    /// Resumes the workers paused by [`pause_consumer`](Self::pause_consumer)
+    pub async fn resume_consumer(&self, consumer_name: &str) {
+        for control in self.controls_named(consumer_name).await {
+            let _ = control.send(ControlRequest::Resume).await;
+        }
+    }
+
+    /// Returns the error recorded when the final delivery of a message to the given consumer failed, if this
+    /// provider saw it fail. The error is forgotten once returned
+    pub fn take_failure(&self, consumer_name: &str, stream_sequence: u64) -> Option<String> {
+        self.failures.take(consumer_name, stream_sequence)
+    }
+
+    async fn control_for(
+        &self,
+        interest: &InterestDeclaration,
+    ) -> Result<mpsc::Sender<ControlRequest>, async_nats::Error> {
+        self.handles
+            .read()
+            .await
+            .get(interest)
+            .filter(|ch| !ch.handle.is_finished())
+            .map(|ch| ch.control.clone())
+            .ok_or_else(|| format!("No running consumer for {interest}").into())
+    }
+
+    async fn controls_named(&self, consumer_name: &str) -> Vec<mpsc::Sender<ControlRequest>> {
+        self.handles
+            .read()
+            .await
+            .iter()
+            .filter(|(decl, ch)| decl.consumer_name() == consumer_name && !ch.handle.is_finished())
+            .map(|(_, ch)| ch.control.clone())
+            .collect()
+    }
+
+    /// Stops all consumers from pulling new messages and waits up to `timeout` for each worker to finish
+    /// (and ack) the message it is currently processing. Messages a consumer pulled but hadn't handed to its
+    /// worker yet are nacked. Workers still busy after the deadline are aborted, in which case their in-flight
+    /// messages are nacked as they are dropped
+    pub async fn shutdown(&self, timeout: Duration) {
+        self.shutdown.send_replace(true);
+        let handles: Vec<_> = self.handles.write().await.drain().collect();
+        let deadline = Instant::now() + timeout;
+        for (decl, mut ch) in handles {
+            if tokio::time::timeout_at(deadline, &mut ch.handle)
+                .await
+                .is_err()
+            {
+                warn!(%decl, "Consumer worker did not finish before the shutdown deadline, aborting");
+                ch.handle.abort();
+                // Wait for the task to actually stop so its in-flight message is dropped, sending its nack
+                let _ = ch.handle.await;
+            } else {
+                debug!(%decl, "Consumer worker drained");
+            }
+        }
+    }
+
+    async fn delete_durable(&self, interest: &InterestDeclaration) {
+        let consumer_name = interest.consumer_name();
+        if let Err(e) = self
+            .stream_for(interest)
+            .delete_consumer(&consumer_name)
+            .await
+        {
+            warn!(error = %e, consumer_name, "Failed to delete durable consumer");
+        } else {
+            debug!(consumer_name, "Deleted durable consumer");
+        }
+    }
+
+    fn stream_for(&self, interest: &InterestDeclaration) -> &NatsStream {
+        if interest.interest_constraint == InterestConstraint::Commands {
+            &self.cmd_stream
+        } else {
+            &self.evt_stream
+        }
+    }
+}
+
+/// Runs the given consumer and recreates it with exponential backoff whenever it stops, so that a transient
+/// JetStream outage or NATS reconnect doesn't silently stop an entity until the provider is restarted. This
+/// only returns once shutdown has been signaled (or when the task is aborted)
+async fn supervise<C, W>(
+    consumer: C,
+    stream: NatsStream,
+    worker: W,
+    interest: InterestDeclaration,
+    supervision: Supervision,
+    signals: Signals,
+    failures: FailureLog,
+) -> WorkResult<()>
+where
+    W: Worker + Send + Sync,
+    C: Stream<Item = Result<AckableMessage<W::Message>, async_nats::Error>>
+        + CreateConsumer<Output = C>
+        + DecodeMessage<Message = W::Message>
+        + Unpin,
+{
+    let Signals {
+        mut shutdown,
+        mut control,
+    } = signals;
+    let mut consumer = Some(consumer);
+    let mut backoff = INITIAL_RESTART_BACKOFF;
+    loop {
+        let current = match consumer.take() {
+            Some(c) => c,
+            None => match C::create(stream.clone(), interest.clone()).await {
+                Ok(c) => {
+                    supervision.update(SupervisorState::recreated);
+                    c
+                }
+                Err(e) => {
+                    warn!(error = %e, ?backoff, "Failed to recreate consumer, will retry");
+                    supervision.update(|s| s.recreate_failed(e.to_string()));
+                    if sleep_unless_shutdown(backoff, &mut shutdown).await {
+                        return Ok(());
+                    }
+                    backoff = (backoff * 2).min(MAX_RESTART_BACKOFF);
+                    continue;
+                }
+            },
+        };
+
+        let started = Instant::now();
+        let mut settled = false;
+        // The worker stops pulling messages when either the provider shuts down or a reset is requested
+        let (stop, mut stopped) = watch::channel(*shutdown.borrow());
+        let work = work_fn(current, &worker, &interest, &mut stopped, &failures);
+        tokio::pin!(work);
+        let mut reset = None;
+        let mut pause = None;
+        let res = loop {
+            tokio::select! {
+                res = &mut work => break res,
+                // A consumer that runs healthily for a while is no longer failing
+                _ = tokio::time::sleep_until(started + MAX_RESTART_BACKOFF), if !settled => {
+                    settled = true;
+                    supervision.update(SupervisorState::settled);
+                }
+                _ = shutdown.changed(), if !*stop.borrow() => {
+                    stop.send_replace(true);
+                }
+                Some(req) = control.recv(), if !*stop.borrow() => match req {
+                    ControlRequest::Reset { deliver_policy, done } => {
+                        reset = Some((deliver_policy, done));
+                        stop.send_replace(true);
+                    }
+                    ControlRequest::Redeliver { payload, stream_sequence, done } => {
+                        let res = redeliver::<C, W>(&worker, &payload, stream_sequence).await;
+                        let _ = done.send(res);
+                    }
+                    ControlRequest::Pause { lease, done } => {
+                        pause = Some(lease);
+                        stop.send_replace(true);
+                        let _ = done.send(());
+                    }
+                    ControlRequest::Resume => {}
+                },
+            }
+        };
+        if let Some((deliver_policy, done)) = reset {
+            if let Err(e) = &res {
+                warn!(error = ?e, "Consumer stopped with an error while pausing for a reset");
+            }
+            let result = reset_durable(&stream, &interest, deliver_policy).await;
+            let _ = done.send(result);
+            if *shutdown.borrow() {
+                return Ok(());
+            }
+            // The supervisor binds a fresh consumer to the reset durable on the next pass
+            continue;
+        }
+        if let Some(lease) = pause {
+            if let Err(e) = &res {
+                warn!(error = ?e, "Consumer stopped with an error while pausing");
+            }
+            debug!(
+                ?lease,
+                "Consumer paused while another provider instance resets it"
+            );
+            if wait_for_resume::<C, W>(lease, &worker, &mut control, &mut shutdown).await {
+                return Ok(());
+            }
+            continue;
+        }
+        if *shutdown.borrow() {
+            debug!("Consumer stopped for shutdown");
+            return res;
+        }
+        // A consumer that ran healthily for a while gets a fresh backoff when it stops
+        if started.elapsed() > MAX_RESTART_BACKOFF {
+            backoff = INITIAL_RESTART_BACKOFF;
+        }
+        let restarts = supervision.state().restarts + 1;
+        let error = match res {
+            Err(e) => {
+                error!(error = ?e, restarts, ?backoff, "Consumer stopped, restarting");
+                e.to_string()
+            }
+            Ok(_) => {
+                warn!(restarts, ?backoff, "Consumer ended, restarting");
+                "consumer ended unexpectedly".to_string()
+            }
+        };
+        supervision.update(|s| s.stopped(error));
+        if sleep_unless_shutdown(backoff, &mut shutdown).await {
+            return Ok(());
+        }
+        backoff = (backoff * 2).min(MAX_RESTART_BACKOFF);
+    }
+}
+
+/// Recreates a durable consumer with the same configuration but a new starting point, returning the starting
+/// point the server gave the recreated consumer. Deleting the durable also discards its ack floor, which is the
+/// point of a reset. The new configuration is tried on an ephemeral consumer first so that a starting point the
+/// server rejects leaves the durable untouched. If the durable still can't be recreated, it is restored to
+/// resume right after its previous ack floor, rather than being recreated by the supervisor from the start
+async fn reset_durable(
+    stream: &NatsStream,
+    interest: &InterestDeclaration,
+    deliver_policy: DeliverPolicy,
+) -> Result<DeliverPolicy, async_nats::Error> {
+    let consumer_name = interest.consumer_name();
+    let info = stream.consumer_info(&consumer_name).await?;
+    let mut config = info.config.clone();
+    config.deliver_policy = deliver_policy;
+    check_consumer_config(stream, &config).await?;
+
+    stream.delete_consumer(&consumer_name).await?;
+    let error = match stream.create_consumer::<ConsumerConfig>(config).await {
+        Ok(consumer) => {
+            let info = consumer.cached_info();
+            // Creating a durable that exists with the same configuration returns the existing one, which has
+            // only delivered messages if another provider instance recreated it before this reset did
+            if info.delivered.consumer_sequence > 0 {
+                return Err(format!(
+                    "Another provider instance recreated the consumer before it could be reset, so it starts from {:?}",
+                    info.config.deliver_policy
+                )
+                .into());
+            }
+            let deliver_policy = info.config.deliver_policy;
+            debug!(consumer_name, ?deliver_policy, "Reset durable consumer");
+            return Ok(deliver_policy);
+        }
+        Err(e) => e,
+    };
+
+    let mut restored = info.config.clone();
+    restored.deliver_policy = DeliverPolicy::ByStartSequence {
+        start_sequence: info.ack_floor.stream_sequence + 1,
+    };
+    match stream.create_consumer::<ConsumerConfig>(restored).await {
+        Ok(_) => Err(format!(
+            "Failed to recreate the consumer ({error}), so it resumes after its previous ack floor at stream sequence {}",
+            info.ack_floor.stream_sequence
+        )
+        .into()),
+        Err(e) => Err(format!(
+            "Failed to recreate the consumer ({error}) or restore it ({e}). It will be recreated from its initial starting point"
+        )
+        .into()),
+    }
+}
+
+/// Has the server check a consumer configuration by creating an ephemeral consumer with it
+async fn check_consumer_config(
+    stream: &NatsStream,
+    config: &ConsumerConfig,
+) -> Result<(), async_nats::Error> {
+    let probe = ConsumerConfig {
+        name: None,
+        durable_name: None,
+        description: Some("Checks the configuration of a durable consumer reset".to_string()),
+        // Removed by the server shortly if deleting it below fails
+        inactive_threshold: Duration::from_secs(5),
+        ..config.clone()
+    };
+    let consumer = stream.create_consumer::<ConsumerConfig>(probe).await?;
+    let name = consumer.cached_info().name.clone();
+    if let Err(e) = stream.delete_consumer(&name).await {
+        debug!(error = %e, name, "Failed to delete ephemeral consumer, it will expire on its own");
+    }
+    Ok(())
+}
+
+/// Decodes a message payload the same way the consumer would and hands it to the worker. The message isn't
+/// backed by a consumer delivery, so the worker acking or nacking it has no effect
+async fn redeliver<C, W>(worker: &W, payload: &[u8], stream_sequence: u64) -> WorkResult<()>
+where
+    W: Worker + Send + Sync,
+    C: DecodeMessage<Message = W::Message>,
+{
+    let message = C::decode(payload)
+        .map_err(|e| WorkError::Other(format!("Unable to decode redelivered message: {e}")))?;
+    worker
+        .do_work(AckableMessage::detached(message, stream_sequence))
+        .await
+}
+
+/// Waits until a paused consumer is resumed, handling control requests in the meantime. The pause ends on its own
+/// once the lease runs out, so that a lost resume doesn't stop the consumer for good. Returns `true` if shutdown
+/// is signaled in the meantime
+async fn wait_for_resume<C, W>(
+    lease: Duration,
+    worker: &W,
+    control: &mut mpsc::Receiver<ControlRequest>,
+    shutdown: &mut watch::Receiver<bool>,
+) -> bool
+where
+    W: Worker + Send + Sync,
+    C: DecodeMessage<Message = W::Message>,
+{
+    let mut until = Instant::now() + lease;
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep_until(until) => {
+                warn!("Consumer pause ran out without being resumed, resuming");
+                return *shutdown.borrow();
+            }
+            _ = shutdown.changed() => return true,
+            req = control.recv() => match req {
+                None | Some(ControlRequest::Resume) => return *shutdown.borrow(),
+                Some(ControlRequest::Pause { lease, done }) => {
+                    until = Instant::now() + lease;
+                    let _ = done.send(());
+                }
+                Some(ControlRequest::Reset { done, .. }) => {
+                    let _ = done.send(Err(
+                        "The consumer is paused while another provider instance resets it".into(),
+                    ));
+                }
+                Some(ControlRequest::Redeliver { payload, stream_sequence, done }) => {
+                    let res = redeliver::<C, W>(worker, &payload, stream_sequence).await;
+                    let _ = done.send(res);
+                }
+            },
+        }
+    }
+}
+
+/// Sleeps for the given duration, returning early with `true` if shutdown is signaled in the meantime
+async fn sleep_unless_shutdown(duration: Duration, shutdown: &mut watch::Receiver<bool>) -> bool {
+    tokio::select! {
+        _ = tokio::time::sleep(duration) => *shutdown.borrow(),
+        _ = shutdown.changed() => true,
+    }
 }
 
-async fn work_fn<C, W>(mut consumer: C, worker: W, _interest: InterestDeclaration) -> WorkResult<()>
+async fn work_fn<C, W>(
+    consumer: C,
+    worker: &W,
+    interest: &InterestDeclaration,
+    shutdown: &mut watch::Receiver<bool>,
+    failures: &FailureLog,
+) -> WorkResult<()>
 where
-    W: Worker + Send,
+    W: Worker + Send + Sync,
     C: Stream<Item = Result<AckableMessage<W::Message>, async_nats::Error>> + Unpin,
+{
+    let concurrency = interest.extract_concurrency();
+    if concurrency > 1 {
+        // Reporting progress twice per ack wait leaves room for the report to reach the server in time
+        let progress_interval = interest.extract_retry_policy().ack_wait / 2;
+        partitioned_work_fn(
+            consumer,
+            worker,
+            concurrency,
+            progress_interval,
+            shutdown,
+            failures,
+        )
+        .await
+    } else {
+        sequential_work_fn(consumer, worker, shutdown, failures).await
+    }
+}
+
+async fn sequential_work_fn<C, W>(
+    mut consumer: C,
+    worker: &W,
+    shutdown: &mut watch::Receiver<bool>,
+    failures: &FailureLog,
+) -> WorkResult<()>
+where
+    W: Worker + Send + Sync,
+    C: Stream<Item = Result<AckableMessage<W::Message>, async_nats::Error>> + Unpin,
+{
+    let mut stream_errors = 0;
+    while let Some(msg) = next_message(&mut consumer, shutdown, &mut stream_errors).await? {
+        do_work(worker, msg, failures).await?;
+    }
+    Ok(())
+}
+
+/// Dispatches messages to `concurrency` partitions by hashing the message's partition key, so that messages
+/// for unrelated entities are processed in parallel while messages for the same entity stay in order
+async fn partitioned_work_fn<C, W>(
+    mut consumer: C,
+    worker: &W,
+    concurrency: usize,
+    progress_interval: Duration,
+    shutdown: &mut watch::Receiver<bool>,
+    failures: &FailureLog,
+) -> WorkResult<()>
+where
+    W: Worker + Send + Sync,
+    C: Stream<Item = Result<AckableMessage<W::Message>, async_nats::Error>> + Unpin,
+{
+    // Each message is held in progress from the moment it is dispatched until its partition starts on it, since
+    // it may wait behind slow messages for longer than its ack wait
+    let (senders, receivers): (Vec<_>, Vec<_>) = (0..concurrency)
+        .map(|_| {
+            mpsc::channel::<(AckableMessage<W::Message>, Option<InProgress>)>(PARTITION_QUEUE_DEPTH)
+        })
+        .unzip();
+
+    // If a partition fails, the others are dropped along with their receivers, which stops the dispatcher
+    let partitions =
+        futures::future::try_join_all(receivers.into_iter().map(|mut rx| async move {
+            while let Some((msg, held)) = rx.recv().await {
+                drop(held);
+                do_work(worker, msg, failures).await?;
+            }
+            Ok::<_, WorkError>(())
+        }));
+
+    // The senders are dropped when the dispatcher finishes, letting each partition drain what it was
+    // already handed before it exits
+    let dispatcher = async move {
+        let mut stream_errors = 0;
+        while let Some(msg) = next_message(&mut consumer, shutdown, &mut stream_errors).await? {
+            let partition = partition_for(worker.partition_key(&msg), concurrency);
+            trace!(partition, "Dispatching message to partition");
+            let held = msg.hold(progress_interval);
+            if senders[partition].send((msg, held)).await.is_err() {
+                return Err(WorkError::Other(
+                    "Partition worker stopped unexpectedly".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    };
+
+    let (dispatched, worked) = tokio::join!(dispatcher, partitions);
+    // A failure in a partition is the root cause of any dispatcher error, so it takes precedence
+    worked?;
+    dispatched
+}
+
+/// Gets the next message from the consumer, returning `None` once shutdown is signaled and an error if the
+/// consumer stopped or its stream kept failing. Shutdown is only checked between messages so that the message
+/// currently being worked on is always finished
+async fn next_message<C, M>(
+    consumer: &mut C,
+    shutdown: &mut watch::Receiver<bool>,
+    stream_errors: &mut u32,
+) -> WorkResult<Option<AckableMessage<M>>>
+where
+    C: Stream<Item = Result<AckableMessage<M>, async_nats::Error>> + Unpin,
+    M: Debug,
 {
     loop {
-        // Get next value from stream, returning error if the consumer stopped
-        let res = consumer.next().await.ok_or(WorkError::ConsumerStopped)?;
-        let res = match res {
+        if *shutdown.borrow() {
+            release_pulled(consumer).await;
+            return Ok(None);
+        }
+        let next = tokio::select! {
+            biased;
+            _ = shutdown.changed() => {
+                release_pulled(consumer).await;
+                return Ok(None);
+            }
+            next = consumer.next() => next,
+        };
+        match next.ok_or(WorkError::ConsumerStopped)? {
             Ok(msg) => {
+                *stream_errors = 0;
                 trace!(message = ?msg, "Got message from consumer");
-                worker.do_work(msg).await
+                return Ok(Some(msg));
+            }
+            Err(e) if *stream_errors + 1 >= MAX_CONSECUTIVE_STREAM_ERRORS => {
+                error!(error = %e, "Too many consecutive errors from consumer stream. Giving up on consumer");
+                return Err(WorkError::NatsError(e));
             }
             Err(e) => {
+                *stream_errors += 1;
                 error!(error = %e, "Got error from stream when reading from consumer. Will try again");
-                continue;
             }
-        };
-        match res {
-            // Return fatal errors if they occur
-            Err(e) if matches!(e, WorkError::Fatal(_)) => return Err(e),
-            // For the rest of the errors, right now we just log. Could do nicer retry behavior as this evolves
-            Err(e) => error!(error = ?e, "Got error from worker"),
-            _ => (),
         }
     }
 }
 
+/// Nacks the messages the consumer has already pulled from the server but not handed out, so that they are
+/// redelivered without waiting for their ack wait to run out
+async fn release_pulled<C, M>(consumer: &mut C)
+where
+    C: Stream<Item = Result<AckableMessage<M>, async_nats::Error>> + Unpin,
+{
+    let mut released = 0;
+    while let Some(Some(Ok(mut msg))) = consumer.next().now_or_never() {
+        msg.nack().await;
+        released += 1;
+    }
+    if released > 0 {
+        debug!(released, "Released pulled messages that weren't worked on");
+    }
+}
+
+/// Hands a message to the worker. If the worker fails on the message's final delivery, or fails permanently, the
+/// error is recorded so that it can be included in the message's dead letter
+async fn do_work<W>(
+    worker: &W,
+    msg: AckableMessage<W::Message>,
+    failures: &FailureLog,
+) -> WorkResult<()>
+where
+    W: Worker + Send + Sync,
+{
+    let delivery = msg.delivery();
+    let final_delivery = msg.is_final_delivery();
+    let span = tracing::info_span!("handle_message");
+    span.in_scope(|| msg.continue_trace());
+    let res = worker.do_work(msg).instrument(span).await;
+    if let (Err(e), Some((consumer_name, stream_sequence))) = (&res, delivery) {
+        if final_delivery || matches!(e, WorkError::Permanent(_)) {
+            failures.record(consumer_name, stream_sequence, e.to_string());
+        }
+    }
+    handle_work_result(res)
+}
+
+fn handle_work_result(res: WorkResult<()>) -> WorkResult<()> {
+    match res {
+        // Return fatal errors if they occur
+        Err(e) if matches!(e, WorkError::Fatal(_)) => Err(e),
+        // For the rest of the errors, right now we just log. Could do nicer retry behavior as this evolves
+        Err(e) => {
+            error!(error = ?e, "Got error from worker");
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+fn partition_for(key: Option<String>, partitions: usize) -> usize {
+    match key {
+        Some(key) if !key.is_empty() => {
+            let mut hasher = DefaultHasher::new();
+            key.hash(&mut hasher);
+            (hasher.finish() % partitions as u64) as usize
+        }
+        _ => 0,
+    }
+}
+
 #[cfg(test)]
 mod test {
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use std::sync::Arc;
+    use std::task::{Context, Poll};
 
+    use cloudevents::Event as CloudEvent;
+    use futures::Stream;
     use serde_json::json;
     use tokio::sync::RwLock;
     use wasmbus_rpc::core::LinkDefinition;
 
     use crate::{
-        config::InterestDeclaration,
+        config::{ActorInterest, ActorRole, BucketSettings, InterestDeclaration},
         consumers::{
-            manager::ConsumerManager, CommandConsumer, EventConsumer, RawCommand, WorkResult,
-            Worker,
+            manager::{partition_for, work_fn, ConsumerManager, SupervisorState},
+            CommandConsumer, CreateConsumer, DecodeMessage, EventConsumer, RawCommand, WorkError,
+            WorkResult, Worker,
         },
+        deadletter::FailureLog,
         natsclient::{
             test::{clear_streams, create_js_context, publish_command},
-            AckableMessage, NatsClient,
+            AckableMessage, Namespace, NatsClient, DEFAULT_MAX_DELIVER,
         },
+        schemas::Schemas,
         state::EntityState,
         workers::{AggregateCommandWorker, AggregateEventWorker},
     };
@@ -135,7 +1005,7 @@ mod test {
         let js = create_js_context().await;
         clear_streams(js.clone()).await;
 
-        let client = NatsClient::new(js.clone());
+        let client = NatsClient::new(js.clone(), Namespace::default());
         let (e, c) = client.ensure_streams().await.unwrap();
         let cm = ConsumerManager::new(e, c);
         let interest = InterestDeclaration::aggregate_for_commands(
@@ -144,7 +1014,14 @@ mod test {
             "account_number",
             LinkDefinition::default(),
         );
-        let state = EntityState::new_from_context(&js).await.unwrap();
+        let state = EntityState::new_from_context(
+            &js,
+            &Namespace::default(),
+            &BucketSettings::default(),
+            &BucketSettings::default(),
+        )
+        .await
+        .unwrap();
 
         cm.add_consumer::<AggregateCommandWorker, CommandConsumer>(
             interest.clone(),
@@ -153,6 +1030,7 @@ mod test {
                 context: js.clone(),
                 interest: interest.clone(),
                 state: state.clone(),
+                schemas: Schemas::default(),
             },
         )
         .await
@@ -180,13 +1058,59 @@ mod test {
         assert_eq!(2, cm.consumers().await.len());
     }
 
+    #[tokio::test]
+    async fn removing_actor_stops_its_consumers() {
+        let js = create_js_context().await;
+        clear_streams(js.clone()).await;
+
+        let client = NatsClient::new(js.clone(), Namespace::default());
+        let (e, c) = client.ensure_streams().await.unwrap();
+        let cm = ConsumerManager::new(e.clone(), c);
+
+        let mut ld = LinkDefinition::default();
+        ld.values
+            .insert("delete_consumer_on_unlink".to_string(), "true".to_string());
+        let interest = InterestDeclaration::new(
+            "MXBOB",
+            "bankaccount",
+            ActorRole::Projector,
+            "account_number",
+            ActorInterest::EventList(vec!["account_created".to_string()]),
+            ld,
+        );
+        let other = InterestDeclaration::new(
+            "MXALICE",
+            "bankaccount_audit",
+            ActorRole::Notifier,
+            "account_number",
+            ActorInterest::EventList(vec!["account_created".to_string()]),
+            LinkDefinition::default(),
+        );
+
+        for decl in [&interest, &other] {
+            cm.add_consumer::<MockEventWorker, EventConsumer>(decl.clone(), MockEventWorker)
+                .await
+                .unwrap();
+        }
+        assert_eq!(2, cm.consumers().await.len());
+
+        let removed = cm.remove_consumers("MXBOB").await;
+        assert_eq!(vec![interest.clone()], removed);
+        assert!(!cm.has_consumer(&interest).await);
+        assert!(cm.has_consumer(&other).await);
+        assert!(e.consumer_info(interest.consumer_name()).await.is_err());
+        assert!(e.consumer_info(other.consumer_name()).await.is_ok());
+
+        clear_streams(js).await;
+    }
+
     #[tokio::test]
     async fn command_consumer_worker_function_basic() {
         let nc = async_nats::connect("127.0.0.1").await.unwrap();
         let js = create_js_context().await;
         clear_streams(js.clone()).await;
 
-        let client = NatsClient::new(js.clone());
+        let client = NatsClient::new(js.clone(), Namespace::default());
         let (e, c) = client.ensure_streams().await.unwrap();
         let cm = ConsumerManager::new(e, c);
         let interest = InterestDeclaration::aggregate_for_commands(
@@ -195,7 +1119,14 @@ mod test {
             "account_number",
             LinkDefinition::default(),
         );
-        let _state = EntityState::new_from_context(&js).await.unwrap();
+        let _state = EntityState::new_from_context(
+            &js,
+            &Namespace::default(),
+            &BucketSettings::default(),
+            &BucketSettings::default(),
+        )
+        .await
+        .unwrap();
 
         let msgs = Arc::new(RwLock::new(Vec::new()));
         cm.add_consumer::<MockCommandWorker, CommandConsumer>(
@@ -215,6 +1146,7 @@ mod test {
                 data: json!({
                     "hello": "world"
                 }),
+                ..Default::default()
             },
             RawCommand {
                 command_type: "test_two".to_string(),
@@ -222,6 +1154,7 @@ mod test {
                 data: json!({
                     "hello": "world2"
                 }),
+                ..Default::default()
             },
             RawCommand {
                 command_type: "test_three".to_string(),
@@ -229,6 +1162,7 @@ mod test {
                 data: json!({
                     "hello": "world3"
                 }),
+                ..Default::default()
             },
         ];
 
@@ -245,6 +1179,347 @@ mod test {
         assert!(true);
     }
 
+    #[tokio::test]
+    async fn supervisor_restarts_stopped_consumers() {
+        let js = create_js_context().await;
+        clear_streams(js.clone()).await;
+
+        let client = NatsClient::new(js.clone(), Namespace::default());
+        let (e, c) = client.ensure_streams().await.unwrap();
+        let cm = ConsumerManager::new(e, c);
+        let interest = InterestDeclaration::aggregate_for_commands(
+            "MXBOB",
+            "bankaccount",
+            "account_number",
+            LinkDefinition::default(),
+        );
+
+        cm.add_consumer::<MockCommandWorker, EndingConsumer>(
+            interest.clone(),
+            MockCommandWorker::new(Arc::new(RwLock::new(Vec::new()))),
+        )
+        .await
+        .unwrap();
+
+        tokio::time::sleep(tokio::time::Duration::from_millis(1000)).await;
+        // The consumer ends immediately every time, so the supervisor should have recreated it
+        assert!(cm.has_consumer(&interest).await);
+        assert!(cm.restart_count(&interest).await.unwrap() >= 1);
+        // The task is still alive, but a consumer that keeps ending must be reported as failing
+        let statuses = cm.consumer_statuses().await;
+        assert!(statuses[0].running);
+        assert!(statuses[0].supervisor.is_failing());
+        assert_eq!(
+            Some("consumer ended unexpectedly"),
+            statuses[0].supervisor.last_error.as_deref()
+        );
+
+        clear_streams(js).await;
+    }
+
+    #[tokio::test]
+    async fn paused_consumer_waits_for_resume() {
+        let js = create_js_context().await;
+        clear_streams(js.clone()).await;
+
+        let client = NatsClient::new(js.clone(), Namespace::default());
+        let (e, c) = client.ensure_streams().await.unwrap();
+        let cm = ConsumerManager::new(e, c);
+        let interest = InterestDeclaration::aggregate_for_commands(
+            "MXBOB",
+            "bankaccount",
+            "account_number",
+            LinkDefinition::default(),
+        );
+        cm.add_consumer::<MockCommandWorker, IdleConsumer>(
+            interest.clone(),
+            MockCommandWorker::new(Arc::new(RwLock::new(Vec::new()))),
+        )
+        .await
+        .unwrap();
+
+        let consumer_name = interest.consumer_name();
+        let lease = tokio::time::Duration::from_secs(5);
+        let created = IDLE_CONSUMERS_CREATED.load(Ordering::SeqCst);
+        assert_eq!(0, cm.pause_consumer("AGG_CMD_other", lease).await);
+        assert_eq!(1, cm.pause_consumer(&consumer_name, lease).await);
+        tokio::time::sleep(tokio::time::Duration::from_millis(300)).await;
+        // A paused consumer must not be recreated while another instance resets it
+        assert_eq!(created, IDLE_CONSUMERS_CREATED.load(Ordering::SeqCst));
+
+        cm.resume_consumer(&consumer_name).await;
+        tokio::time::sleep(tokio::time::Duration::from_millis(300)).await;
+        assert_eq!(created + 1, IDLE_CONSUMERS_CREATED.load(Ordering::SeqCst));
+        assert_eq!(Some(0), cm.restart_count(&interest).await);
+
+        clear_streams(js).await;
+    }
+
+    #[test]
+    fn supervisor_state_tracks_crash_loops() {
+        let mut state = SupervisorState::default();
+        assert!(!state.is_failing());
+
+        // A consumer waiting to be recreated is down
+        state.stopped("stream closed".to_string());
+        assert!(state.is_failing());
+        state.recreated();
+        assert!(!state.is_failing());
+
+        // One that stops again right after every restart is failing even while it runs
+        state.stopped("stream closed".to_string());
+        state.recreate_failed("no responders".to_string());
+        state.recreated();
+        assert_eq!(3, state.consecutive_failures);
+        assert_eq!(2, state.restarts);
+        assert_eq!(Some("no responders"), state.last_error.as_deref());
+        assert!(state.is_failing());
+
+        // Until it has run healthily for a while
+        state.settled();
+        assert!(!state.is_failing());
+        assert_eq!(2, state.restarts);
+
+        // The server failing to report on the consumer only counts once it keeps failing
+        state.info_failed();
+        state.info_failed();
+        assert!(!state.is_failing());
+        state.info_fetched();
+        state.info_failed();
+        state.info_failed();
+        assert!(!state.is_failing());
+        state.info_failed();
+        assert!(state.is_failing());
+        state.info_fetched();
+        assert!(!state.is_failing());
+    }
+
+    #[tokio::test]
+    async fn shutdown_finishes_in_flight_work() {
+        let nc = async_nats::connect("127.0.0.1").await.unwrap();
+        let js = create_js_context().await;
+        clear_streams(js.clone()).await;
+
+        let client = NatsClient::new(js.clone(), Namespace::default());
+        let (e, c) = client.ensure_streams().await.unwrap();
+        let cm = ConsumerManager::new(e, c);
+        let interest = InterestDeclaration::aggregate_for_commands(
+            "MXBOB",
+            "bankaccount",
+            "account_number",
+            LinkDefinition::default(),
+        );
+
+        let msgs = Arc::new(RwLock::new(Vec::new()));
+        cm.add_consumer::<SlowCommandWorker, CommandConsumer>(
+            interest.clone(),
+            SlowCommandWorker {
+                messages: msgs.clone(),
+            },
+        )
+        .await
+        .unwrap();
+
+        let cmd = RawCommand {
+            command_type: "test_one".to_string(),
+            key: "slow1".to_string(),
+            data: json!({}),
+            ..Default::default()
+        };
+        publish_command(&nc, "bankaccount", &cmd).await.unwrap();
+        // Give the worker time to pick up the command, but not enough to finish it
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        cm.shutdown(tokio::time::Duration::from_secs(2)).await;
+        assert_eq!(1, msgs.read().await.len());
+        assert!(!cm.has_consumer(&interest).await);
+
+        clear_streams(js).await;
+    }
+
+    #[tokio::test]
+    async fn queued_messages_outlive_their_ack_wait() {
+        let nc = async_nats::connect("127.0.0.1").await.unwrap();
+        let js = create_js_context().await;
+        clear_streams(js.clone()).await;
+
+        let client = NatsClient::new(js.clone(), Namespace::default());
+        let (e, c) = client.ensure_streams().await.unwrap();
+        let cm = ConsumerManager::new(e, c);
+        let mut ld = LinkDefinition::default();
+        ld.values.insert("concurrency".to_string(), "2".to_string());
+        ld.values.insert("ack_wait".to_string(), "1".to_string());
+        let interest = InterestDeclaration::aggregate_for_commands(
+            "MXBOB",
+            "bankaccount",
+            "account_number",
+            ld,
+        );
+
+        let deliveries = Arc::new(RwLock::new(Vec::new()));
+        cm.add_consumer::<DeliveryCountingWorker, CommandConsumer>(
+            interest,
+            DeliveryCountingWorker {
+                deliveries: deliveries.clone(),
+            },
+        )
+        .await
+        .unwrap();
+
+        // Every command has the same key, so the last ones wait in their partition for longer than the ack wait
+        for n in 1..=4 {
+            let cmd = RawCommand {
+                command_type: format!("cmd_{n}"),
+                key: "ACCT1".to_string(),
+                data: json!({}),
+                ..Default::default()
+            };
+            publish_command(&nc, "bankaccount", &cmd).await.unwrap();
+        }
+
+        tokio::time::sleep(tokio::time::Duration::from_millis(4000)).await;
+        assert_eq!(
+            vec![
+                ("cmd_1".to_string(), 1),
+                ("cmd_2".to_string(), 1),
+                ("cmd_3".to_string(), 1),
+                ("cmd_4".to_string(), 1)
+            ],
+            *deliveries.read().await
+        );
+
+        clear_streams(js).await;
+    }
+
+    #[tokio::test]
+    async fn partitioned_work_preserves_per_key_order() {
+        // Sanity check that the two keys land in different partitions
+        assert_ne!(
+            partition_for(Some("slow".to_string()), 4),
+            partition_for(Some("fast".to_string()), 4)
+        );
+        let mut ld = LinkDefinition::default();
+        ld.values.insert("concurrency".to_string(), "4".to_string());
+        let interest = InterestDeclaration::aggregate_for_commands(
+            "MXBOB",
+            "bankaccount",
+            "account_number",
+            ld,
+        );
+
+        let command = |key: &str, n: usize| AckableMessage {
+            inner: RawCommand {
+                command_type: format!("cmd_{n}"),
+                key: key.to_string(),
+                data: json!({}),
+                ..Default::default()
+            },
+            acker: None,
+            detached: false,
+            origin_sequence: None,
+            nak_delay: None,
+            max_deliver: DEFAULT_MAX_DELIVER,
+        };
+        let consumer = futures::stream::iter(vec![
+            Ok(command("slow", 1)),
+            Ok(command("slow", 2)),
+            Ok(command("fast", 1)),
+            Ok(command("fast", 2)),
+            Ok(command("fast", 3)),
+        ]);
+        let completed = Arc::new(RwLock::new(Vec::new()));
+        let worker = KeyedCommandWorker {
+            completed: completed.clone(),
+        };
+        let (_tx, mut shutdown) = tokio::sync::watch::channel(false);
+
+        let res = work_fn(
+            consumer,
+            &worker,
+            &interest,
+            &mut shutdown,
+            &FailureLog::default(),
+        )
+        .await;
+        // The stream ending is reported once every partition has drained
+        assert!(matches!(res, Err(WorkError::ConsumerStopped)));
+        assert_eq!(
+            vec![
+                "fast/cmd_1",
+                "fast/cmd_2",
+                "fast/cmd_3",
+                "slow/cmd_1",
+                "slow/cmd_2"
+            ],
+            *completed.read().await
+        );
+    }
+
+    /// A consumer whose stream ends right away, as if the server had deleted it
+    struct EndingConsumer;
+
+    impl Stream for EndingConsumer {
+        type Item = Result<AckableMessage<RawCommand>, async_nats::Error>;
+
+        fn poll_next(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            Poll::Ready(None)
+        }
+    }
+
+    impl DecodeMessage for EndingConsumer {
+        type Message = RawCommand;
+
+        fn decode(payload: &[u8]) -> Result<RawCommand, serde_json::Error> {
+            serde_json::from_slice(payload)
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl CreateConsumer for EndingConsumer {
+        type Output = EndingConsumer;
+
+        async fn create(
+            _stream: async_nats::jetstream::stream::Stream,
+            _interest: InterestDeclaration,
+        ) -> Result<Self::Output, async_nats::Error> {
+            Ok(EndingConsumer)
+        }
+    }
+
+    static IDLE_CONSUMERS_CREATED: AtomicUsize = AtomicUsize::new(0);
+
+    /// A consumer that never receives a message, counting how many times it is recreated
+    struct IdleConsumer;
+
+    impl Stream for IdleConsumer {
+        type Item = Result<AckableMessage<RawCommand>, async_nats::Error>;
+
+        fn poll_next(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            Poll::Pending
+        }
+    }
+
+    impl DecodeMessage for IdleConsumer {
+        type Message = RawCommand;
+
+        fn decode(payload: &[u8]) -> Result<RawCommand, serde_json::Error> {
+            serde_json::from_slice(payload)
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl CreateConsumer for IdleConsumer {
+        type Output = IdleConsumer;
+
+        async fn create(
+            _stream: async_nats::jetstream::stream::Stream,
+            _interest: InterestDeclaration,
+        ) -> Result<Self::Output, async_nats::Error> {
+            IDLE_CONSUMERS_CREATED.fetch_add(1, Ordering::SeqCst);
+            Ok(IdleConsumer)
+        }
+    }
+
     struct MockCommandWorker {
         pub messages: Arc<RwLock<Vec<AckableMessage<RawCommand>>>>,
     }
@@ -265,4 +1540,85 @@ mod test {
             Ok(())
         }
     }
+
+    struct SlowCommandWorker {
+        pub messages: Arc<RwLock<Vec<String>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl Worker for SlowCommandWorker {
+        type Message = RawCommand;
+
+        async fn do_work(&self, mut message: AckableMessage<Self::Message>) -> WorkResult<()> {
+            tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+            message.ack().await.map_err(WorkError::NatsError)?;
+            self.messages.write().await.push(message.key.clone());
+            Ok(())
+        }
+    }
+
+    /// Records how many times each command was delivered, taking longer than half its ack wait on each
+    struct DeliveryCountingWorker {
+        pub deliveries: Arc<RwLock<Vec<(String, i64)>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl Worker for DeliveryCountingWorker {
+        type Message = RawCommand;
+
+        async fn do_work(&self, mut message: AckableMessage<Self::Message>) -> WorkResult<()> {
+            let delivered = message
+                .acker
+                .as_ref()
+                .and_then(|m| m.info().ok())
+                .map_or(0, |info| info.delivered);
+            tokio::time::sleep(tokio::time::Duration::from_millis(600)).await;
+            message.ack().await.map_err(WorkError::NatsError)?;
+            self.deliveries
+                .write()
+                .await
+                .push((message.command_type.clone(), delivered));
+            Ok(())
+        }
+
+        fn partition_key(&self, message: &Self::Message) -> Option<String> {
+            Some(message.key.clone())
+        }
+    }
+
+    /// Records the order in which commands complete, taking a while for the `slow` key
+    struct KeyedCommandWorker {
+        pub completed: Arc<RwLock<Vec<String>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl Worker for KeyedCommandWorker {
+        type Message = RawCommand;
+
+        async fn do_work(&self, message: AckableMessage<Self::Message>) -> WorkResult<()> {
+            if message.key == "slow" {
+                tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+            }
+            self.completed
+                .write()
+                .await
+                .push(format!("{}/{}", message.key, message.command_type));
+            Ok(())
+        }
+
+        fn partition_key(&self, message: &Self::Message) -> Option<String> {
+            Some(message.key.clone())
+        }
+    }
+
+    struct MockEventWorker;
+
+    #[async_trait::async_trait]
+    impl Worker for MockEventWorker {
+        type Message = CloudEvent;
+
+        async fn do_work(&self, mut message: AckableMessage<Self::Message>) -> WorkResult<()> {
+            message.ack().await.map_err(WorkError::NatsError)
+        }
+    }
 }
@@ -4,17 +4,27 @@ mod manager;
 
 use std::{collections::HashMap, fmt::Debug, sync::Arc};
 
-use async_nats::Error as NatsError;
+use async_nats::{
+    jetstream::{
+        consumer::{pull::Config as PullConfig, FromConsumer, PullConsumer},
+        stream::Stream as JsStream,
+    },
+    Error as NatsError,
+};
 pub use command_consumer::{CommandConsumer, RawCommand};
 pub use event_consumer::EventConsumer;
-pub use manager::ConsumerManager;
+pub use manager::{ConsumerManager, ConsumerStatus, SupervisorState};
 
-use tokio::{sync::RwLock, task::JoinHandle};
+use tokio::sync::RwLock;
+use tracing::info;
 
-use crate::{config::InterestDeclaration, natsclient::AckableMessage};
+use crate::{
+    config::{InterestDeclaration, RetryPolicy},
+    natsclient::AckableMessage,
+};
 
 pub type WorkResult<T> = Result<T, WorkError>;
-pub(crate) type WorkHandles = Arc<RwLock<HashMap<InterestDeclaration, JoinHandle<WorkResult<()>>>>>;
+pub(crate) type WorkHandles = Arc<RwLock<HashMap<InterestDeclaration, manager::ConsumerHandle>>>;
 
 /// A helper trait to allow for constructing any consumer
 #[async_trait::async_trait]
@@ -28,14 +38,29 @@ pub trait CreateConsumer {
     ) -> Result<Self::Output, NatsError>;
 }
 
+/// Decodes a raw message payload into the type delivered by a consumer. This is what consumer streams use for
+/// every message they pull, and what dead letters are decoded with when they are delivered to a worker again
+pub trait DecodeMessage {
+    type Message;
+
+    fn decode(payload: &[u8]) -> Result<Self::Message, serde_json::Error>;
+}
+
 #[async_trait::async_trait]
 pub trait Worker {
     /// The actual message type to expect, such as a cloud event or a command
     type Message: Debug + Send;
     /// Process the given work to completion. Almost all errors returned are things that could be
     /// retried. But if for some reason a fatal error occurs, return `WorkError::Fatal` to indicate
-    /// that work should stop.
+    /// that work should stop. The consumer will then be torn down and recreated by its supervisor.
     async fn do_work(&self, message: AckableMessage<Self::Message>) -> WorkResult<()>;
+
+    /// Returns the entity key used to partition messages when a consumer processes messages concurrently.
+    /// Messages with the same key are always processed in order. Messages without a key are all processed by
+    /// the same partition
+    fn partition_key(&self, _message: &Self::Message) -> Option<String> {
+        None
+    }
 }
 
 /// An error that describes possible work failures when performing actions based on incoming messages
@@ -51,14 +76,68 @@ pub enum WorkError {
     NatsError(async_nats::Error),
     /// A catch all error for non-described errors that are not fatal    
     Other(String),
+    /// A failure that retrying won't fix. The worker has terminated the message instead of nacking it, so it
+    /// goes to the dead letter stream without being redelivered
+    Permanent(String),
+}
+
+impl std::fmt::Display for WorkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WorkError::ConsumerStopped => write!(f, "consumer stopped"),
+            WorkError::Fatal(e) => write!(f, "fatal error: {e}"),
+            WorkError::NatsError(e) => write!(f, "NATS error: {e}"),
+            WorkError::Other(e) => write!(f, "{e}"),
+            WorkError::Permanent(e) => write!(f, "permanent failure: {e}"),
+        }
+    }
+}
+
+/// Brings the retry settings of an existing durable consumer in line with its retry policy. Getting or creating a
+/// consumer leaves an existing durable as it is, but JetStream allows these settings to be changed in place
+pub(crate) async fn update_retry_policy(
+    stream: &JsStream,
+    consumer: PullConsumer,
+    retry: &RetryPolicy,
+) -> Result<PullConsumer, NatsError> {
+    let current = &consumer.cached_info().config;
+    let ack_timeouts = retry.ack_timeouts();
+    if current.max_deliver == retry.max_deliver
+        && current.ack_wait == retry.initial_ack_wait()
+        && current.backoff == ack_timeouts
+    {
+        return Ok(consumer);
+    }
+    info!(
+        consumer_name = consumer.cached_info().name,
+        ?retry,
+        "Updating retry settings of existing consumer"
+    );
+    let mut config = current.clone();
+    config.max_deliver = retry.max_deliver;
+    config.ack_wait = retry.initial_ack_wait();
+    config.backoff = ack_timeouts;
+    Ok(stream
+        .create_consumer(PullConfig::try_from_consumer_config(config)?)
+        .await?)
 }
 
 /// Creates a futures::Stream for the given type, pulling items of the specified type by deserializing
 /// them from JSON. The consumer types need to expose a `sanitize_type_name` function that is called
-/// by each stream prior to delivering the message
+/// by each stream prior to delivering the message, and a `retry` field with their [`RetryPolicy`]
+///
+/// [`RetryPolicy`]: crate::config::RetryPolicy
 macro_rules! impl_Stream {
     ($($t:ty; $u:ty),+) => {
-        $(impl Stream for $t {
+        $(impl $crate::consumers::DecodeMessage for $t {
+            type Message = $u;
+
+            fn decode(payload: &[u8]) -> Result<$u, serde_json::Error> {
+                serde_json::from_slice(payload).map(<$t>::sanitize_type_name)
+            }
+        }
+
+        impl Stream for $t {
             type Item = Result<AckableMessage<$u>, NatsError>;
 
             fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
@@ -66,31 +145,26 @@ macro_rules! impl_Stream {
                     Poll::Ready(None) => Poll::Ready(None),
                     Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(Box::new(e)))),
                     Poll::Ready(Some(Ok(msg))) => {
-                        // Convert to our $u type, skipping if we can't do it (and looping around to
-                        // try the next poll)
-                        let item: $u = match serde_json::from_slice(&msg.payload) {
+                        // Convert to our $u type, quarantining the message if we can't do it (and looping
+                        // around to try the next poll)
+                        let item = match <$t as $crate::consumers::DecodeMessage>::decode(&msg.payload) {
                             Ok(item) => item,
                             Err(e) => {
-                                warn!(error = ?e, "Unable to decode as <$u>. Skipping message");
                                 let waker = cx.waker().clone();
+                                let interest = self.interest.clone();
                                 tokio::spawn(async move {
-                                    if let Err(e) = msg.ack().await {
-                                        error!(error = %e, "Error when trying to ack skipped message, message will be redelivered")
-                                    }
+                                    $crate::deadletter::quarantine(msg, &interest, format!("Unable to decode message: {e}")).await;
                                     waker.wake();
                                 });
-                                // Return a poll pending. It will then wake up and try again once it has acked
+                                // Return a poll pending. It will then wake up and try again once it has
+                                // quarantined the message
                                 return Poll::Pending;
                             }
                         };
-                        let item = <$t>::sanitize_type_name(item);
                         // NOTE(thomastaylor312): Ideally we'd consume `msg.payload` above with a
                         // `Cursor` and `from_reader` and then manually reconstruct the acking using the
                         // message context, but I didn't want to waste time optimizing yet
-                        Poll::Ready(Some(Ok(AckableMessage {
-                            inner: item,
-                            acker: Some(msg),
-                        })))
+                        Poll::Ready(Some(Ok(AckableMessage::delivered(item, msg, &self.retry))))
                     }
                     Poll::Pending => Poll::Pending,
                 }
@@ -0,0 +1,633 @@
+//! # Dead Letters
+//! Commands and events that are still not acked after their final delivery are copied into the dead letter
+//! stream along with the consumer, the actor and the last error the worker reported. The JetStream max
+//! deliveries advisory tells us when that happens, which also covers messages that timed out rather than
+//! failing outright. Events that a handler reports as permanently failed are terminated instead, and the
+//! terminated advisory moves them over right away. Whichever provider instance receives an advisory captures the
+//! dead letter, whether or not it runs the consumer. Messages that can never be processed, because a consumer
+//! can't decode them or they don't match their schema, are quarantined in the same stream as soon as they
+//! arrive. Dead letters can then be inspected and either handed to the worker again or discarded
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_nats::{
+    jetstream::{
+        stream::{DirectGetErrorKind, Stream as JsStream},
+        Context,
+    },
+    HeaderMap, Message,
+};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, trace, warn, Instrument};
+use wasmbus_rpc::error::RpcError;
+
+use crate::{
+    config::InterestDeclaration,
+    consumers::ConsumerManager,
+    metrics::metrics,
+    natsclient::{Namespace, NATS_MSG_ID},
+    Result,
+};
+
+/// Published by the server when a message reaches a consumer's max deliveries without being acked, followed by
+/// `.{stream}.{consumer}`
+const MAX_DELIVERIES_ADVISORY_PREFIX: &str = "$JS.EVENT.ADVISORY.CONSUMER.MAX_DELIVERIES";
+/// Published by the server when a worker terminates a message, followed by `.{stream}.{consumer}`
+const TERMINATED_ADVISORY_PREFIX: &str = "$JS.EVENT.ADVISORY.CONSUMER.MSG_TERMINATED";
+/// Only one provider instance needs to capture each dead letter
+const ADVISORY_QUEUE_GROUP: &str = "concordance_dead_letters";
+/// Upper bound on the number of dead letters returned when listing them
+const MAX_LISTED_DEAD_LETTERS: usize = 1000;
+/// Upper bound on remembered failures. The oldest failure is forgotten to make room for a new one
+const MAX_RECORDED_FAILURES: usize = 1024;
+/// How long a failure is remembered. The advisory for a message follows its final failure within moments, but
+/// with several provider instances it is usually delivered to another instance, which can't take the failure
+const FAILURE_RETENTION: Duration = Duration::from_secs(300);
+
+const NATS_SEQUENCE: &str = "Nats-Sequence";
+const NATS_TIME_STAMP: &str = "Nats-Time-Stamp";
+const DEAD_LETTER_CONSUMER: &str = "Concordance-Dead-Letter-Consumer";
+const DEAD_LETTER_ACTOR: &str = "Concordance-Dead-Letter-Actor";
+const DEAD_LETTER_STREAM: &str = "Concordance-Dead-Letter-Stream";
+const DEAD_LETTER_SEQUENCE: &str = "Concordance-Dead-Letter-Sequence";
+const DEAD_LETTER_SUBJECT: &str = "Concordance-Dead-Letter-Subject";
+const DEAD_LETTER_DELIVERIES: &str = "Concordance-Dead-Letter-Deliveries";
+const DEAD_LETTER_ERROR: &str = "Concordance-Dead-Letter-Error";
+/// Headers of the original message are kept under their own name with this prefix, so they can't clash with the
+/// dead letter's headers
+const ORIGINAL_HEADER_PREFIX: &str = "Concordance-Original-";
+
+/// A command or event that a consumer gave up on
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct DeadLetter {
+    /// The durable consumer the message was delivered to
+    pub consumer: String,
+    /// The actor behind the consumer, if the provider instance that captured the dead letter runs the consumer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actor_id: Option<String>,
+    /// The stream and sequence the message was originally delivered from
+    pub stream: String,
+    pub stream_sequence: u64,
+    pub subject: String,
+    pub deliveries: u64,
+    /// The last error reported by the worker, if the provider instance that captured the dead letter is the one
+    /// whose worker reported it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The headers of the original message
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, Vec<String>>,
+}
+
+impl DeadLetter {
+    fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        // Deduplicates the dead letter if the same advisory is handled twice
+        headers.insert(
+            NATS_MSG_ID,
+            format!("{}.{}.{}", self.stream, self.consumer, self.stream_sequence).as_str(),
+        );
+        headers.insert(DEAD_LETTER_CONSUMER, self.consumer.as_str());
+        if let Some(actor_id) = &self.actor_id {
+            headers.insert(DEAD_LETTER_ACTOR, actor_id.as_str());
+        }
+        headers.insert(DEAD_LETTER_STREAM, self.stream.as_str());
+        headers.insert(
+            DEAD_LETTER_SEQUENCE,
+            self.stream_sequence.to_string().as_str(),
+        );
+        headers.insert(DEAD_LETTER_SUBJECT, self.subject.as_str());
+        headers.insert(DEAD_LETTER_DELIVERIES, self.deliveries.to_string().as_str());
+        if let Some(error) = &self.error {
+            headers.insert(DEAD_LETTER_ERROR, header_value(error).as_str());
+        }
+        for (name, values) in &self.headers {
+            for value in values {
+                headers.append(
+                    format!("{ORIGINAL_HEADER_PREFIX}{name}").as_str(),
+                    header_value(value),
+                );
+            }
+        }
+        headers
+    }
+
+    fn from_headers(headers: &HeaderMap) -> Option<DeadLetter> {
+        let header = |name: &str| headers.get(name).map(|v| v.to_string());
+        Some(DeadLetter {
+            consumer: header(DEAD_LETTER_CONSUMER)?,
+            actor_id: header(DEAD_LETTER_ACTOR),
+            stream: header(DEAD_LETTER_STREAM).unwrap_or_default(),
+            stream_sequence: header(DEAD_LETTER_SEQUENCE)?.parse().ok()?,
+            subject: header(DEAD_LETTER_SUBJECT).unwrap_or_default(),
+            deliveries: header(DEAD_LETTER_DELIVERIES)
+                .and_then(|v| v.parse().ok())
+                .unwrap_or_default(),
+            error: header(DEAD_LETTER_ERROR),
+            headers: headers
+                .iter()
+                .filter_map(|(name, values)| {
+                    let name = name.to_string();
+                    let name = name.strip_prefix(ORIGINAL_HEADER_PREFIX)?;
+                    Some((name.to_string(), values.iter().cloned().collect()))
+                })
+                .collect(),
+        })
+    }
+}
+
+/// Collects the headers of a message so they can be kept in its dead letter
+fn original_headers(headers: Option<&HeaderMap>) -> BTreeMap<String, Vec<String>> {
+    headers
+        .map(|headers| {
+            headers
+                .iter()
+                .map(|(name, values)| (name.to_string(), values.iter().cloned().collect()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// A dead letter as stored in the dead letter stream
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct StoredDeadLetter {
+    /// Sequence in the dead letter stream, which identifies the dead letter in admin requests
+    pub sequence: u64,
+    /// When the dead letter was captured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub captured_at: Option<String>,
+    #[serde(flatten)]
+    pub letter: DeadLetter,
+    #[serde(skip)]
+    pub payload: Vec<u8>,
+}
+
+impl StoredDeadLetter {
+    fn from_message(message: &Message) -> Option<StoredDeadLetter> {
+        let headers = message.headers.as_ref()?;
+        Some(StoredDeadLetter {
+            sequence: headers.get(NATS_SEQUENCE)?.to_string().parse().ok()?,
+            captured_at: headers.get(NATS_TIME_STAMP).map(|v| v.to_string()),
+            letter: DeadLetter::from_headers(headers)?,
+            payload: message.payload.to_vec(),
+        })
+    }
+
+    /// The original message payload as JSON, or as a string if it isn't valid JSON
+    pub fn payload_json(&self) -> serde_json::Value {
+        serde_json::from_slice(&self.payload).unwrap_or_else(|_| {
+            serde_json::Value::String(String::from_utf8_lossy(&self.payload).into_owned())
+        })
+    }
+}
+
+/// Header values can't span lines, and worker errors sometimes do
+fn header_value(value: &str) -> String {
+    value.replace(['\r', '\n'], " ")
+}
+
+/// Reads and writes dead letters in the dead letter stream
+#[derive(Clone)]
+pub(crate) struct DeadLetterQueue {
+    js: Context,
+    namespace: Namespace,
+}
+
+impl DeadLetterQueue {
+    pub(crate) fn new(js: Context, namespace: Namespace) -> DeadLetterQueue {
+        DeadLetterQueue { js, namespace }
+    }
+
+    /// Stores a dead letter along with the original message payload, on a subject named after its consumer
+    pub(crate) async fn add(&self, letter: &DeadLetter, payload: Vec<u8>) -> Result<u64> {
+        let subject = format!(
+            "{}.{}",
+            self.namespace.dead_letter_subject_prefix(),
+            letter.consumer
+        );
+        let ack = self
+            .js
+            .publish_with_headers(subject, letter.headers(), payload.into())
+            .await
+            .map_err(|e| RpcError::Nats(e.to_string()))?
+            .await
+            .map_err(|e| RpcError::Nats(e.to_string()))?;
+        metrics().dead_lettered(&letter.consumer);
+        Ok(ack.sequence)
+    }
+
+    /// Lists dead letters, oldest first, optionally only those of a single consumer
+    pub(crate) async fn list(&self, consumer: Option<&str>) -> Result<Vec<StoredDeadLetter>> {
+        let stream = self.stream().await?;
+        let filter = format!(
+            "{}.{}",
+            self.namespace.dead_letter_subject_prefix(),
+            consumer.unwrap_or(">")
+        );
+        let mut letters = Vec::new();
+        let mut next = 1;
+        while letters.len() < MAX_LISTED_DEAD_LETTERS {
+            let message = match stream
+                .direct_get_next_for_subject(&filter, Some(next))
+                .await
+            {
+                Ok(message) => message,
+                Err(e) if e.kind() == DirectGetErrorKind::NotFound => break,
+                Err(e) => {
+                    return Err(RpcError::Nats(format!(
+                        "Failed to read dead letters for {filter}: {e}"
+                    )))
+                }
+            };
+            let letter = StoredDeadLetter::from_message(&message).ok_or_else(|| {
+                RpcError::Deser(format!("Malformed dead letter on {}", message.subject))
+            })?;
+            next = letter.sequence + 1;
+            letters.push(letter);
+        }
+        Ok(letters)
+    }
+
+    pub(crate) async fn get(&self, sequence: u64) -> Result<StoredDeadLetter> {
+        let message = match self.stream().await?.direct_get(sequence).await {
+            Ok(message) => message,
+            Err(e) if e.kind() == DirectGetErrorKind::NotFound => {
+                return Err(RpcError::InvalidParameter(format!(
+                    "No dead letter with sequence {sequence}"
+                )))
+            }
+            Err(e) => {
+                return Err(RpcError::Nats(format!(
+                    "Failed to read dead letter {sequence}: {e}"
+                )))
+            }
+        };
+        StoredDeadLetter::from_message(&message)
+            .ok_or_else(|| RpcError::Deser(format!("Malformed dead letter {sequence}")))
+    }
+
+    pub(crate) async fn remove(&self, sequence: u64) -> Result<()> {
+        self.stream()
+            .await?
+            .delete_message(sequence)
+            .await
+            .map_err(|e| RpcError::Nats(format!("Failed to remove dead letter {sequence}: {e}")))?;
+        Ok(())
+    }
+
+    async fn stream(&self) -> Result<JsStream> {
+        self.js
+            .get_stream(self.namespace.dead_letter_stream_name())
+            .await
+            .map_err(|e| RpcError::Nats(format!("Failed to get dead letter stream: {e}")))
+    }
+}
+
+/// Errors from the final delivery of messages, keyed by consumer name and stream sequence. Workers run well
+/// before the server announces that a message has run out of deliveries, so this is how the error makes it
+/// into the dead letter when the same provider instance receives the advisory. Failures taken by no one, because
+/// another instance captured the dead letter, are forgotten after a while
+#[derive(Clone, Default)]
+pub(crate) struct FailureLog(Arc<Mutex<HashMap<(String, u64), RecordedFailure>>>);
+
+struct RecordedFailure {
+    error: String,
+    recorded: Instant,
+}
+
+impl FailureLog {
+    pub(crate) fn record(&self, consumer_name: String, stream_sequence: u64, error: String) {
+        self.record_at(consumer_name, stream_sequence, error, Instant::now())
+    }
+
+    fn record_at(&self, consumer_name: String, stream_sequence: u64, error: String, now: Instant) {
+        let Ok(mut failures) = self.0.lock() else {
+            return;
+        };
+        failures.retain(|_, failure| now.duration_since(failure.recorded) < FAILURE_RETENTION);
+        if failures.len() >= MAX_RECORDED_FAILURES {
+            let oldest = failures
+                .iter()
+                .min_by_key(|(_, failure)| failure.recorded)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                failures.remove(&oldest);
+            }
+        }
+        failures.insert(
+            (consumer_name, stream_sequence),
+            RecordedFailure {
+                error,
+                recorded: now,
+            },
+        );
+    }
+
+    pub(crate) fn take(&self, consumer_name: &str, stream_sequence: u64) -> Option<String> {
+        self.0
+            .lock()
+            .ok()?
+            .remove(&(consumer_name.to_string(), stream_sequence))
+            .map(|failure| failure.error)
+    }
+}
+
+/// Moves a message that can never be processed, such as one a consumer couldn't decode, into the dead letter stream
+/// along with the reason, then acks it so the consumer can move on. If the message can't be moved it is left
+/// unacked, so it is delivered again and tried once more
+pub(crate) async fn quarantine(
+    msg: async_nats::jetstream::Message,
+    interest: &InterestDeclaration,
+    reason: String,
+) {
+    let letter = match msg.info() {
+        Ok(info) => DeadLetter {
+            consumer: info.consumer.to_string(),
+            actor_id: Some(interest.actor_id.clone()),
+            stream: info.stream.to_string(),
+            stream_sequence: info.stream_sequence,
+            subject: msg.subject.clone(),
+            deliveries: info.delivered as u64,
+            error: Some(reason),
+            headers: original_headers(msg.headers.as_ref()),
+        },
+        Err(e) => {
+            error!(error = %e, "Unable to read delivery info of message to quarantine, message will be redelivered");
+            return;
+        }
+    };
+    let dead_letters = DeadLetterQueue::new(msg.context.clone(), interest.namespace.clone());
+    match dead_letters.add(&letter, msg.payload.to_vec()).await {
+        Ok(sequence) => {
+            warn!(
+                sequence,
+                consumer = letter.consumer,
+                stream_sequence = letter.stream_sequence,
+                reason = letter.error.as_deref().unwrap_or_default(),
+                "Message can't be processed and was quarantined in the dead letter stream"
+            );
+            if let Err(e) = msg.ack().await {
+                error!(error = %e, "Error when trying to ack quarantined message, message will be redelivered")
+            }
+        }
+        Err(e) => {
+            error!(error = %e, "Failed to quarantine message, message will be redelivered")
+        }
+    }
+}
+
+/// The parts of the max deliveries and terminated advisories needed to capture a dead letter. Both carry the same
+/// fields
+#[derive(Debug, Deserialize)]
+struct DeliveryAdvisory {
+    stream: String,
+    consumer: String,
+    stream_seq: u64,
+    deliveries: u64,
+}
+
+/// Captures dead letters for the consumers of the event and command streams as the server reports them
+#[derive(Clone)]
+pub(crate) struct DeadLetterService {
+    pub nc: async_nats::Client,
+    pub js: Context,
+    pub namespace: Namespace,
+    pub dead_letters: DeadLetterQueue,
+    pub consumer_manager: ConsumerManager,
+}
+
+impl DeadLetterService {
+    pub(crate) async fn serve(self) -> Result<JoinHandle<()>> {
+        let mut subs = Vec::new();
+        let events = self.namespace.event_stream_name();
+        let commands = self.namespace.command_stream_name();
+        for (prefix, stream) in [
+            (MAX_DELIVERIES_ADVISORY_PREFIX, &events),
+            (MAX_DELIVERIES_ADVISORY_PREFIX, &commands),
+            (TERMINATED_ADVISORY_PREFIX, &events),
+        ] {
+            let subject = format!("{prefix}.{stream}.*");
+            let sub = self
+                .nc
+                .queue_subscribe(subject.clone(), ADVISORY_QUEUE_GROUP.to_string())
+                .await
+                .map_err(|e| RpcError::Nats(format!("Failed to subscribe to {subject}: {e}")))?;
+            subs.push(sub);
+        }
+        Ok(tokio::spawn(async move {
+            let mut advisories = futures::stream::select_all(subs);
+            while let Some(msg) = advisories.next().await {
+                let advisory: DeliveryAdvisory = match serde_json::from_slice(&msg.payload) {
+                    Ok(advisory) => advisory,
+                    Err(e) => {
+                        warn!(error = %e, subject = %msg.subject, "Ignoring malformed advisory");
+                        continue;
+                    }
+                };
+                let span = tracing::info_span!(
+                    "dead_letter",
+                    consumer = advisory.consumer,
+                    stream_sequence = advisory.stream_seq
+                );
+                if let Err(e) = self.capture(advisory).instrument(span).await {
+                    error!("Failed to capture dead letter: {e}");
+                }
+            }
+            debug!("Dead letter advisory subscriptions closed");
+        }))
+    }
+
+    /// Copies the message an advisory is about into the dead letter stream. Everything needed comes from the
+    /// advisory and the original stream, since the advisory is only delivered to one provider instance and that
+    /// instance may not run the consumer. The actor is only known to an instance that does, and the worker's
+    /// error only to the instance whose worker failed on the final delivery
+    async fn capture(&self, advisory: DeliveryAdvisory) -> Result<()> {
+        let actor_id = self
+            .consumer_manager
+            .consumers()
+            .await
+            .into_iter()
+            .find(|i| i.consumer_name() == advisory.consumer)
+            .map(|i| i.actor_id);
+        if actor_id.is_none() {
+            trace!("Capturing dead letter for a consumer this provider doesn't run");
+        }
+        let stream = self
+            .js
+            .get_stream(&advisory.stream)
+            .await
+            .map_err(|e| RpcError::Nats(format!("Failed to get stream: {e}")))?;
+        let message = stream
+            .get_raw_message(advisory.stream_seq)
+            .await
+            .and_then(Message::try_from)
+            .map_err(|e| RpcError::Nats(format!("Failed to read original message: {e}")))?;
+
+        let letter = DeadLetter {
+            error: self
+                .consumer_manager
+                .take_failure(&advisory.consumer, advisory.stream_seq),
+            consumer: advisory.consumer,
+            actor_id,
+            stream: advisory.stream,
+            stream_sequence: advisory.stream_seq,
+            headers: original_headers(message.headers.as_ref()),
+            subject: message.subject,
+            deliveries: advisory.deliveries,
+        };
+        let sequence = self
+            .dead_letters
+            .add(&letter, message.payload.to_vec())
+            .await?;
+        warn!(
+            sequence,
+            error = letter.error.as_deref().unwrap_or("unknown"),
+            "Message failed and was moved to the dead letter stream"
+        );
+
+        // Commands stay in their work queue until acked, so the original is removed once it is safely copied
+        if letter.stream == self.namespace.command_stream_name() {
+            if let Err(e) = stream.delete_message(letter.stream_sequence).await {
+                warn!(error = %e, "Failed to remove dead lettered command from the command stream");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::natsclient::{
+        test::{clear_streams, create_js_context},
+        Namespace, NatsClient,
+    };
+
+    use std::time::{Duration, Instant};
+
+    use super::{
+        DeadLetter, DeadLetterQueue, FailureLog, FAILURE_RETENTION, MAX_RECORDED_FAILURES,
+    };
+
+    #[test]
+    fn failure_log_forgets_failures_no_one_takes() {
+        let log = FailureLog::default();
+        let start = Instant::now();
+        log.record_at("PROJ_a".to_string(), 1, "boom".to_string(), start);
+        // Failures taken elsewhere expire
+        log.record_at(
+            "PROJ_a".to_string(),
+            2,
+            "bang".to_string(),
+            start + FAILURE_RETENTION,
+        );
+        assert_eq!(None, log.take("PROJ_a", 1));
+        assert_eq!(Some("bang".to_string()), log.take("PROJ_a", 2));
+        assert_eq!(None, log.take("PROJ_a", 2));
+
+        // A full log makes room by forgetting the oldest failure
+        for sequence in 0..MAX_RECORDED_FAILURES as u64 + 1 {
+            log.record_at(
+                "PROJ_b".to_string(),
+                sequence,
+                format!("failure {sequence}"),
+                start + Duration::from_millis(sequence),
+            );
+        }
+        assert_eq!(None, log.take("PROJ_b", 0));
+        assert_eq!(Some("failure 1".to_string()), log.take("PROJ_b", 1));
+        assert_eq!(
+            Some(format!("failure {MAX_RECORDED_FAILURES}")),
+            log.take("PROJ_b", MAX_RECORDED_FAILURES as u64)
+        );
+    }
+
+    fn letter(consumer: &str, stream_sequence: u64) -> DeadLetter {
+        DeadLetter {
+            consumer: consumer.to_string(),
+            actor_id: Some("MXBOB".to_string()),
+            stream: "CC_COMMANDS".to_string(),
+            stream_sequence,
+            subject: "cc.commands.bankaccount".to_string(),
+            deliveries: 3,
+            error: Some("first line\nsecond line".to_string()),
+            headers: [("Nats-Msg-Id".to_string(), vec!["original".to_string()])].into(),
+        }
+    }
+
+    #[test]
+    fn dead_letter_headers_round_trip() {
+        let letter = letter("AGG_CMD_bankaccount", 7);
+        let headers = letter.headers();
+        // The original message ID must not deduplicate the dead letter itself
+        assert_eq!(
+            "CC_COMMANDS.AGG_CMD_bankaccount.7",
+            headers.get("Nats-Msg-Id").unwrap().as_str()
+        );
+        let parsed = DeadLetter::from_headers(&headers).unwrap();
+        assert_eq!(
+            DeadLetter {
+                error: Some("first line second line".to_string()),
+                ..letter
+            },
+            parsed
+        );
+    }
+
+    #[tokio::test]
+    async fn dead_letters_can_be_listed_inspected_and_removed() {
+        let js = create_js_context().await;
+        clear_streams(js.clone()).await;
+        NatsClient::new(js.clone(), Namespace::default())
+            .ensure_dead_letter_stream()
+            .await
+            .unwrap();
+        let dlq = DeadLetterQueue::new(js.clone(), Namespace::default());
+
+        let first = dlq
+            .add(
+                &letter("AGG_CMD_bankaccount", 7),
+                br#"{"key":"a"}"#.to_vec(),
+            )
+            .await
+            .unwrap();
+        // The same message is only captured once
+        assert_eq!(
+            first,
+            dlq.add(
+                &letter("AGG_CMD_bankaccount", 7),
+                br#"{"key":"a"}"#.to_vec()
+            )
+            .await
+            .unwrap()
+        );
+        dlq.add(&letter("PROJ_bankaccount", 8), b"not json".to_vec())
+            .await
+            .unwrap();
+
+        assert_eq!(2, dlq.list(None).await.unwrap().len());
+        let listed = dlq.list(Some("AGG_CMD_bankaccount")).await.unwrap();
+        assert_eq!(1, listed.len());
+        assert_eq!(first, listed[0].sequence);
+        assert_eq!(
+            Some("first line second line"),
+            listed[0].letter.error.as_deref()
+        );
+
+        let stored = dlq.get(first).await.unwrap();
+        assert_eq!(7, stored.letter.stream_sequence);
+        assert_eq!(serde_json::json!({"key": "a"}), stored.payload_json());
+        assert!(stored.captured_at.is_some());
+
+        dlq.remove(first).await.unwrap();
+        assert!(dlq.get(first).await.is_err());
+        assert_eq!(1, dlq.list(None).await.unwrap().len());
+
+        clear_streams(js).await;
+    }
+}
@@ -1,8 +1,16 @@
 use crate::Result;
-use crate::{consumers::RawCommand, eventsourcing::Event as ConcordanceEvent};
+use crate::{
+    consumers::RawCommand,
+    eventsourcing::{Event as ConcordanceEvent, EventMetadata},
+    natsclient::{Namespace, NATS_EXPECTED_LAST_SUBJECT_SEQUENCE, NATS_MSG_ID},
+    otel::inject_trace_context,
+    schemas::Schemas,
+};
+use async_nats::{jetstream::Context, HeaderMap};
 use case::CaseExt;
 use chrono::Utc; // only using chrono because cloudevents SDK needs it
 use cloudevents::AttributesReader;
+use serde_json::json;
 use tracing::{error, instrument};
 use wasmbus_rpc::error::RpcError;
 
@@ -12,45 +20,193 @@ pub(crate) const EVENT_TOPIC_PREFIX: &str = "cc.events";
 pub(crate) const COMMAND_TOPIC_PREFIX: &str = "cc.commands";
 
 pub(crate) const EXT_CONCORDANCE_STREAM: &str = "x-concordance-stream";
+/// CloudEvent extension identifying the saga an event belongs to
+pub(crate) const EXT_CORRELATION_ID: &str = "correlationid";
+/// CloudEvent extension identifying the command that caused an event
+pub(crate) const EXT_CAUSATION_ID: &str = "causationid";
+
+/// Applies the expected sequence to every subject matching a filter rather than only the published subject.
+/// Requires NATS server 2.11 or later
+const NATS_EXPECTED_LAST_SUBJECT_SEQUENCE_SUBJECT: &str =
+    "Nats-Expected-Last-Subject-Sequence-Subject";
+/// JetStream API error code for a message lookup that found nothing
+const JS_NO_MESSAGE_FOUND: u64 = 10037;
 
 // NOTE: making the publication functions below use request versus publish forces
 // the stream to acknowledge the new entry. Un-acked messages will result in errors
 
-#[instrument(level = "debug", skip(nc))]
+/// The last event sequence for a single entity that the next published event must follow
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ExpectedSequence {
+    /// A filter matching every event subject for the entity
+    pub filter: String,
+    pub sequence: u64,
+}
+
+/// Links a message to the saga it belongs to and to the message that caused it, so that every command and event
+/// in a saga can be traced back to the command that started it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Causation {
+    pub correlation_id: String,
+    pub causation_id: String,
+}
+
+impl Causation {
+    /// The causation of events published in response to a command. A command without a correlation id starts a
+    /// new saga. Commands are given an id before they're handled, so one is only made up here as a last resort
+    pub(crate) fn for_command(cmd: &RawCommand) -> Causation {
+        let command_id = cmd
+            .id
+            .clone()
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+        Causation {
+            correlation_id: cmd
+                .correlation_id
+                .clone()
+                .unwrap_or_else(|| command_id.clone()),
+            causation_id: command_id,
+        }
+    }
+
+    /// The causation of commands issued in response to an event. Events published before correlation ids were
+    /// recorded start a new saga
+    pub(crate) fn for_event(evt: &CloudEvent) -> Causation {
+        Causation {
+            correlation_id: evt
+                .extension(EXT_CORRELATION_ID)
+                .map(|id| id.to_string())
+                .unwrap_or_else(|| evt.id().to_string()),
+            causation_id: evt.id().to_string(),
+        }
+    }
+
+    /// The id of a command issued in response to an event, made from the event's id and the command's position
+    /// among the commands issued for it. Handling the event again therefore issues the same command ids, which
+    /// lets the command stream drop the duplicates
+    pub(crate) fn issued_command_id(&self, index: usize) -> String {
+        format!("{}.{index}", self.causation_id)
+    }
+
+    /// The message id of an event published in response to a command, made from the command's id and the event's
+    /// position among the events published for it. Handling the command again after only some of its events were
+    /// published therefore lets the event stream drop the ones it already has
+    pub(crate) fn published_event_id(&self, index: usize) -> String {
+        format!("{}.{index}", self.causation_id)
+    }
+
+    fn apply(&self, evt: &mut CloudEvent) {
+        evt.set_extension(EXT_CORRELATION_ID, self.correlation_id.as_str());
+        evt.set_extension(EXT_CAUSATION_ID, self.causation_id.as_str());
+    }
+}
+
+/// Publishes an event, returning its sequence in the event stream. An event published in response to a command is
+/// given along with its causation and its position among the command's events, and the stream drops it if it
+/// already has it. When an expected sequence is given, the stream rejects the event if anything else was
+/// published for the same entity since that sequence. Events that don't match their schema are rejected with an
+/// `InvalidParameter` error before they are published
+#[instrument(level = "debug", skip(js, namespace, schemas))]
 pub(crate) async fn publish_es_event(
-    nc: &async_nats::Client,
+    js: &Context,
+    namespace: &Namespace,
+    schemas: &Schemas,
     event: ConcordanceEvent,
-) -> Result<()> {
+    key: &str,
+    causation: Option<(&Causation, usize)>,
+    expected: Option<&ExpectedSequence>,
+) -> Result<u64> {
+    schemas.validate_event(&event)?;
     let evt_type = event.event_type.to_snake();
-    let topic = format!("{EVENT_TOPIC_PREFIX}.{evt_type}"); // e.g. cc.events.amount_withdrawn
+    // e.g. cc.events.bankaccount.ACT123.amount_withdrawn, or cc.events.amount_withdrawn for legacy subjects
+    let topic = namespace.event_subject(&event.stream, key, &evt_type);
 
-    let cloud_event: CloudEvent = event.into();
+    let mut cloud_event: CloudEvent = event.into();
+    if let Some((causation, _)) = causation {
+        causation.apply(&mut cloud_event);
+    }
     let Ok(raw) = serde_json::to_vec(&cloud_event) else {
         error!("Failed to serialize a stock cloudevent. Something is very wrong.");
         return Err(RpcError::Ser("Fatal serialization failure - could not serialize a cloud event".to_string()));
     };
 
-    nc.request(topic, raw.into())
+    let mut headers = inject_trace_context(HeaderMap::new());
+    if let Some((causation, index)) = causation {
+        headers.insert(NATS_MSG_ID, causation.published_event_id(index).as_str());
+    }
+    if let Some(expected) = expected {
+        headers.insert(
+            NATS_EXPECTED_LAST_SUBJECT_SEQUENCE,
+            expected.sequence.to_string().as_str(),
+        );
+        headers.insert(
+            NATS_EXPECTED_LAST_SUBJECT_SEQUENCE_SUBJECT,
+            expected.filter.as_str(),
+        );
+    }
+
+    let ack = js
+        .publish_with_headers(topic, headers, raw.into())
+        .await
+        .map_err(|e| RpcError::Nats(e.to_string()))?
         .await
         .map_err(|e| RpcError::Nats(e.to_string()))?;
 
-    Ok(())
+    Ok(ack.sequence)
 }
 
-#[instrument(level = "debug", skip(nc))]
+/// Looks up the sequence of the last event published for a single entity, returning 0 if there are none
+#[instrument(level = "debug", skip(js, namespace))]
+pub(crate) async fn last_event_sequence(
+    js: &Context,
+    namespace: &Namespace,
+    stream: &str,
+    key: &str,
+) -> Result<ExpectedSequence> {
+    let filter = namespace.entity_event_filter(stream, key);
+    let response: serde_json::Value = js
+        .request(
+            format!("STREAM.MSG.GET.{}", namespace.event_stream_name()),
+            &json!({ "last_by_subj": filter }),
+        )
+        .await
+        .map_err(|e| RpcError::Nats(e.to_string()))?;
+
+    let sequence = match (
+        response["message"]["seq"].as_u64(),
+        response["error"]["err_code"].as_u64(),
+    ) {
+        (Some(seq), _) => seq,
+        (None, Some(JS_NO_MESSAGE_FOUND)) => 0,
+        _ => {
+            return Err(RpcError::Nats(format!(
+                "Failed to look up last event for {filter}: {}",
+                response["error"]
+            )))
+        }
+    };
+    Ok(ExpectedSequence { filter, sequence })
+}
+
+#[instrument(level = "debug", skip(nc, namespace))]
 pub(crate) async fn publish_raw_command(
     nc: &async_nats::Client,
+    namespace: &Namespace,
     cmd: RawCommand,
     stream: &str,
 ) -> Result<()> {
-    let topic = format!("{COMMAND_TOPIC_PREFIX}.{stream}"); // e.g. cc.commands.bankaccount
+    let prefix = namespace.command_subject_prefix();
+    let topic = format!("{prefix}.{stream}"); // e.g. cc.commands.bankaccount
 
     let Ok(raw) = serde_json::to_vec(&cmd) else {
         error!("Failed to serialize an internal raw command. Something is very wrong.");
         return Err(RpcError::Ser("Fatal serialization failure - could not serialize a raw command".to_string()));
     };
 
-    nc.request(topic, raw.into())
+    let mut headers = inject_trace_context(HeaderMap::new());
+    if let Some(id) = &cmd.id {
+        headers.insert(NATS_MSG_ID, id.as_str());
+    }
+    nc.request_with_headers(topic, headers, raw.into())
         .await
         .map_err(|e| RpcError::Nats(e.to_string()))?;
 
@@ -80,6 +236,17 @@ impl From<ConcordanceEvent> for CloudEvent {
     }
 }
 
+/// Converts an event read from the event stream into the event handed to actors, with its sequence in the stream
+pub(crate) fn recorded_event(event: CloudEvent, sequence: Option<u64>) -> ConcordanceEvent {
+    let mut event: ConcordanceEvent = event.into();
+    if let Some(metadata) = event.metadata.as_mut() {
+        metadata.sequence = sequence;
+    }
+    event
+}
+
+/// Converts a cloud event into an internal Concordance Event, keeping its id, source, time and correlation in the
+/// event's metadata
 impl From<CloudEvent> for ConcordanceEvent {
     fn from(val: CloudEvent) -> ConcordanceEvent {
         let payload = match val.data() {
@@ -95,6 +262,14 @@ impl From<CloudEvent> for ConcordanceEvent {
                 .cloned()
                 .unwrap_or("".to_string().into())
                 .to_string(),
+            metadata: Some(EventMetadata {
+                id: val.id().to_string(),
+                source: val.source().to_string(),
+                time: val.time().map(|time| time.to_rfc3339()),
+                sequence: None,
+                correlation_id: val.extension(EXT_CORRELATION_ID).map(|id| id.to_string()),
+                causation_id: val.extension(EXT_CAUSATION_ID).map(|id| id.to_string()),
+            }),
             payload,
         }
     }
@@ -102,11 +277,14 @@ impl From<CloudEvent> for ConcordanceEvent {
 
 #[cfg(test)]
 mod test {
-    use cloudevents::{event::ExtensionValue, Data};
+    use cloudevents::{event::ExtensionValue, AttributesReader, Data};
     use serde::{Deserialize, Serialize};
 
-    use super::CloudEvent;
-    use crate::{events::EXT_CONCORDANCE_STREAM, eventsourcing::Event as ConcordanceEvent};
+    use super::{recorded_event, Causation, CloudEvent, EXT_CAUSATION_ID, EXT_CORRELATION_ID};
+    use crate::{
+        consumers::RawCommand, events::EXT_CONCORDANCE_STREAM,
+        eventsourcing::Event as ConcordanceEvent,
+    };
 
     #[derive(Debug, Clone, Serialize, Deserialize)]
     struct CreateAccountCommand {
@@ -135,6 +313,7 @@ mod test {
             event_type: "account_created".to_string(),
             payload: serde_json::to_vec(&ace).unwrap(),
             stream: "bankaccount".to_string(),
+            metadata: None,
         };
         let ce: CloudEvent = internal_event.into();
 
@@ -150,11 +329,68 @@ mod test {
             assert_eq!(round_tripped.initial_balance, 100);
         }
 
-        let ie2: ConcordanceEvent = ce.into();
+        let id = ce.id().to_string();
+        let time = ce.time().unwrap().to_rfc3339();
+        let ie2 = recorded_event(ce, Some(7));
         assert_eq!(ie2.event_type, "account_created");
         assert_eq!(ie2.stream, "bankaccount");
         let ace2: AccountCreatedEvent = serde_json::from_slice(&ie2.payload).unwrap();
         assert_eq!(ace2.account_number, "ABC123");
         assert_eq!(ace2.min_balance, 1000);
+
+        let metadata = ie2.metadata.unwrap();
+        assert_eq!(metadata.id, id);
+        assert_eq!(metadata.source, "concordance");
+        assert_eq!(metadata.time, Some(time));
+        assert_eq!(metadata.sequence, Some(7));
+        assert_eq!(metadata.correlation_id, None);
+    }
+
+    #[test]
+    fn causation_follows_a_saga() {
+        let started = Causation::for_command(&RawCommand {
+            command_type: "request_wire_transfer".to_string(),
+            id: Some("CMD1".to_string()),
+            ..Default::default()
+        });
+        assert_eq!("CMD1", started.correlation_id);
+        assert_eq!("CMD1", started.causation_id);
+
+        let mut ce: CloudEvent = ConcordanceEvent {
+            event_type: "wire_transfer_requested".to_string(),
+            payload: b"{}".to_vec(),
+            stream: "bankaccount".to_string(),
+            metadata: None,
+        }
+        .into();
+        started.apply(&mut ce);
+        assert_eq!(
+            ce.extension(EXT_CAUSATION_ID),
+            Some(&ExtensionValue::String("CMD1".to_string()))
+        );
+
+        // Commands issued in response to the event carry on the saga, caused by the event
+        let next = Causation::for_event(&ce);
+        assert_eq!("CMD1", next.correlation_id);
+        assert_eq!(ce.id(), next.causation_id);
+        // Handling the event again issues the same command ids
+        assert_eq!(format!("{}.1", ce.id()), next.issued_command_id(1));
+        assert_eq!(next.issued_command_id(1), next.issued_command_id(1));
+        assert_ne!(next.issued_command_id(0), next.issued_command_id(1));
+
+        let followed = Causation::for_command(&RawCommand {
+            id: Some("CMD2".to_string()),
+            correlation_id: Some(next.correlation_id),
+            causation_id: Some(next.causation_id),
+            ..Default::default()
+        });
+        assert_eq!("CMD1", followed.correlation_id);
+        assert_eq!("CMD2", followed.causation_id);
+        assert_eq!("CMD2.0", followed.published_event_id(0));
+
+        // Events from before correlation ids were recorded start a new saga
+        let mut legacy = ce.clone();
+        legacy.remove_extension(EXT_CORRELATION_ID);
+        assert_eq!(legacy.id(), Causation::for_event(&legacy).correlation_id);
     }
 }
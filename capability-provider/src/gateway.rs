@@ -0,0 +1,386 @@
+//! # Command Gateway
+//! Lets clients send a command as a NATS request on `cc.gateway.{aggregate}` and receive its outcome as the reply.
+//! The gateway stores the command in the command stream like any other command, along with the requester's reply
+//! subject, so it is still handled durably by the aggregate's command worker. The worker replies once the outcome
+//! is final: when the command's events have been published, when the command is invalid or refused by the
+//! aggregate and so isn't retried, or when it fails on its last delivery. A request that times out may therefore
+//! still be handled later, and sending it again with the same id gets a reply saying it's a duplicate
+
+use async_nats::{jetstream::Context, HeaderMap, Message};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, warn, Instrument};
+use wasmbus_rpc::error::RpcError;
+
+use crate::{
+    config::{ActorRole, InterestConstraint},
+    consumers::{ConsumerManager, RawCommand},
+    natsclient::{Namespace, NATS_MSG_ID},
+    otel::{continue_trace, inject_trace_context},
+    Result,
+};
+
+pub(crate) const GATEWAY_TOPIC_PREFIX: &str = "cc.gateway";
+/// Each command sent to the gateway must only be stored once, however many provider instances are running
+const GATEWAY_QUEUE_GROUP: &str = "concordance_gateway";
+/// Carries the requester's reply subject from the gateway to the command worker
+pub(crate) const REPLY_TO_HEADER: &str = "Concordance-Reply-To";
+
+/// An event published by an aggregate in response to a command
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct EmittedEvent {
+    pub event_type: String,
+    pub stream: String,
+    /// Sequence of the event in the event stream
+    pub sequence: u64,
+    pub payload: serde_json::Value,
+}
+
+/// Why a command was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum RejectionReason {
+    /// The command isn't well formed or doesn't match its schema
+    Invalid,
+    /// The aggregate refused the command
+    Refused,
+    /// The aggregate couldn't handle the command, or the events it produced couldn't be published
+    Failed,
+    /// The command couldn't be accepted for handling, because no aggregate by that name is linked or the command
+    /// couldn't be stored
+    Unavailable,
+}
+
+/// The reply to a command sent through the gateway
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub(crate) enum CommandReply {
+    /// The command was handled and these events were published
+    Accepted { events: Vec<EmittedEvent> },
+    Rejected {
+        reason: RejectionReason,
+        error: String,
+    },
+    /// A command with the same id was already stored at this sequence of the command stream, and its outcome is
+    /// sent to the request that stored it
+    Duplicate { sequence: u64 },
+}
+
+impl CommandReply {
+    pub(crate) fn rejected(reason: RejectionReason, error: impl Into<String>) -> CommandReply {
+        CommandReply::Rejected {
+            reason,
+            error: error.into(),
+        }
+    }
+
+    pub(crate) async fn send(&self, nc: &async_nats::Client, reply_to: String) {
+        let body = match serde_json::to_vec(self) {
+            Ok(body) => body,
+            Err(e) => {
+                error!("Failed to serialize command reply: {e}");
+                return;
+            }
+        };
+        if let Err(e) = nc.publish(reply_to, body.into()).await {
+            error!("Failed to send command reply: {e}");
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct CommandGateway {
+    pub nc: async_nats::Client,
+    pub js: Context,
+    pub namespace: Namespace,
+    pub consumer_manager: ConsumerManager,
+}
+
+impl CommandGateway {
+    pub(crate) async fn serve(self) -> Result<JoinHandle<()>> {
+        let subject = format!("{}.*", self.namespace.gateway_subject_prefix());
+        let mut sub = self
+            .nc
+            .queue_subscribe(subject.clone(), GATEWAY_QUEUE_GROUP.to_string())
+            .await
+            .map_err(|e| RpcError::Nats(format!("Failed to subscribe to {subject}: {e}")))?;
+        info!("Serving command gateway requests on {subject}");
+        Ok(tokio::spawn(async move {
+            while let Some(msg) = sub.next().await {
+                let gateway = self.clone();
+                let span = tracing::info_span!("gateway_request", subject = %msg.subject);
+                tokio::spawn(async move { gateway.handle(msg).await }.instrument(span));
+            }
+            debug!("Command gateway subscription closed");
+        }))
+    }
+
+    async fn handle(&self, msg: Message) {
+        continue_trace(&msg);
+        let Some(reply) = msg.reply.clone() else {
+            warn!("Ignoring gateway request without a reply subject");
+            return;
+        };
+        let prefix = format!("{}.", self.namespace.gateway_subject_prefix());
+        let aggregate = msg.subject.strip_prefix(&prefix).unwrap_or_default();
+        // Accepted commands are replied to by the command worker once they have been handled
+        if let Err(early) = self.submit(aggregate, &msg.payload, reply.clone()).await {
+            early.send(&self.nc, reply).await;
+        }
+    }
+
+    /// Stores the command in the command stream for the aggregate's command worker to handle. A command without
+    /// an id is given one here, and the id lets the stream drop a command that a client sends again. Returns the
+    /// reply to send right away when the command won't be handled for this request
+    async fn submit(
+        &self,
+        aggregate: &str,
+        payload: &[u8],
+        reply: String,
+    ) -> std::result::Result<(), CommandReply> {
+        let mut command: RawCommand = serde_json::from_slice(payload).map_err(|e| {
+            CommandReply::rejected(RejectionReason::Invalid, format!("Invalid command: {e}"))
+        })?;
+        let linked = self
+            .consumer_manager
+            .consumers()
+            .await
+            .into_iter()
+            .any(|i| {
+                i.role == ActorRole::Aggregate
+                    && i.interest_constraint == InterestConstraint::Commands
+                    && i.entity_name == aggregate
+            });
+        if !linked {
+            return Err(CommandReply::rejected(
+                RejectionReason::Unavailable,
+                format!("No aggregate named '{aggregate}' is linked to this provider"),
+            ));
+        }
+
+        let id = command
+            .id
+            .get_or_insert_with(|| uuid::Uuid::new_v4().to_string())
+            .clone();
+        let payload = serde_json::to_vec(&command).map_err(|e| {
+            CommandReply::rejected(RejectionReason::Invalid, format!("Invalid command: {e}"))
+        })?;
+        let mut headers = inject_trace_context(HeaderMap::new());
+        headers.insert(REPLY_TO_HEADER, reply.as_str());
+        headers.insert(NATS_MSG_ID, id.as_str());
+        let subject = format!("{}.{aggregate}", self.namespace.command_subject_prefix());
+        let stored = async {
+            self.js
+                .publish_with_headers(subject, headers, payload.into())
+                .await?
+                .await
+        }
+        .await;
+        match stored {
+            // The worker replies to the request that stored the command, which may no longer be waiting
+            Ok(ack) if ack.duplicate => {
+                debug!(id, sequence = ack.sequence, "Dropped duplicate command");
+                Err(CommandReply::Duplicate {
+                    sequence: ack.sequence,
+                })
+            }
+            Ok(ack) => {
+                debug!(
+                    command_type = command.command_type,
+                    key = command.key,
+                    id,
+                    sequence = ack.sequence,
+                    "Stored command from gateway"
+                );
+                Ok(())
+            }
+            Err(e) => Err(CommandReply::rejected(
+                RejectionReason::Unavailable,
+                format!("Failed to store command: {e}"),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+    use wasmbus_rpc::core::LinkDefinition;
+
+    use super::{CommandGateway, CommandReply, EmittedEvent, RejectionReason, REPLY_TO_HEADER};
+    use crate::{
+        config::InterestDeclaration,
+        consumers::{CommandConsumer, ConsumerManager, RawCommand, WorkError, WorkResult, Worker},
+        natsclient::{
+            test::{clear_streams, create_js_context},
+            AckableMessage, Namespace, NatsClient,
+        },
+    };
+
+    /// Stands in for an aggregate's command worker, refusing `close_account` and accepting anything else with
+    /// a single event
+    struct ReplyingCommandWorker {
+        nc: async_nats::Client,
+    }
+
+    #[async_trait::async_trait]
+    impl Worker for ReplyingCommandWorker {
+        type Message = RawCommand;
+
+        async fn do_work(&self, mut message: AckableMessage<Self::Message>) -> WorkResult<()> {
+            let reply = if message.command_type == "close_account" {
+                CommandReply::rejected(RejectionReason::Refused, "Account has funds")
+            } else {
+                CommandReply::Accepted {
+                    events: vec![EmittedEvent {
+                        event_type: "funds_deposited".to_string(),
+                        stream: "bankaccount".to_string(),
+                        sequence: 1,
+                        payload: message.data.clone(),
+                    }],
+                }
+            };
+            let reply_to = message.header(REPLY_TO_HEADER);
+            message.ack().await.map_err(WorkError::NatsError)?;
+            if let Some(reply_to) = reply_to {
+                reply.send(&self.nc, reply_to).await;
+            }
+            Ok(())
+        }
+    }
+
+    async fn request(nc: &async_nats::Client, payload: serde_json::Value) -> CommandReply {
+        let msg = nc
+            .request(
+                "cc.gateway.bankaccount".to_string(),
+                serde_json::to_vec(&payload).unwrap().into(),
+            )
+            .await
+            .unwrap();
+        serde_json::from_slice(&msg.payload).unwrap()
+    }
+
+    #[tokio::test]
+    async fn gateway_replies_with_command_outcome() {
+        let nc = async_nats::connect("127.0.0.1").await.unwrap();
+        let js = create_js_context().await;
+        clear_streams(js.clone()).await;
+
+        let client = NatsClient::new(js.clone(), Namespace::default());
+        let (e, c) = client.ensure_streams().await.unwrap();
+        let cm = ConsumerManager::new(e, c);
+        let gateway = CommandGateway {
+            nc: nc.clone(),
+            js: js.clone(),
+            namespace: Namespace::default(),
+            consumer_manager: cm.clone(),
+        }
+        .serve()
+        .await
+        .unwrap();
+
+        let deposit = json!({
+            "command_type": "deposit_funds",
+            "key": "ACCT1",
+            "data": {"amount": 200},
+            "id": "deposit-1"
+        });
+        let unlinked = request(&nc, deposit.clone()).await;
+        assert!(matches!(
+            unlinked,
+            CommandReply::Rejected {
+                reason: RejectionReason::Unavailable,
+                ..
+            }
+        ));
+
+        let interest = InterestDeclaration::aggregate_for_commands(
+            "MXBOB",
+            "bankaccount",
+            "account_number",
+            LinkDefinition::default(),
+        );
+        cm.add_consumer::<ReplyingCommandWorker, CommandConsumer>(
+            interest,
+            ReplyingCommandWorker { nc: nc.clone() },
+        )
+        .await
+        .unwrap();
+
+        let invalid = request(&nc, json!({"key": "ACCT1"})).await;
+        assert!(matches!(
+            invalid,
+            CommandReply::Rejected {
+                reason: RejectionReason::Invalid,
+                ..
+            }
+        ));
+
+        assert_eq!(
+            CommandReply::Accepted {
+                events: vec![EmittedEvent {
+                    event_type: "funds_deposited".to_string(),
+                    stream: "bankaccount".to_string(),
+                    sequence: 1,
+                    payload: json!({"amount": 200}),
+                }]
+            },
+            request(&nc, deposit.clone()).await
+        );
+        // The command stream drops a command sent again with the same id, so only the gateway can reply
+        assert!(matches!(
+            request(&nc, deposit).await,
+            CommandReply::Duplicate { .. }
+        ));
+
+        let close = json!({
+            "command_type": "close_account",
+            "key": "ACCT1",
+            "data": {}
+        });
+        assert_eq!(
+            CommandReply::rejected(RejectionReason::Refused, "Account has funds"),
+            request(&nc, close).await
+        );
+
+        gateway.abort();
+        clear_streams(js).await;
+    }
+
+    #[test]
+    fn replies_are_tagged_with_their_status() {
+        let accepted = CommandReply::Accepted {
+            events: vec![EmittedEvent {
+                event_type: "funds_deposited".to_string(),
+                stream: "bankaccount".to_string(),
+                sequence: 12,
+                payload: json!({"amount": 200}),
+            }],
+        };
+        assert_eq!(
+            json!({
+                "status": "accepted",
+                "events": [{
+                    "event_type": "funds_deposited",
+                    "stream": "bankaccount",
+                    "sequence": 12,
+                    "payload": {"amount": 200}
+                }]
+            }),
+            serde_json::to_value(accepted).unwrap()
+        );
+
+        let rejected = CommandReply::rejected(RejectionReason::Invalid, "Invalid command");
+        assert_eq!(
+            json!({"status": "rejected", "reason": "invalid", "error": "Invalid command"}),
+            serde_json::to_value(rejected).unwrap()
+        );
+
+        let duplicate = CommandReply::Duplicate { sequence: 7 };
+        assert_eq!(
+            json!({"status": "duplicate", "sequence": 7}),
+            serde_json::to_value(duplicate).unwrap()
+        );
+    }
+}
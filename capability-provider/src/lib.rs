@@ -1,18 +1,28 @@
 use wasmbus_rpc::error::RpcResult;
 
+mod admin;
 mod config;
 mod consumers;
+mod deadletter;
 mod events;
+mod gateway;
+mod metrics;
 
 #[allow(dead_code)]
 mod eventsourcing;
 
 mod natsclient;
+mod otel;
+mod rehydrate;
+mod schemas;
 mod state;
 mod wcprovider;
 mod workers;
 
-pub use config::BaseConfiguration;
+pub use config::{
+    BaseConfiguration, BucketSettings, EventSubjects, OutboundStreamSettings, SchemaSettings,
+    StreamSettings,
+};
 pub use wcprovider::ConcordanceProvider;
 
 pub type Result<T> = RpcResult<T>;
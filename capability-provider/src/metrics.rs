@@ -0,0 +1,276 @@
+//! # Metrics
+//! Prometheus metrics for the messages each consumer handles, the time spent in calls to actors and in reads and
+//! writes of the state buckets, and how far each consumer is behind its stream. The metrics are collected in one
+//! registry for the whole provider and, when `metrics_address` is configured, served over HTTP on `/metrics`
+
+use std::{convert::Infallible, future::Future, net::SocketAddr, sync::OnceLock};
+
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use tokio::task::JoinHandle;
+use tracing::{error, info};
+use wasmbus_rpc::error::RpcError;
+
+use crate::{consumers::ConsumerManager, Result};
+
+const METRICS_PATH: &str = "/metrics";
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+/// The provider's metrics, created the first time they're used
+pub(crate) fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(Metrics::new)
+}
+
+pub(crate) struct Metrics {
+    registry: Registry,
+    delivered: IntCounterVec,
+    acked: IntCounterVec,
+    nacked: IntCounterVec,
+    skipped: IntCounterVec,
+    dead_lettered: IntCounterVec,
+    pending: IntGaugeVec,
+    ack_pending: IntGaugeVec,
+    actor_invocations: HistogramVec,
+    state_operations: HistogramVec,
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        let registry = Registry::new_custom(Some("concordance".to_string()), None)
+            .expect("metrics registry should be valid");
+        let counter = |name: &str, help: &str| {
+            let counter = IntCounterVec::new(Opts::new(name, help), &["consumer"])
+                .expect("metric should be valid");
+            registry
+                .register(Box::new(counter.clone()))
+                .expect("metric should only be registered once");
+            counter
+        };
+        let gauge = |name: &str, help: &str| {
+            let gauge = IntGaugeVec::new(Opts::new(name, help), &["consumer"])
+                .expect("metric should be valid");
+            registry
+                .register(Box::new(gauge.clone()))
+                .expect("metric should only be registered once");
+            gauge
+        };
+        let histogram = |name: &str, help: &str, labels: &[&str]| {
+            let histogram = HistogramVec::new(HistogramOpts::new(name, help), labels)
+                .expect("metric should be valid");
+            registry
+                .register(Box::new(histogram.clone()))
+                .expect("metric should only be registered once");
+            histogram
+        };
+
+        Metrics {
+            delivered: counter(
+                "messages_delivered_total",
+                "Messages delivered to a consumer, including redeliveries",
+            ),
+            acked: counter(
+                "messages_acked_total",
+                "Messages a consumer's worker finished with",
+            ),
+            nacked: counter(
+                "messages_nacked_total",
+                "Messages a consumer's worker gave back for redelivery",
+            ),
+            skipped: counter(
+                "messages_skipped_total",
+                "Events a consumer's worker acked without handling because its actor isn't interested in them",
+            ),
+            dead_lettered: counter(
+                "messages_dead_lettered_total",
+                "Messages of a consumer moved to the dead letter stream",
+            ),
+            pending: gauge(
+                "consumer_pending_messages",
+                "Messages in the stream that haven't been delivered to a consumer yet",
+            ),
+            ack_pending: gauge(
+                "consumer_ack_pending_messages",
+                "Messages delivered to a consumer that haven't been acked yet",
+            ),
+            actor_invocations: histogram(
+                "actor_invocation_duration_seconds",
+                "Time taken by calls to actors",
+                &["worker", "entity"],
+            ),
+            state_operations: histogram(
+                "state_operation_duration_seconds",
+                "Time taken by reads, writes and deletes in the state and snapshot buckets",
+                &["operation"],
+            ),
+            registry,
+        }
+    }
+
+    pub(crate) fn delivered(&self, consumer: &str) {
+        self.delivered.with_label_values(&[consumer]).inc();
+    }
+
+    pub(crate) fn acked(&self, consumer: &str) {
+        self.acked.with_label_values(&[consumer]).inc();
+    }
+
+    pub(crate) fn nacked(&self, consumer: &str) {
+        self.nacked.with_label_values(&[consumer]).inc();
+    }
+
+    pub(crate) fn skipped(&self, consumer: &str) {
+        self.skipped.with_label_values(&[consumer]).inc();
+    }
+
+    pub(crate) fn dead_lettered(&self, consumer: &str) {
+        self.dead_lettered.with_label_values(&[consumer]).inc();
+    }
+
+    /// Times a call that a worker makes to its actor
+    pub(crate) async fn time_invocation<F: Future>(
+        &self,
+        worker: &str,
+        entity: &str,
+        call: F,
+    ) -> F::Output {
+        let timer = self
+            .actor_invocations
+            .with_label_values(&[worker, entity])
+            .start_timer();
+        let output = call.await;
+        timer.observe_duration();
+        output
+    }
+
+    /// Times a `read`, `write` or `delete` of an entry in a state bucket
+    pub(crate) async fn time_state<F: Future>(&self, operation: &str, call: F) -> F::Output {
+        let timer = self
+            .state_operations
+            .with_label_values(&[operation])
+            .start_timer();
+        let output = call.await;
+        timer.observe_duration();
+        output
+    }
+
+    /// Records how many messages each of the provider's consumers has yet to handle
+    async fn update_consumer_lag(&self, consumer_manager: &ConsumerManager) {
+        // Consumers of actors that have been unlinked shouldn't be reported anymore
+        self.pending.reset();
+        self.ack_pending.reset();
+        for status in consumer_manager.consumer_statuses().await {
+            let consumer = status.interest.consumer_name();
+            if let Some(pending) = status.pending {
+                self.pending
+                    .with_label_values(&[&consumer])
+                    .set(pending as i64);
+            }
+            if let Some(ack_pending) = status.ack_pending {
+                self.ack_pending
+                    .with_label_values(&[&consumer])
+                    .set(ack_pending as i64);
+            }
+        }
+    }
+
+    /// Encodes every metric in the Prometheus text format
+    fn render(&self) -> Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .map_err(|e| RpcError::Ser(format!("Failed to encode metrics: {e}")))?;
+        Ok(buffer)
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct MetricsServer {
+    /// The local address to listen on, e.g. `127.0.0.1:9090`
+    pub address: String,
+    pub consumer_manager: ConsumerManager,
+}
+
+impl MetricsServer {
+    pub(crate) async fn serve(self) -> Result<JoinHandle<()>> {
+        let address: SocketAddr = self.address.parse().map_err(|e| {
+            RpcError::InvalidParameter(format!("Invalid metrics address {}: {e}", self.address))
+        })?;
+        let builder = Server::try_bind(&address).map_err(|e| {
+            RpcError::InvalidParameter(format!("Failed to listen for metrics on {address}: {e}"))
+        })?;
+        let make_service = make_service_fn(move |_| {
+            let server = self.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let server = server.clone();
+                    async move { Ok::<_, Infallible>(server.handle(req).await) }
+                }))
+            }
+        });
+        let server = builder.serve(make_service);
+        info!("Serving metrics on http://{address}{METRICS_PATH}");
+        Ok(tokio::spawn(async move {
+            if let Err(e) = server.await {
+                error!(error = %e, "Metrics server stopped");
+            }
+        }))
+    }
+
+    async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        if req.method() != Method::GET || req.uri().path() != METRICS_PATH {
+            return respond(StatusCode::NOT_FOUND, Body::empty());
+        }
+        let metrics = metrics();
+        metrics.update_consumer_lag(&self.consumer_manager).await;
+        match metrics.render() {
+            Ok(body) => respond(StatusCode::OK, body.into()),
+            Err(e) => {
+                error!(error = %e, "Failed to render metrics");
+                respond(StatusCode::INTERNAL_SERVER_ERROR, Body::empty())
+            }
+        }
+    }
+}
+
+fn respond(status: StatusCode, body: Body) -> Response<Body> {
+    let mut response = Response::new(body);
+    *response.status_mut() = status;
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use super::metrics;
+
+    #[tokio::test]
+    async fn renders_recorded_metrics() {
+        let metrics = metrics();
+        metrics.delivered("PROJ_metrics_test");
+        metrics.delivered("PROJ_metrics_test");
+        metrics.acked("PROJ_metrics_test");
+        metrics.skipped("PROJ_metrics_test");
+        let answer = metrics
+            .time_invocation("general_event", "metrics_test", async { 42 })
+            .await;
+        assert_eq!(42, answer);
+
+        let text = String::from_utf8(metrics.render().unwrap()).unwrap();
+        assert!(text
+            .contains(r#"concordance_messages_delivered_total{consumer="PROJ_metrics_test"} 2"#));
+        assert!(
+            text.contains(r#"concordance_messages_acked_total{consumer="PROJ_metrics_test"} 1"#)
+        );
+        assert!(
+            text.contains(r#"concordance_messages_skipped_total{consumer="PROJ_metrics_test"} 1"#)
+        );
+        assert!(text.contains(
+            r#"concordance_actor_invocation_duration_seconds_count{entity="metrics_test",worker="general_event"} 1"#
+        ));
+    }
+}
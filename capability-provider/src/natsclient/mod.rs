@@ -1,15 +1,24 @@
+mod namespace;
 mod natsconn;
 
-const EVENT_STREAM_NAME: &str = "CC_EVENTS";
-const EVENTS_STREAM_TOPIC: &str = "cc.events.*";
+pub(crate) const EVENT_STREAM_NAME: &str = "CC_EVENTS";
+pub(crate) const COMMANDS_STREAM_NAME: &str = "CC_COMMANDS";
+pub(crate) const OUTBOUND_STREAM_NAME: &str = "CC_OUTBOUND";
+pub(crate) const DEAD_LETTER_STREAM_NAME: &str = "CC_DLQ";
+pub(crate) const DEAD_LETTER_TOPIC_PREFIX: &str = "cc.dlq";
 
-const COMMANDS_STREAM_NAME: &str = "CC_COMMANDS";
-const COMMANDS_STREAM_TOPIC: &str = "cc.commands.*";
+pub(crate) const NATS_EXPECTED_LAST_SUBJECT_SEQUENCE: &str = "Nats-Expected-Last-Subject-Sequence";
+/// Streams drop a message whose id they have already stored within their duplicate window
+pub(crate) const NATS_MSG_ID: &str = "Nats-Msg-Id";
 
 /// The default time given for an event/command to ack. Set to 3 to give a buffer
 /// for actors that have a default timeout of 2s
 pub(crate) const DEFAULT_ACK_TIME: std::time::Duration = std::time::Duration::from_secs(3);
 
+/// The default number of times a command or event is delivered before it is given up on and moved to the dead
+/// letter stream
+pub(crate) const DEFAULT_MAX_DELIVER: i64 = 3;
+
 // Timeout accounts for time to send message
 pub(crate) const SEND_TIMEOUT_DURATION: tokio::time::Duration = tokio::time::Duration::from_secs(2);
 
@@ -17,19 +26,104 @@ use async_nats::{jetstream::AckKind, Error as NatsError};
 use std::fmt::Debug;
 use std::ops::Deref;
 use std::ops::DerefMut;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Duration;
 
 use tracing::{error, warn};
 
+use crate::config::{InterestDeclaration, RetryPolicy};
+use crate::metrics::metrics;
+
+pub(crate) use namespace::Namespace;
 pub(crate) use natsconn::NatsClient;
 
+/// Nacks sent on behalf of dropped messages that haven't been handed to the connection yet
+static PENDING_DROP_NACKS: AtomicUsize = AtomicUsize::new(0);
+
+/// Waits, up to the given timeout, until the nacks sent on behalf of dropped messages have been handed to the
+/// connection, so that flushing the connection afterwards sends them to the server
+pub(crate) async fn wait_for_dropped_nacks(timeout: Duration) {
+    let deadline = tokio::time::Instant::now() + timeout;
+    while PENDING_DROP_NACKS.load(Ordering::Acquire) > 0 && tokio::time::Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// Reports a message as in progress until it is dropped. See [`AckableMessage::hold`]
+pub(crate) struct InProgress(tokio::task::JoinHandle<()>);
+
+impl Drop for InProgress {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
 pub struct AckableMessage<T> {
     pub(crate) inner: T,
     // Wrapped in an option so we only do it once
     pub(crate) acker: Option<async_nats::jetstream::Message>,
+    // Set for messages that didn't come from a consumer, such as redelivered dead letters, which have nothing
+    // to ack
+    pub(crate) detached: bool,
+    // For detached messages, the sequence of the message in the stream it was originally delivered from
+    pub(crate) origin_sequence: Option<u64>,
+    // How long the server waits before redelivering this message once it is nacked
+    pub(crate) nak_delay: Option<Duration>,
+    pub(crate) max_deliver: i64,
 }
 
 impl<T> AckableMessage<T> {
+    /// Wraps a message delivered by a consumer with the given retry policy
+    pub(crate) fn delivered(
+        inner: T,
+        msg: async_nats::jetstream::Message,
+        retry: &RetryPolicy,
+    ) -> AckableMessage<T> {
+        let delivered = match msg.info() {
+            Ok(info) => {
+                metrics().delivered(info.consumer);
+                info.delivered
+            }
+            Err(_) => 1,
+        };
+        AckableMessage {
+            inner,
+            acker: Some(msg),
+            detached: false,
+            origin_sequence: None,
+            nak_delay: retry.redelivery_delay(delivered),
+            max_deliver: retry.max_deliver,
+        }
+    }
+
+    /// Wraps a message that isn't backed by a consumer delivery, such as a dead letter, along with its sequence in
+    /// the stream it was originally delivered from. Acking or nacking it does nothing
+    pub(crate) fn detached(inner: T, stream_sequence: u64) -> AckableMessage<T> {
+        AckableMessage {
+            inner,
+            acker: None,
+            detached: true,
+            origin_sequence: Some(stream_sequence),
+            nak_delay: None,
+            max_deliver: DEFAULT_MAX_DELIVER,
+        }
+    }
+
+    /// Keeps the server from redelivering this message while it waits to be worked on, by reporting it as in
+    /// progress every `interval` until the returned guard is dropped. Detached messages have nothing to report
+    pub(crate) fn hold(&self, interval: Duration) -> Option<InProgress> {
+        let acker = self.acker.clone()?;
+        Some(InProgress(tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                if let Err(e) = acker.ack_with(AckKind::Progress).await {
+                    warn!(error = %e, "Failed to report waiting message as in progress");
+                    return;
+                }
+            }
+        })))
+    }
+
     /// Acks this message. This should be called when all work related to this message has been
     /// completed. If this is called before work is done (e.g. like sending a command), instability
     /// could occur. Calling this function again (or after nacking) is a noop.
@@ -37,6 +131,25 @@ impl<T> AckableMessage<T> {
     /// This function will only error after it has tried up to 3 times to ack the request. If it
     /// doesn't receive a response after those 3 times, this will return an error.
     pub async fn ack(&mut self) -> Result<(), NatsError> {
+        let consumer = self.consumer();
+        self.double_ack().await?;
+        if let Some(consumer) = consumer {
+            metrics().acked(&consumer);
+        }
+        Ok(())
+    }
+
+    /// Acks a message that the worker isn't interested in, without handling it
+    pub async fn skip(&mut self) -> Result<(), NatsError> {
+        let consumer = self.consumer();
+        self.double_ack().await?;
+        if let Some(consumer) = consumer {
+            metrics().skipped(&consumer);
+        }
+        Ok(())
+    }
+
+    async fn double_ack(&mut self) -> Result<(), NatsError> {
         // We want to double ack so we are sure that the server has marked this task as done
         if let Some(msg) = self.acker.take() {
             // Starting at 1 for humans/logging
@@ -53,15 +166,103 @@ impl<T> AckableMessage<T> {
                 }
             }
         } else {
-            warn!("Ack has already been sent");
+            if !self.detached {
+                warn!("Ack has already been sent");
+            }
             Ok(())
         }
     }
 
+    /// The durable consumer the message was delivered to. This is only available until the message is acked or
+    /// nacked
+    fn consumer(&self) -> Option<String> {
+        self.acker
+            .as_ref()
+            .and_then(|msg| msg.info().ok())
+            .map(|info| info.consumer.to_string())
+    }
+
+    /// The sequence of the message in its stream. For delivered messages, this is only available until the
+    /// message is acked or nacked
+    pub fn stream_sequence(&self) -> Option<u64> {
+        self.acker
+            .as_ref()
+            .and_then(|msg| msg.info().ok())
+            .map(|info| info.stream_sequence)
+            .or(self.origin_sequence)
+    }
+
+    /// Returns the value of a header of the delivered message. This is only available until the message is acked
+    /// or nacked
+    pub fn header(&self, name: &str) -> Option<String> {
+        self.acker
+            .as_ref()
+            .and_then(|msg| msg.headers.as_ref())
+            .and_then(|headers| headers.get(name))
+            .map(|value| value.to_string())
+    }
+
+    /// Makes the current span a child of the span that published this message, if it was published with a trace
+    /// context
+    pub fn continue_trace(&self) {
+        if let Some(msg) = &self.acker {
+            crate::otel::continue_trace(&msg.message);
+        }
+    }
+
+    /// Returns the consumer name and stream sequence this message was delivered with. This is only available
+    /// until the message is acked or nacked
+    pub fn delivery(&self) -> Option<(String, u64)> {
+        self.acker
+            .as_ref()
+            .and_then(|msg| msg.info().ok())
+            .map(|info| (info.consumer.to_string(), info.stream_sequence))
+    }
+
+    /// Whether this is the message's last delivery, meaning the message is moved to the dead letter stream
+    /// rather than redelivered if it isn't acked
+    pub fn is_final_delivery(&self) -> bool {
+        self.acker
+            .as_ref()
+            .and_then(|msg| msg.info().ok())
+            .map(|info| info.delivered >= self.max_deliver)
+            .unwrap_or(false)
+    }
+
+    /// Nacks this message so that it is redelivered, after the delay from the consumer's backoff if it has one
     pub async fn nack(&mut self) {
-        if let Err(e) = self.custom_ack(AckKind::Nak(None)).await {
+        let consumer = self.consumer();
+        if let Err(e) = self.custom_ack(AckKind::Nak(self.nak_delay)).await {
             error!(error = %e, "Error when nacking message");
             self.acker = None;
+        } else if let Some(consumer) = consumer {
+            metrics().nacked(&consumer);
+        }
+    }
+
+    /// Terminates this message so that it is never redelivered. The server announces this with an advisory, which
+    /// moves the message to the dead letter stream. Only use this for events, as terminating a command removes it
+    /// from the work queue before it can be copied
+    pub async fn term(&mut self) {
+        if let Err(e) = self.custom_ack(AckKind::Term).await {
+            error!(error = %e, "Error when terminating message");
+            self.acker = None;
+        }
+    }
+
+    /// Moves this message into the dead letter stream with the given reason instead of retrying it, then acks
+    /// it. Returns false if there is no delivery to move, such as for a detached message
+    pub(crate) async fn quarantine(
+        &mut self,
+        interest: &InterestDeclaration,
+        reason: String,
+    ) -> bool {
+        match self.acker.take() {
+            Some(msg) => {
+                crate::deadletter::quarantine(msg, interest, reason).await;
+                true
+            }
+            None => false,
         }
     }
 
@@ -75,7 +276,9 @@ impl<T> AckableMessage<T> {
                 Ok(())
             }
         } else {
-            warn!("Nack has already been sent");
+            if !self.detached {
+                warn!("Nack has already been sent");
+            }
             Ok(())
         }
     }
@@ -85,11 +288,17 @@ impl<T> Drop for AckableMessage<T> {
     fn drop(&mut self) {
         // self.nack escapes current lifetime, so just manually take the message
         if let Some(msg) = self.acker.take() {
+            let delay = self.nak_delay;
+            if let Ok(info) = msg.info() {
+                metrics().nacked(info.consumer);
+            }
             if let Ok(handle) = tokio::runtime::Handle::try_current() {
+                PENDING_DROP_NACKS.fetch_add(1, Ordering::AcqRel);
                 handle.spawn(async move {
-                    if let Err(e) = msg.ack_with(AckKind::Nak(None)).await {
+                    if let Err(e) = msg.ack_with(AckKind::Nak(delay)).await {
                         warn!(error = %e, "Error when sending nack during drop")
                     }
+                    PENDING_DROP_NACKS.fetch_sub(1, Ordering::AcqRel);
                 });
             } else {
                 warn!("Couldn't find async runtime to send nack during drop")
@@ -134,8 +343,12 @@ impl<T: Debug> Debug for AckableMessage<T> {
 
 #[cfg(test)]
 pub(crate) mod test {
-    use super::{COMMANDS_STREAM_NAME, EVENT_STREAM_NAME};
-    use crate::{consumers::RawCommand, state::STATE_BUCKET_NAME, Result};
+    use super::{COMMANDS_STREAM_NAME, DEAD_LETTER_STREAM_NAME, EVENT_STREAM_NAME};
+    use crate::{
+        consumers::RawCommand,
+        state::{SNAPSHOT_BUCKET_NAME, STATE_BUCKET_NAME},
+        Result,
+    };
 
     pub(crate) async fn create_js_context() -> async_nats::jetstream::Context {
         let nc = async_nats::connect("127.0.0.1").await.unwrap();
@@ -145,7 +358,9 @@ pub(crate) mod test {
     pub(crate) async fn clear_streams(js: async_nats::jetstream::Context) {
         js.delete_stream(EVENT_STREAM_NAME).await.ok();
         js.delete_stream(COMMANDS_STREAM_NAME).await.ok();
+        js.delete_stream(DEAD_LETTER_STREAM_NAME).await.ok();
         js.delete_key_value(STATE_BUCKET_NAME).await.ok();
+        js.delete_key_value(SNAPSHOT_BUCKET_NAME).await.ok();
     }
 
     pub(crate) async fn publish_command(
@@ -0,0 +1,332 @@
+use crate::{
+    admin::{ADMIN_TOPIC_PREFIX, CONTROL_TOPIC_PREFIX},
+    events::{COMMAND_TOPIC_PREFIX, EVENT_TOPIC_PREFIX},
+    gateway::GATEWAY_TOPIC_PREFIX,
+    natsclient::{
+        COMMANDS_STREAM_NAME, DEAD_LETTER_STREAM_NAME, DEAD_LETTER_TOPIC_PREFIX, EVENT_STREAM_NAME,
+        OUTBOUND_STREAM_NAME,
+    },
+    state::{SNAPSHOT_BUCKET_NAME, STATE_BUCKET_NAME},
+    Result,
+};
+use wasmbus_rpc::error::RpcError;
+
+/// Resolves the names of the streams, subjects, buckets and consumers used by the provider. When a namespace
+/// prefix is configured, every one of those names is prefixed with it so that several independent applications
+/// can share a single JetStream account without interfering with each other
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Namespace {
+    prefix: Option<String>,
+    keyed_events: bool,
+}
+
+impl Namespace {
+    pub fn new(prefix: Option<&str>) -> Result<Namespace> {
+        let prefix = prefix.map(str::trim).filter(|p| !p.is_empty());
+        if let Some(p) = prefix {
+            if !p
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+            {
+                return Err(RpcError::InvalidParameter(format!(
+                    "Namespace '{p}' may only contain alphanumeric characters, '-' and '_'"
+                )));
+            }
+        }
+        Ok(Namespace {
+            prefix: prefix.map(|p| p.to_string()),
+            keyed_events: false,
+        })
+    }
+
+    /// Switches event subjects between the keyed scheme (`cc.events.{stream}.{key}.{event_type}`) and the
+    /// legacy scheme (`cc.events.{event_type}`) still used by event streams created before keyed subjects
+    pub fn with_keyed_events(self, keyed_events: bool) -> Namespace {
+        Namespace {
+            keyed_events,
+            ..self
+        }
+    }
+
+    pub fn keyed_events(&self) -> bool {
+        self.keyed_events
+    }
+
+    /// e.g. `CC_EVENTS` or `bank_CC_EVENTS`
+    pub fn event_stream_name(&self) -> String {
+        self.scoped_name(EVENT_STREAM_NAME)
+    }
+
+    /// e.g. `CC_COMMANDS` or `bank_CC_COMMANDS`
+    pub fn command_stream_name(&self) -> String {
+        self.scoped_name(COMMANDS_STREAM_NAME)
+    }
+
+    /// e.g. `CC_OUTBOUND` or `bank_CC_OUTBOUND`
+    pub fn outbound_stream_name(&self) -> String {
+        self.scoped_name(OUTBOUND_STREAM_NAME)
+    }
+
+    /// e.g. `CC_DLQ` or `bank_CC_DLQ`
+    pub fn dead_letter_stream_name(&self) -> String {
+        self.scoped_name(DEAD_LETTER_STREAM_NAME)
+    }
+
+    /// e.g. `CC_STATE` or `bank_CC_STATE`
+    pub fn state_bucket_name(&self) -> String {
+        self.scoped_name(STATE_BUCKET_NAME)
+    }
+
+    /// e.g. `CC_SNAPSHOTS` or `bank_CC_SNAPSHOTS`
+    pub fn snapshot_bucket_name(&self) -> String {
+        self.scoped_name(SNAPSHOT_BUCKET_NAME)
+    }
+
+    /// Prefixes a durable consumer name, e.g. `AGG_CMD_bankaccount` or `bank_AGG_CMD_bankaccount`
+    pub fn consumer_name(&self, name: &str) -> String {
+        self.scoped_name(name)
+    }
+
+    /// e.g. `cc.events` or `bank.cc.events`
+    pub fn event_subject_prefix(&self) -> String {
+        self.scoped_subject(EVENT_TOPIC_PREFIX)
+    }
+
+    /// e.g. `cc.commands` or `bank.cc.commands`
+    pub fn command_subject_prefix(&self) -> String {
+        self.scoped_subject(COMMAND_TOPIC_PREFIX)
+    }
+
+    /// e.g. `cc.dlq` or `bank.cc.dlq`
+    pub fn dead_letter_subject_prefix(&self) -> String {
+        self.scoped_subject(DEAD_LETTER_TOPIC_PREFIX)
+    }
+
+    /// e.g. `cc.admin` or `bank.cc.admin`
+    pub fn admin_subject_prefix(&self) -> String {
+        self.scoped_subject(ADMIN_TOPIC_PREFIX)
+    }
+
+    /// e.g. `cc.control` or `bank.cc.control`
+    pub fn control_subject_prefix(&self) -> String {
+        self.scoped_subject(CONTROL_TOPIC_PREFIX)
+    }
+
+    /// e.g. `cc.gateway` or `bank.cc.gateway`
+    pub fn gateway_subject_prefix(&self) -> String {
+        self.scoped_subject(GATEWAY_TOPIC_PREFIX)
+    }
+
+    /// The subject an event is published on, e.g. `cc.events.bankaccount.ACT123.amount_withdrawn`, or
+    /// `cc.events.amount_withdrawn` when using legacy subjects
+    pub fn event_subject(&self, stream: &str, key: &str, event_type: &str) -> String {
+        let prefix = self.event_subject_prefix();
+        if self.keyed_events {
+            format!(
+                "{prefix}.{}.{}.{}",
+                subject_token(stream),
+                subject_token(key),
+                subject_token(event_type)
+            )
+        } else {
+            format!("{prefix}.{}", subject_token(event_type))
+        }
+    }
+
+    /// A filter matching every event for a single entity, e.g. `cc.events.bankaccount.ACT123.*`. Only
+    /// meaningful with keyed subjects
+    pub fn entity_event_filter(&self, stream: &str, key: &str) -> String {
+        format!(
+            "{}.{}.{}.*",
+            self.event_subject_prefix(),
+            subject_token(stream),
+            subject_token(key)
+        )
+    }
+
+    /// A filter matching every event for an aggregate stream, e.g. `cc.events.bankaccount.>`, or `None` when
+    /// using legacy subjects since those don't include the stream
+    pub fn stream_event_filter(&self, stream: &str) -> Option<String> {
+        self.keyed_events.then(|| {
+            format!(
+                "{}.{}.>",
+                self.event_subject_prefix(),
+                subject_token(stream)
+            )
+        })
+    }
+
+    /// Filters matching a single event type, e.g. `cc.events.amount_withdrawn`. With keyed subjects this also
+    /// includes `cc.events.*.*.amount_withdrawn`
+    pub fn event_type_filters(&self, event_type: &str) -> Vec<String> {
+        let prefix = self.event_subject_prefix();
+        let event_type = subject_token(event_type);
+        let mut filters = vec![format!("{prefix}.{event_type}")];
+        if self.keyed_events {
+            filters.push(format!("{prefix}.*.*.{event_type}"));
+        }
+        filters
+    }
+
+    fn scoped_name(&self, name: &str) -> String {
+        match self.prefix {
+            Some(ref p) => format!("{p}_{name}"),
+            None => name.to_string(),
+        }
+    }
+
+    fn scoped_subject(&self, subject: &str) -> String {
+        match self.prefix {
+            Some(ref p) => format!("{p}.{subject}"),
+            None => subject.to_string(),
+        }
+    }
+}
+
+/// Encodes a value as a single subject token. Characters that aren't allowed within a token are percent encoded,
+/// along with `%` itself, so that distinct values never end up on the same subject. An empty value becomes `%`
+fn subject_token(value: &str) -> String {
+    if value.is_empty() {
+        return "%".to_string();
+    }
+    let mut token = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '.' | '*' | '>' | '%') || c.is_whitespace() {
+            let mut buf = [0; 4];
+            for byte in c.encode_utf8(&mut buf).bytes() {
+                token.push_str(&format!("%{byte:02X}"));
+            }
+        } else {
+            token.push(c);
+        }
+    }
+    token
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use super::Namespace;
+
+    #[test]
+    fn keyed_event_subjects() {
+        let ns = Namespace::new(Some("bank")).unwrap();
+        assert_eq!(
+            "bank.cc.events.amount_withdrawn",
+            ns.event_subject("bankaccount", "ACT123", "amount_withdrawn")
+        );
+        assert!(ns.stream_event_filter("bankaccount").is_none());
+        assert_eq!(
+            vec!["bank.cc.events.account_created"],
+            ns.event_type_filters("account_created")
+        );
+
+        let ns = ns.with_keyed_events(true);
+        assert_eq!(
+            "bank.cc.events.bankaccount.ACT123.amount_withdrawn",
+            ns.event_subject("bankaccount", "ACT123", "amount_withdrawn")
+        );
+        // Keys can't be allowed to add tokens or wildcards to the subject
+        assert_eq!(
+            "bank.cc.events.bankaccount.ACT%2E1%202.account_created",
+            ns.event_subject("bankaccount", "ACT.1 2", "account_created")
+        );
+        assert_eq!(
+            "bank.cc.events.bankaccount.%2A%2E%3E.account_created",
+            ns.event_subject("bankaccount", "*.>", "account_created")
+        );
+        assert_eq!(
+            "bank.cc.events.bankaccount.%.account_created",
+            ns.event_subject("bankaccount", "", "account_created")
+        );
+        assert_eq!(
+            "bank.cc.events.bankaccount.ACT123.*",
+            ns.entity_event_filter("bankaccount", "ACT123")
+        );
+        assert_eq!(
+            Some("bank.cc.events.bankaccount.>".to_string()),
+            ns.stream_event_filter("bankaccount")
+        );
+        assert_eq!(
+            vec![
+                "bank.cc.events.account_created",
+                "bank.cc.events.*.*.account_created"
+            ],
+            ns.event_type_filters("account_created")
+        );
+    }
+
+    #[test]
+    fn distinct_keys_get_distinct_subjects() {
+        let ns = Namespace::new(None).unwrap().with_keyed_events(true);
+        let keys = [
+            "ACT.1",
+            "ACT 1",
+            "ACT_1",
+            "ACT%2E1",
+            "ACT\u{a0}1",
+            " ACT_1",
+            "",
+            "%",
+            "_",
+        ];
+        let filters: HashSet<_> = keys
+            .iter()
+            .map(|key| ns.entity_event_filter("bankaccount", key))
+            .collect();
+        assert_eq!(keys.len(), filters.len());
+        let subjects: HashSet<_> = keys
+            .iter()
+            .map(|key| ns.event_subject("bankaccount", key, "account_created"))
+            .collect();
+        assert_eq!(keys.len(), subjects.len());
+        assert_eq!(
+            "cc.events.bankaccount.ACT%C2%A01.*",
+            ns.entity_event_filter("bankaccount", "ACT\u{a0}1")
+        );
+    }
+
+    #[test]
+    fn default_namespace_uses_plain_names() {
+        let ns = Namespace::new(None).unwrap();
+        assert_eq!("CC_EVENTS", ns.event_stream_name());
+        assert_eq!("CC_COMMANDS", ns.command_stream_name());
+        assert_eq!("CC_STATE", ns.state_bucket_name());
+        assert_eq!("CC_SNAPSHOTS", ns.snapshot_bucket_name());
+        assert_eq!("cc.events", ns.event_subject_prefix());
+        assert_eq!("cc.commands", ns.command_subject_prefix());
+        assert_eq!("CC_OUTBOUND", ns.outbound_stream_name());
+        assert_eq!("cc.admin", ns.admin_subject_prefix());
+        assert_eq!("cc.control", ns.control_subject_prefix());
+        assert_eq!("cc.gateway", ns.gateway_subject_prefix());
+        assert_eq!("CC_DLQ", ns.dead_letter_stream_name());
+        assert_eq!("cc.dlq", ns.dead_letter_subject_prefix());
+        assert_eq!("PROJ_bank", ns.consumer_name("PROJ_bank"));
+        assert_eq!(ns, Namespace::new(Some("  ")).unwrap());
+    }
+
+    #[test]
+    fn prefixed_namespace() {
+        let ns = Namespace::new(Some("bank")).unwrap();
+        assert_eq!("bank_CC_EVENTS", ns.event_stream_name());
+        assert_eq!("bank_CC_COMMANDS", ns.command_stream_name());
+        assert_eq!("bank_CC_STATE", ns.state_bucket_name());
+        assert_eq!("bank_CC_SNAPSHOTS", ns.snapshot_bucket_name());
+        assert_eq!("bank.cc.events", ns.event_subject_prefix());
+        assert_eq!("bank.cc.commands", ns.command_subject_prefix());
+        assert_eq!("bank_CC_OUTBOUND", ns.outbound_stream_name());
+        assert_eq!("bank.cc.admin", ns.admin_subject_prefix());
+        assert_eq!("bank.cc.control", ns.control_subject_prefix());
+        assert_eq!("bank.cc.gateway", ns.gateway_subject_prefix());
+        assert_eq!("bank_CC_DLQ", ns.dead_letter_stream_name());
+        assert_eq!("bank.cc.dlq", ns.dead_letter_subject_prefix());
+        assert_eq!("bank_PROJ_bank", ns.consumer_name("PROJ_bank"));
+    }
+
+    #[test]
+    fn rejects_invalid_namespace() {
+        assert!(Namespace::new(Some("bank.app")).is_err());
+        assert!(Namespace::new(Some("bank*")).is_err());
+    }
+}
@@ -1,75 +1,502 @@
+use std::time::Duration;
+
 use crate::{
-    natsclient::{
-        COMMANDS_STREAM_NAME, COMMANDS_STREAM_TOPIC, EVENTS_STREAM_TOPIC, EVENT_STREAM_NAME,
-    },
+    config::{EventSubjects, OutboundStreamSettings, StreamSettings},
+    natsclient::Namespace,
     Result,
 };
-use async_nats::jetstream::stream::{Config as StreamConfig, Stream};
-use tracing::{debug, instrument};
+use async_nats::jetstream::stream::{Config as StreamConfig, Source, Stream};
+use tracing::{debug, instrument, warn};
 use wasmbus_rpc::error::RpcError;
 
 pub(crate) struct NatsClient {
     context: async_nats::jetstream::Context,
+    namespace: Namespace,
+    event_settings: StreamSettings,
+    command_settings: StreamSettings,
+    event_sources: Vec<Source>,
+    event_mirror: Option<Source>,
+    outbound: Option<OutboundStreamSettings>,
+    event_subjects: EventSubjects,
 }
 
 impl NatsClient {
-    pub fn new(js: async_nats::jetstream::Context) -> NatsClient {
-        NatsClient { context: js }
+    pub fn new(js: async_nats::jetstream::Context, namespace: Namespace) -> NatsClient {
+        NatsClient {
+            context: js,
+            namespace,
+            event_settings: StreamSettings::default(),
+            command_settings: StreamSettings::default(),
+            event_sources: Vec::new(),
+            event_mirror: None,
+            outbound: None,
+            event_subjects: EventSubjects::default(),
+        }
+    }
+
+    /// Overrides the settings used when creating the event and command streams
+    pub fn with_stream_settings(self, events: StreamSettings, commands: StreamSettings) -> Self {
+        NatsClient {
+            event_settings: events,
+            command_settings: commands,
+            ..self
+        }
+    }
+
+    /// Sets the upstream sources or mirror of the event stream, along with the optional outbound stream, so that
+    /// leaf nodes and the streams aggregating their events can be created by the provider
+    pub fn with_topology(
+        self,
+        event_sources: Vec<Source>,
+        event_mirror: Option<Source>,
+        outbound: Option<OutboundStreamSettings>,
+    ) -> Self {
+        NatsClient {
+            event_sources,
+            event_mirror,
+            outbound,
+            ..self
+        }
+    }
+
+    /// Sets the subject scheme used for events
+    pub fn with_event_subjects(self, event_subjects: EventSubjects) -> Self {
+        NatsClient {
+            event_subjects,
+            ..self
+        }
+    }
+
+    /// Determines whether events should be published on keyed subjects. Event streams created before keyed
+    /// subjects only capture `cc.events.*`, so they keep using legacy subjects unless recreated
+    pub fn uses_keyed_subjects(&self, event_stream: &Stream) -> bool {
+        let supports_keyed = self.supports_keyed_subjects(event_stream);
+        match self.event_subjects {
+            EventSubjects::Legacy => false,
+            EventSubjects::Auto => supports_keyed,
+            EventSubjects::Keyed => {
+                if !supports_keyed {
+                    warn!("Keyed event subjects were requested but the event stream doesn't capture them. Falling back to legacy subjects");
+                }
+                supports_keyed
+            }
+        }
+    }
+
+    /// Compares the configuration of the given event and command streams against the requested settings,
+    /// returning a description of every difference found. Existing streams are never modified by the provider
+    pub fn config_drift(&self, event_stream: &Stream, command_stream: &Stream) -> Vec<String> {
+        let mut drift =
+            stream_config_drift(&event_stream.cached_info().config, &self.event_settings);
+        drift.extend(stream_config_drift(
+            &command_stream.cached_info().config,
+            &self.command_settings,
+        ));
+        drift.extend(topology_drift(
+            &event_stream.cached_info().config,
+            &self.event_sources,
+            self.event_mirror.as_ref(),
+        ));
+        if self.event_subjects == EventSubjects::Keyed
+            && !self.supports_keyed_subjects(event_stream)
+        {
+            drift.push(format!(
+                "{}: keyed event subjects requested but stream has subjects {:?}",
+                event_stream.cached_info().config.name,
+                event_stream.cached_info().config.subjects
+            ));
+        }
+        drift
     }
 
     #[instrument(level = "debug", skip(self))]
     pub async fn ensure_streams(&self) -> Result<(Stream, Stream)> {
+        let event_stream_name = self.namespace.event_stream_name();
+        let command_stream_name = self.namespace.command_stream_name();
         let event_stream = self
             .context
-            .get_or_create_stream(StreamConfig {
-                name: EVENT_STREAM_NAME.to_string(),
-                description: Some(
-                    "Concordance event stream for event sourcing capability provider".to_string(),
-                ),
-                num_replicas: 1,
-                retention: async_nats::jetstream::stream::RetentionPolicy::Limits, // does not delete upon ack, overlapping interest consumers ARE allowed
-                subjects: vec![EVENTS_STREAM_TOPIC.to_owned()],
-                storage: async_nats::jetstream::stream::StorageType::File,
-                allow_rollup: false,
-                ..Default::default()
-            })
+            .get_or_create_stream(self.event_stream_config()?)
             .await
             .map_err(|e| RpcError::Nats(format!("{e:?}")))?;
 
         let command_stream = self
+            .context
+            .get_or_create_stream(apply_settings(
+                StreamConfig {
+                    name: command_stream_name.clone(),
+                    description: Some(
+                        "Concordance command stream for event sourcing capability provider"
+                            .to_string(),
+                    ),
+                    retention: async_nats::jetstream::stream::RetentionPolicy::WorkQueue, // delete upon ack, overlapping interest consumers ARE NOT allowed
+                    subjects: vec![format!("{}.*", self.namespace.command_subject_prefix())],
+                    allow_rollup: false,
+                    ..Default::default()
+                },
+                &self.command_settings,
+            ))
+            .await
+            .map_err(|e| RpcError::Nats(format!("{e:?}")))?;
+
+        debug!("Detected or created both {event_stream_name} and {command_stream_name}");
+
+        if let Some(config) = self.outbound_stream_config() {
+            let outbound_name = config.name.clone();
+            self.context
+                .get_or_create_stream(config)
+                .await
+                .map_err(|e| RpcError::Nats(format!("{e:?}")))?;
+            debug!("Detected or created outbound stream {outbound_name}");
+        }
+
+        Ok((event_stream, command_stream))
+    }
+
+    /// Creates the dead letter stream if it doesn't exist. It is replicated like the command stream but otherwise
+    /// keeps dead letters until they are replayed or discarded
+    #[instrument(level = "debug", skip(self))]
+    pub async fn ensure_dead_letter_stream(&self) -> Result<Stream> {
+        let stream = self
             .context
             .get_or_create_stream(StreamConfig {
-                name: COMMANDS_STREAM_NAME.to_string(),
+                name: self.namespace.dead_letter_stream_name(),
                 description: Some(
-                    "Concordance command stream for event sourcing capability provider".to_string(),
+                    "Concordance dead letter stream for commands and events that exhausted their deliveries"
+                        .to_string(),
                 ),
-                num_replicas: 1,
-                retention: async_nats::jetstream::stream::RetentionPolicy::WorkQueue, // delete upon ack, overlapping interest consumers ARE NOT allowed
-                subjects: vec![COMMANDS_STREAM_TOPIC.to_owned()],
-                storage: async_nats::jetstream::stream::StorageType::File,
-                allow_rollup: false,
+                retention: async_nats::jetstream::stream::RetentionPolicy::Limits,
+                subjects: vec![format!("{}.>", self.namespace.dead_letter_subject_prefix())],
+                num_replicas: self.command_settings.replicas,
+                // Dead letters are read back individually
+                allow_direct: true,
                 ..Default::default()
             })
             .await
             .map_err(|e| RpcError::Nats(format!("{e:?}")))?;
+        debug!(
+            "Detected or created dead letter stream {}",
+            self.namespace.dead_letter_stream_name()
+        );
+        Ok(stream)
+    }
 
-        debug!("Detected or created both CC_EVENTS and CC_COMMANDS");
+    fn supports_keyed_subjects(&self, event_stream: &Stream) -> bool {
+        let config = &event_stream.cached_info().config;
+        // A mirror or sourcing-only stream is read-only for this provider, so the scheme only affects filters
+        config.subjects.is_empty()
+            || config
+                .subjects
+                .contains(&format!("{}.>", self.namespace.event_subject_prefix()))
+    }
 
-        Ok((event_stream, command_stream))
+    fn event_stream_config(&self) -> Result<StreamConfig> {
+        if self.event_mirror.is_some() && !self.event_sources.is_empty() {
+            return Err(RpcError::InvalidParameter(
+                "The event stream can either mirror a single stream or have sources, not both"
+                    .to_string(),
+            ));
+        }
+        // A mirror can't have subjects of its own, its contents come entirely from the mirrored stream
+        let subjects = if self.event_mirror.is_some() {
+            Vec::new()
+        } else if self.event_subjects == EventSubjects::Legacy {
+            vec![format!("{}.*", self.namespace.event_subject_prefix())]
+        } else {
+            // Also captures legacy subjects, so events from older publishers still land in the stream
+            vec![format!("{}.>", self.namespace.event_subject_prefix())]
+        };
+        Ok(apply_settings(
+            StreamConfig {
+                name: self.namespace.event_stream_name(),
+                description: Some(
+                    "Concordance event stream for event sourcing capability provider".to_string(),
+                ),
+                retention: async_nats::jetstream::stream::RetentionPolicy::Limits, // does not delete upon ack, overlapping interest consumers ARE allowed
+                subjects,
+                allow_rollup: false,
+                mirror: self.event_mirror.clone(),
+                sources: (!self.event_sources.is_empty()).then(|| self.event_sources.clone()),
+                ..Default::default()
+            },
+            &self.event_settings,
+        ))
+    }
+
+    /// The outbound stream sources the forwarded events from the event stream. Forwarding only some event types
+    /// takes a source per subject filter, and sourcing a stream more than once requires NATS server 2.10
+    fn outbound_stream_config(&self) -> Option<StreamConfig> {
+        self.outbound.as_ref().map(|outbound| {
+            let event_stream = Source {
+                name: self.namespace.event_stream_name(),
+                ..Default::default()
+            };
+            // Whichever subject scheme the event stream uses, the events are matched by both
+            let keyed = self.namespace.clone().with_keyed_events(true);
+            let sources = if outbound.event_types.is_empty() {
+                vec![event_stream]
+            } else {
+                outbound
+                    .event_types
+                    .iter()
+                    .flat_map(|event_type| keyed.event_type_filters(event_type))
+                    .map(|filter_subject| Source {
+                        filter_subject: Some(filter_subject),
+                        ..event_stream.clone()
+                    })
+                    .collect()
+            };
+            apply_settings(
+                StreamConfig {
+                    name: outbound
+                        .name
+                        .clone()
+                        .unwrap_or_else(|| self.namespace.outbound_stream_name()),
+                    description: Some(
+                        "Concordance outbound stream for events forwarded upstream".to_string(),
+                    ),
+                    retention: async_nats::jetstream::stream::RetentionPolicy::Limits,
+                    sources: Some(sources),
+                    ..Default::default()
+                },
+                &outbound.settings,
+            )
+        })
     }
 }
 
+fn apply_settings(config: StreamConfig, settings: &StreamSettings) -> StreamConfig {
+    StreamConfig {
+        num_replicas: settings.replicas,
+        storage: settings.storage,
+        max_age: Duration::from_secs(settings.max_age_secs),
+        max_bytes: settings.max_bytes,
+        duplicate_window: settings
+            .duplicate_window_secs
+            .map(Duration::from_secs)
+            .unwrap_or_default(),
+        discard: settings.discard,
+        ..config
+    }
+}
+
+fn stream_config_drift(actual: &StreamConfig, settings: &StreamSettings) -> Vec<String> {
+    let name = &actual.name;
+    let mut drift = Vec::new();
+    if actual.num_replicas.max(1) != settings.replicas.max(1) {
+        drift.push(format!(
+            "{name}: replicas requested {} but stream has {}",
+            settings.replicas, actual.num_replicas
+        ));
+    }
+    if actual.storage != settings.storage {
+        drift.push(format!(
+            "{name}: storage requested {:?} but stream has {:?}",
+            settings.storage, actual.storage
+        ));
+    }
+    if actual.max_age != Duration::from_secs(settings.max_age_secs) {
+        drift.push(format!(
+            "{name}: max age requested {}s but stream has {}s",
+            settings.max_age_secs,
+            actual.max_age.as_secs()
+        ));
+    }
+    // The server reports unlimited as -1 regardless of how it was requested
+    if actual.max_bytes.max(-1) != settings.max_bytes.max(-1)
+        && !(actual.max_bytes <= 0 && settings.max_bytes <= 0)
+    {
+        drift.push(format!(
+            "{name}: max bytes requested {} but stream has {}",
+            settings.max_bytes, actual.max_bytes
+        ));
+    }
+    if let Some(window) = settings.duplicate_window_secs {
+        if actual.duplicate_window != Duration::from_secs(window) {
+            drift.push(format!(
+                "{name}: duplicate window requested {window}s but stream has {}s",
+                actual.duplicate_window.as_secs()
+            ));
+        }
+    }
+    if actual.discard != settings.discard {
+        drift.push(format!(
+            "{name}: discard policy requested {:?} but stream has {:?}",
+            settings.discard, actual.discard
+        ));
+    }
+    drift
+}
+
+fn topology_drift(
+    actual: &StreamConfig,
+    sources: &[Source],
+    mirror: Option<&Source>,
+) -> Vec<String> {
+    let name = &actual.name;
+    let mut drift = Vec::new();
+    let mut requested: Vec<&str> = sources.iter().map(|s| s.name.as_str()).collect();
+    let mut existing: Vec<&str> = actual
+        .sources
+        .iter()
+        .flatten()
+        .map(|s| s.name.as_str())
+        .collect();
+    requested.sort_unstable();
+    existing.sort_unstable();
+    if requested != existing {
+        drift.push(format!(
+            "{name}: sources requested {requested:?} but stream has {existing:?}"
+        ));
+    }
+    let requested_mirror = mirror.map(|m| m.name.as_str());
+    let existing_mirror = actual.mirror.as_ref().map(|m| m.name.as_str());
+    if requested_mirror != existing_mirror {
+        drift.push(format!(
+            "{name}: mirror requested {requested_mirror:?} but stream has {existing_mirror:?}"
+        ));
+    }
+    drift
+}
+
 #[cfg(test)]
 mod test {
-    use crate::natsclient::{COMMANDS_STREAM_NAME, EVENT_STREAM_NAME};
+    use std::time::Duration;
+
+    use async_nats::jetstream::stream::{Config as StreamConfig, DiscardPolicy, Source};
 
-    use super::NatsClient;
+    use crate::{
+        config::{EventSubjects, OutboundStreamSettings, StreamSettings},
+        natsclient::{Namespace, COMMANDS_STREAM_NAME, EVENT_STREAM_NAME},
+    };
+
+    use super::{apply_settings, stream_config_drift, topology_drift, NatsClient};
+
+    #[test]
+    fn detects_stream_config_drift() {
+        let settings = StreamSettings {
+            replicas: 3,
+            max_age_secs: 3600,
+            ..Default::default()
+        };
+        let requested = apply_settings(
+            StreamConfig {
+                name: "CC_EVENTS".to_string(),
+                ..Default::default()
+            },
+            &settings,
+        );
+        assert!(stream_config_drift(&requested, &settings).is_empty());
+
+        let existing = StreamConfig {
+            name: "CC_EVENTS".to_string(),
+            num_replicas: 1,
+            max_bytes: -1,
+            duplicate_window: Duration::from_secs(120),
+            discard: DiscardPolicy::New,
+            ..Default::default()
+        };
+        let drift = stream_config_drift(&existing, &settings);
+        assert_eq!(3, drift.len());
+        assert_eq!("CC_EVENTS: replicas requested 3 but stream has 1", drift[0]);
+        assert!(drift[1].starts_with("CC_EVENTS: max age"));
+        assert!(drift[2].starts_with("CC_EVENTS: discard policy"));
+    }
+
+    #[tokio::test]
+    async fn builds_ddil_stream_topology() {
+        let nc = async_nats::connect("127.0.0.1").await.unwrap();
+        let js = async_nats::jetstream::new(nc);
+        let rover = |name: &str| Source {
+            name: name.to_string(),
+            ..Default::default()
+        };
+
+        let mothership = NatsClient::new(js.clone(), Namespace::default()).with_topology(
+            vec![rover("ROVER1_OUTBOUND"), rover("ROVER2_OUTBOUND")],
+            None,
+            None,
+        );
+        let config = mothership.event_stream_config().unwrap();
+        assert_eq!(vec!["cc.events.>"], config.subjects);
+        assert_eq!(2, config.sources.as_ref().unwrap().len());
+        assert!(mothership.outbound_stream_config().is_none());
+        assert!(topology_drift(&config, &mothership.event_sources, None).is_empty());
+        assert_eq!(
+            vec![
+                r#"CC_EVENTS: sources requested ["ROVER1_OUTBOUND", "ROVER2_OUTBOUND"] but stream has []"#
+            ],
+            topology_drift(
+                &StreamConfig {
+                    name: "CC_EVENTS".to_string(),
+                    ..Default::default()
+                },
+                &mothership.event_sources,
+                None
+            )
+        );
+
+        let rover1 = NatsClient::new(js.clone(), Namespace::new(Some("rover1")).unwrap())
+            .with_topology(Vec::new(), None, Some(OutboundStreamSettings::default()));
+        let outbound = rover1.outbound_stream_config().unwrap();
+        assert_eq!("rover1_CC_OUTBOUND", outbound.name);
+        assert!(outbound.subjects.is_empty());
+        let sources = outbound.sources.unwrap();
+        assert_eq!(1, sources.len());
+        assert_eq!("rover1_CC_EVENTS", sources[0].name);
+        assert!(sources[0].filter_subject.is_none());
+
+        let rover2 = NatsClient::new(js.clone(), Namespace::default()).with_topology(
+            Vec::new(),
+            None,
+            Some(OutboundStreamSettings {
+                event_types: vec!["rover_landed".to_string()],
+                ..Default::default()
+            }),
+        );
+        let filters: Vec<_> = rover2
+            .outbound_stream_config()
+            .unwrap()
+            .sources
+            .unwrap()
+            .into_iter()
+            .map(|s| (s.name, s.filter_subject.unwrap()))
+            .collect();
+        assert_eq!(
+            vec![
+                (
+                    "CC_EVENTS".to_string(),
+                    "cc.events.rover_landed".to_string()
+                ),
+                (
+                    "CC_EVENTS".to_string(),
+                    "cc.events.*.*.rover_landed".to_string()
+                )
+            ],
+            filters
+        );
+
+        let replica = NatsClient::new(js.clone(), Namespace::default()).with_topology(
+            Vec::new(),
+            Some(rover("MOTHERSHIP_EVENTS")),
+            None,
+        );
+        let config = replica.event_stream_config().unwrap();
+        assert!(config.subjects.is_empty());
+        assert_eq!("MOTHERSHIP_EVENTS", config.mirror.unwrap().name);
+
+        let invalid = NatsClient::new(js, Namespace::default()).with_topology(
+            vec![rover("ROVER1_OUTBOUND")],
+            Some(rover("MOTHERSHIP_EVENTS")),
+            None,
+        );
+        assert!(invalid.event_stream_config().is_err());
+    }
 
     #[tokio::test]
     async fn test_ensure_streams() {
         let nc = async_nats::connect("127.0.0.1").await.unwrap();
         let js = async_nats::jetstream::new(nc.clone());
-        let nc = NatsClient::new(js.clone());
+        let nc = NatsClient::new(js.clone(), Namespace::default());
 
         let (a, b) = nc.ensure_streams().await.unwrap();
         let (c, d) = nc.ensure_streams().await.unwrap(); // idempotency check
@@ -82,4 +509,48 @@ mod test {
 
         assert!(true);
     }
+
+    #[tokio::test]
+    async fn existing_streams_keep_legacy_subjects() {
+        let nc = async_nats::connect("127.0.0.1").await.unwrap();
+        let js = async_nats::jetstream::new(nc);
+        let ns = Namespace::new(Some("legacyapp")).unwrap();
+        // An event stream as created before keyed subjects existed
+        js.get_or_create_stream(StreamConfig {
+            name: "legacyapp_CC_EVENTS".to_string(),
+            subjects: vec!["legacyapp.cc.events.*".to_string()],
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+        let client = NatsClient::new(js.clone(), ns.clone());
+        let (e, c) = client.ensure_streams().await.unwrap();
+        assert!(!client.uses_keyed_subjects(&e));
+        assert!(client.config_drift(&e, &c).is_empty());
+
+        let client = client.with_event_subjects(EventSubjects::Keyed);
+        assert!(!client.uses_keyed_subjects(&e));
+        assert_eq!(1, client.config_drift(&e, &c).len());
+
+        js.delete_stream("legacyapp_CC_EVENTS").await.unwrap();
+        js.delete_stream("legacyapp_CC_COMMANDS").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ensure_namespaced_streams() {
+        let nc = async_nats::connect("127.0.0.1").await.unwrap();
+        let js = async_nats::jetstream::new(nc);
+        let ns = Namespace::new(Some("testapp")).unwrap();
+        let client = NatsClient::new(js.clone(), ns);
+
+        let (e, c) = client.ensure_streams().await.unwrap();
+        assert_eq!(e.cached_info().config.name, "testapp_CC_EVENTS");
+        assert_eq!(e.cached_info().config.subjects, vec!["testapp.cc.events.>"]);
+        assert!(client.uses_keyed_subjects(&e));
+        assert_eq!(c.cached_info().config.name, "testapp_CC_COMMANDS");
+
+        js.delete_stream("testapp_CC_EVENTS").await.unwrap();
+        js.delete_stream("testapp_CC_COMMANDS").await.unwrap();
+    }
 }
@@ -0,0 +1,97 @@
+//! # Trace Context
+//! Propagates W3C trace context through the headers of the commands and events the provider publishes, so that a
+//! command, the events it produces, and the commands process managers issue in response all appear in one trace.
+//! Each message is handled in a span whose parent is the span that published it. Calls to actors made while
+//! handling it are linked by `wasmbus-rpc`, which adds the current span's context to every RPC request
+
+use async_nats::{HeaderMap, Message};
+use wasmbus_rpc::{
+    otel::{attach_span_context, OtelHeaderInjector},
+    provider::prelude::Context,
+};
+
+pub(crate) const TRACEPARENT_HEADER: &str = "traceparent";
+
+/// Adds the current span's trace context to the headers of a message about to be published
+pub(crate) fn inject_trace_context(headers: HeaderMap) -> HeaderMap {
+    OtelHeaderInjector::new_with_span(headers).into()
+}
+
+/// Makes the current span a child of the span that published the message. Messages published without a trace
+/// context, such as commands sent by clients that don't trace, leave the current span as it is
+pub(crate) fn continue_trace(msg: &Message) {
+    let traced = msg
+        .headers
+        .as_ref()
+        .map_or(false, |headers| headers.get(TRACEPARENT_HEADER).is_some());
+    if traced {
+        attach_span_context(msg);
+    }
+}
+
+/// The context for a call to an actor, carrying the current span's trace context
+pub(crate) fn rpc_context() -> Context {
+    let headers = inject_trace_context(HeaderMap::new());
+    Context {
+        span: headers
+            .get(TRACEPARENT_HEADER)
+            .map(|value| value.to_string()),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use async_nats::{HeaderMap, Message};
+    use opentelemetry::{sdk::trace::TracerProvider, trace::TracerProvider as _};
+    use tracing_subscriber::prelude::*;
+
+    use super::{continue_trace, inject_trace_context, rpc_context, TRACEPARENT_HEADER};
+
+    /// The trace id of a `traceparent` header, e.g. `00-{trace id}-{span id}-01`
+    fn trace_id(traceparent: &str) -> &str {
+        traceparent.split('-').nth(1).unwrap()
+    }
+
+    #[test]
+    fn trace_continues_across_published_messages() {
+        // The tracer only holds a weak reference to its provider
+        let provider = TracerProvider::builder().build();
+        let tracer = provider.tracer("concordance");
+        let subscriber =
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let headers =
+                tracing::info_span!("publish").in_scope(|| inject_trace_context(HeaderMap::new()));
+            let published = headers.get(TRACEPARENT_HEADER).unwrap().to_string();
+            let msg = Message {
+                subject: "cc.commands.bankaccount".to_string(),
+                reply: None,
+                payload: "{}".into(),
+                headers: Some(headers),
+                status: None,
+                description: None,
+                length: 0,
+            };
+
+            let handled = tracing::info_span!("handle").in_scope(|| {
+                continue_trace(&msg);
+                rpc_context().span.unwrap()
+            });
+            assert_eq!(trace_id(&published), trace_id(&handled));
+            assert_ne!(published, handled);
+
+            // Without a trace context in the message, handling it starts a new trace
+            let untraced = Message {
+                headers: None,
+                ..msg
+            };
+            let handled = tracing::info_span!("handle").in_scope(|| {
+                continue_trace(&untraced);
+                rpc_context().span.unwrap()
+            });
+            assert_ne!(trace_id(&published), trace_id(&handled));
+        });
+    }
+}
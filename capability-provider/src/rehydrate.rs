@@ -0,0 +1,429 @@
+//! # Rehydration
+//! Rebuilds aggregate state by replaying the aggregate's events from the event stream. This is used when state
+//! is missing from the state bucket (e.g. the bucket was lost or recreated) for an entity that has events.
+//! Replays start from an entity's latest snapshot, when there is one, rather than from its first event
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use async_nats::jetstream::{
+    consumer::{
+        pull::{Config as PullConfig, Stream as MessageStream},
+        AckPolicy, DeliverPolicy, PullConsumer,
+    },
+    stream::Stream as JsStream,
+    Context,
+};
+use cloudevents::Event as CloudEvent;
+use futures::StreamExt;
+use serde::Serialize;
+use tracing::{debug, instrument, trace, warn};
+use wasmbus_rpc::error::RpcError;
+
+use crate::{
+    config::InterestDeclaration,
+    events::recorded_event,
+    eventsourcing::{
+        AggregateService, AggregateServiceSender, Event as ConcordanceEvent, EventWithState,
+    },
+    metrics::metrics,
+    natsclient::Namespace,
+    otel::rpc_context,
+    state::{ConditionalWrite, EntityState, EventPosition},
+    Result,
+};
+
+/// How long to wait for the next event before giving up on a replay
+const REPLAY_IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Replay consumers are ephemeral, so the server cleans them up if a replay is abandoned part way through
+const REPLAY_INACTIVE_THRESHOLD: Duration = Duration::from_secs(30);
+
+/// The outcome of rebuilding the state of every entity of an aggregate
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub(crate) struct RebuildSummary {
+    /// Number of entities whose state was written
+    pub written: usize,
+    /// Number of entities whose state was removed because their last event removed it
+    pub removed: usize,
+    /// Number of entities whose state was changed by someone else during the rebuild, and so wasn't replaced
+    pub conflicts: usize,
+    /// Number of entities whose replay started from a snapshot
+    pub snapshots: usize,
+    /// Number of events applied
+    pub events: u64,
+}
+
+/// State rebuilt for a single entity along with the last event applied to it
+#[derive(Debug, Default)]
+struct RebuiltState {
+    state: Option<Vec<u8>>,
+    position: Option<EventPosition>,
+}
+
+/// Reads every event matching a filter, from the start of the event stream (or a given sequence) up to the last
+/// matching event at the time the replay started, through an ephemeral consumer
+pub(crate) struct EventReplay {
+    stream: JsStream,
+    consumer_name: String,
+    messages: MessageStream,
+    remaining: u64,
+}
+
+impl EventReplay {
+    /// Starts a replay of the events matching the filter, or of the entire event stream when there is no filter
+    pub(crate) async fn start(
+        js: &Context,
+        namespace: &Namespace,
+        filter: Option<String>,
+        start_sequence: Option<u64>,
+    ) -> Result<EventReplay> {
+        let stream = js
+            .get_stream(namespace.event_stream_name())
+            .await
+            .map_err(|e| RpcError::Nats(format!("Failed to get event stream for replay: {e}")))?;
+        let consumer: PullConsumer = stream
+            .create_consumer(PullConfig {
+                filter_subject: filter.unwrap_or_default(),
+                deliver_policy: start_sequence.map_or(DeliverPolicy::All, |start_sequence| {
+                    DeliverPolicy::ByStartSequence { start_sequence }
+                }),
+                ack_policy: AckPolicy::None,
+                inactive_threshold: REPLAY_INACTIVE_THRESHOLD,
+                memory_storage: true,
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| RpcError::Nats(format!("Failed to create replay consumer: {e}")))?;
+        let info = consumer.cached_info();
+        let consumer_name = info.name.clone();
+        let remaining = info.num_pending;
+        let messages = consumer
+            .messages()
+            .await
+            .map_err(|e| RpcError::Nats(format!("Failed to pull events for replay: {e}")))?;
+        Ok(EventReplay {
+            stream,
+            consumer_name,
+            messages,
+            remaining,
+        })
+    }
+
+    /// Returns the next event along with its stream sequence, or `None` once every event that existed when the
+    /// replay started has been read. Messages that aren't cloud events are skipped
+    pub(crate) async fn next(&mut self) -> Result<Option<(u64, CloudEvent)>> {
+        while self.remaining > 0 {
+            let msg = match tokio::time::timeout(REPLAY_IDLE_TIMEOUT, self.messages.next()).await {
+                Ok(Some(Ok(msg))) => msg,
+                Ok(Some(Err(e))) => {
+                    return Err(RpcError::Nats(format!(
+                        "Failed to read event for replay: {e}"
+                    )))
+                }
+                // Missing events would silently produce the wrong state, so stopping short is an error
+                Ok(None) | Err(_) => {
+                    return Err(RpcError::Nats(format!(
+                        "Event replay stopped with {} events left to read",
+                        self.remaining
+                    )))
+                }
+            };
+            self.remaining -= 1;
+            let sequence = msg
+                .info()
+                .map_err(|e| RpcError::Nats(format!("Replayed event has no metadata: {e}")))?
+                .stream_sequence;
+            match serde_json::from_slice::<CloudEvent>(&msg.payload) {
+                Ok(event) => return Ok(Some((sequence, event))),
+                Err(e) => warn!(
+                    subject = %msg.subject,
+                    "Skipping message that isn't a cloud event during replay: {e}"
+                ),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Deletes the replay consumer rather than waiting for the server to clean it up
+    pub(crate) async fn finish(self) {
+        if let Err(e) = self.stream.delete_consumer(&self.consumer_name).await {
+            debug!(
+                consumer = self.consumer_name,
+                "Failed to delete replay consumer, the server will remove it once inactive: {e}"
+            );
+        }
+    }
+}
+
+/// Rebuilds the state of a single entity by replaying its events through the aggregate, starting from its latest
+/// snapshot if it has one. Returns `None` if the entity has no events or its last event removed its state. Only
+/// events on keyed subjects can be read for a single entity, so this always returns `None` when using legacy
+/// subjects
+#[instrument(level = "debug", skip(js, state, interest), fields(entity_name = interest.entity_name))]
+pub(crate) async fn rehydrate_entity(
+    js: &Context,
+    state: &EntityState,
+    interest: &InterestDeclaration,
+    key: &str,
+) -> Result<Option<Vec<u8>>> {
+    if !interest.namespace.keyed_events() {
+        return Ok(None);
+    }
+    let snapshot = state.fetch_snapshot(&interest.entity_name, key).await?;
+    let start_sequence = snapshot.as_ref().map(|s| s.position.sequence + 1);
+    let filter = interest
+        .namespace
+        .entity_event_filter(&interest.entity_name, key);
+    let mut replay =
+        EventReplay::start(js, &interest.namespace, Some(filter), start_sequence).await?;
+
+    let target = AggregateServiceSender::for_actor(&interest.link_definition);
+    let result = async {
+        let mut state = snapshot.map(|s| s.state);
+        let mut count = 0;
+        while let Some((sequence, event)) = replay.next().await? {
+            let ce = recorded_event(event, Some(sequence));
+            // The subject only says which entity the event was published for, not that its payload agrees
+            if !is_entity_event(interest, &ce, key) {
+                continue;
+            }
+            state = apply_event(&target, interest, ce, state).await?;
+            count += 1;
+        }
+        Ok::<_, RpcError>((state, count))
+    }
+    .await;
+    replay.finish().await;
+    let (state, count) = result?;
+
+    debug!(
+        key,
+        events = count,
+        from_snapshot = start_sequence.is_some(),
+        "Rehydrated aggregate state from event log"
+    );
+    Ok(state)
+}
+
+/// Rebuilds the state of every entity of an aggregate by replaying all of the aggregate's events, then
+/// replaces each entity's state in the bucket. Events covered by an entity's latest snapshot are skipped rather
+/// than applied. Events published while the rebuild runs are applied by the aggregate's event consumer as usual,
+/// so the rebuild should be run while the aggregate isn't receiving commands
+#[instrument(level = "info", skip_all, fields(entity_name = interest.entity_name))]
+pub(crate) async fn rebuild_aggregate(
+    js: &Context,
+    state: &EntityState,
+    interest: &InterestDeclaration,
+) -> Result<RebuildSummary> {
+    let filter = interest
+        .namespace
+        .stream_event_filter(&interest.entity_name);
+    let mut replay = EventReplay::start(js, &interest.namespace, filter, None).await?;
+
+    let target = AggregateServiceSender::for_actor(&interest.link_definition);
+    let mut summary = RebuildSummary::default();
+    let result = async {
+        let mut states: HashMap<String, RebuiltState> = HashMap::new();
+        while let Some((sequence, event)) = replay.next().await? {
+            let ce = recorded_event(event, Some(sequence));
+            if !interest.is_interested_in_event(&ce) {
+                continue;
+            }
+            let payload: serde_json::Value =
+                serde_json::from_slice(&ce.payload).unwrap_or_default();
+            let key = interest.extract_key_value_from_payload(&payload);
+            if key.is_empty() {
+                warn!(
+                    event_type = ce.event_type,
+                    "Skipping event without key field {} during rebuild", interest.key_field
+                );
+                continue;
+            }
+            let mut current = match states.remove(&key) {
+                Some(current) => current,
+                None => match state.fetch_snapshot(&interest.entity_name, &key).await? {
+                    Some(snapshot) => {
+                        summary.snapshots += 1;
+                        RebuiltState {
+                            state: Some(snapshot.state),
+                            position: Some(snapshot.position),
+                        }
+                    }
+                    None => RebuiltState::default(),
+                },
+            };
+            if current.position.map_or(true, |p| sequence > p.sequence) {
+                current.state = apply_event(&target, interest, ce, current.state).await?;
+                current.position = Some(EventPosition::next(current.position, sequence));
+                summary.events += 1;
+            }
+            states.insert(key, current);
+        }
+        Ok::<_, RpcError>(states)
+    }
+    .await;
+    replay.finish().await;
+    let states = result?;
+
+    for (key, rebuilt) in states {
+        let current = state
+            .fetch_versioned_state(&interest.role, &interest.entity_name, &key)
+            .await?;
+        let removed = rebuilt.state.is_none();
+        let written = match rebuilt.state {
+            Some(s) => {
+                state
+                    .write_state_at_revision(
+                        &interest.role,
+                        &interest.entity_name,
+                        &key,
+                        s,
+                        current.revision,
+                        rebuilt.position,
+                    )
+                    .await?
+            }
+            None => {
+                state
+                    .remove_state_at_revision(
+                        &interest.role,
+                        &interest.entity_name,
+                        &key,
+                        current.revision,
+                        rebuilt.position,
+                    )
+                    .await?
+            }
+        };
+        match written {
+            ConditionalWrite::Written(_) if removed => summary.removed += 1,
+            ConditionalWrite::Written(_) => summary.written += 1,
+            ConditionalWrite::Conflict => {
+                warn!(key, "State changed during rebuild and was not replaced");
+                summary.conflicts += 1;
+            }
+        }
+    }
+    debug!(?summary, "Rebuilt aggregate state from event log");
+    Ok(summary)
+}
+
+fn is_entity_event(interest: &InterestDeclaration, event: &ConcordanceEvent, key: &str) -> bool {
+    let payload: serde_json::Value = serde_json::from_slice(&event.payload).unwrap_or_default();
+    interest.is_interested_in_event(event)
+        && interest.extract_key_value_from_payload(&payload) == key
+}
+
+/// Applies a single event to the given state, returning the aggregate's new state
+async fn apply_event(
+    target: &(impl AggregateService + Sync),
+    interest: &InterestDeclaration,
+    event: ConcordanceEvent,
+    state: Option<Vec<u8>>,
+) -> Result<Option<Vec<u8>>> {
+    trace!(event_type = event.event_type, "Replaying event");
+    let event_type = event.event_type.clone();
+    let ctx = rpc_context();
+    let ack = metrics()
+        .time_invocation(
+            "rehydrate",
+            &interest.entity_name,
+            target.apply_event(&ctx, &EventWithState { event, state }),
+        )
+        .await?;
+    if ack.succeeded {
+        Ok(ack.state)
+    } else {
+        Err(RpcError::Other(format!(
+            "Aggregate {} failed to apply event {event_type} during replay: {}",
+            interest.actor_id,
+            ack.error.unwrap_or_default()
+        )))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cloudevents::AttributesReader;
+
+    use super::EventReplay;
+    use crate::{
+        events::publish_es_event,
+        eventsourcing::Event as ConcordanceEvent,
+        natsclient::{
+            test::{clear_streams, create_js_context},
+            Namespace, NatsClient,
+        },
+        schemas::Schemas,
+    };
+
+    #[tokio::test]
+    async fn replays_only_the_entity_events_published_before_starting() {
+        let js = create_js_context().await;
+        clear_streams(js.clone()).await;
+
+        let ns = Namespace::default().with_keyed_events(true);
+        NatsClient::new(js.clone(), ns.clone())
+            .ensure_streams()
+            .await
+            .unwrap();
+
+        let event = |event_type: &str, key: &str| ConcordanceEvent {
+            event_type: event_type.to_string(),
+            stream: "order".to_string(),
+            payload: format!(r#"{{"order_id": "{key}"}}"#).into_bytes(),
+            metadata: None,
+        };
+        for (event_type, key) in [
+            ("order_created", "ORD1"),
+            ("order_created", "ORD2"),
+            ("item_added", "ORD1"),
+        ] {
+            publish_es_event(
+                &js,
+                &ns,
+                &Schemas::default(),
+                event(event_type, key),
+                key,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        }
+        // Not a cloud event, so it's skipped
+        js.publish(ns.event_subject("order", "ORD1", "garbage"), "nope".into())
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+
+        let mut replay = EventReplay::start(
+            &js,
+            &ns,
+            Some(ns.entity_event_filter("order", "ORD1")),
+            None,
+        )
+        .await
+        .unwrap();
+        // Published after the replay started, so it isn't included
+        publish_es_event(
+            &js,
+            &ns,
+            &Schemas::default(),
+            event("order_shipped", "ORD1"),
+            "ORD1",
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+
+        let mut types = Vec::new();
+        while let Some((_, event)) = replay.next().await.unwrap() {
+            types.push(event.ty().to_string());
+        }
+        replay.finish().await;
+        assert_eq!(vec!["order_created", "item_added"], types);
+    }
+}
@@ -0,0 +1,231 @@
+//! # Schemas
+//! Commands and events can optionally be validated against JSON schemas at the provider boundary, so that a
+//! command with the wrong shape is rejected before it reaches an aggregate and an aggregate can't publish an
+//! event its consumers won't be able to read. The schemas are the `schema.json` files of an eventcatalog site,
+//! loaded from a directory or a key value bucket when the provider starts. Commands and events without a schema
+//! aren't validated
+
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+use async_nats::jetstream::Context;
+use case::CaseExt;
+use futures::TryStreamExt;
+use jsonschema::JSONSchema;
+use serde_json::Value;
+use tracing::{debug, info};
+use wasmbus_rpc::error::RpcError;
+
+use crate::{
+    config::SchemaSettings, consumers::RawCommand, eventsourcing::Event as ConcordanceEvent, Result,
+};
+
+const SCHEMA_FILE_NAME: &str = "schema.json";
+
+/// Compiled schemas, keyed by the snake case name of the command or event type they apply to
+#[derive(Clone, Default)]
+pub struct Schemas(Arc<HashMap<String, JSONSchema>>);
+
+impl Schemas {
+    /// Loads the schemas from the configured catalog directory and bucket
+    pub(crate) async fn load(js: &Context, settings: &SchemaSettings) -> Result<Schemas> {
+        let mut documents = Vec::new();
+        if let Some(path) = &settings.catalog_path {
+            documents.extend(read_catalog(Path::new(path))?);
+        }
+        if let Some(bucket) = &settings.bucket {
+            documents.extend(read_bucket(js, bucket).await?);
+        }
+        let schemas = Schemas::compile(documents)?;
+        if !schemas.0.is_empty() {
+            info!(
+                count = schemas.0.len(),
+                "Loaded schemas for validating commands and events"
+            );
+        }
+        Ok(schemas)
+    }
+
+    /// Compiles schema documents, each given with the type it applies to (if known) and where it came from.
+    /// Documents without a type apply to the type named by their `title`
+    fn compile(documents: Vec<(Option<String>, String, Value)>) -> Result<Schemas> {
+        let mut schemas = HashMap::new();
+        for (type_name, origin, document) in documents {
+            let Some(type_name) = type_name.or_else(|| document["title"].as_str().map(String::from))
+            else {
+                return Err(RpcError::InvalidParameter(format!(
+                    "Schema {origin} has no title to identify the command or event it applies to"
+                )));
+            };
+            let schema = JSONSchema::compile(&document).map_err(|e| {
+                RpcError::InvalidParameter(format!("Schema {origin} is invalid: {e}"))
+            })?;
+            debug!(type_name, origin, "Compiled schema");
+            schemas.insert(type_name.to_snake(), schema);
+        }
+        Ok(Schemas(Arc::new(schemas)))
+    }
+
+    /// Validates the data of an inbound command against the schema for its type
+    pub(crate) fn validate_command(&self, command: &RawCommand) -> Result<()> {
+        self.validate("Command", &command.command_type, &command.data)
+    }
+
+    /// Validates the payload of an outbound event against the schema for its type
+    pub(crate) fn validate_event(&self, event: &ConcordanceEvent) -> Result<()> {
+        if !self.0.contains_key(&event.event_type.to_snake()) {
+            return Ok(());
+        }
+        let payload: Value = serde_json::from_slice(&event.payload).map_err(|e| {
+            RpcError::InvalidParameter(format!(
+                "Event {} does not have a JSON payload: {e}",
+                event.event_type
+            ))
+        })?;
+        self.validate("Event", &event.event_type, &payload)
+    }
+
+    fn validate(&self, kind: &str, type_name: &str, value: &Value) -> Result<()> {
+        let Some(schema) = self.0.get(&type_name.to_snake()) else {
+            return Ok(());
+        };
+        schema.validate(value).map_err(|errors| {
+            let errors = errors
+                .map(|e| {
+                    let path = e.instance_path.to_string();
+                    let path = if path.is_empty() { "/" } else { &path };
+                    format!("{path}: {e}")
+                })
+                .collect::<Vec<_>>();
+            RpcError::InvalidParameter(format!(
+                "{kind} {type_name} does not match its schema - {}",
+                errors.join("; ")
+            ))
+        })
+    }
+}
+
+/// Reads every schema file beneath an eventcatalog directory
+fn read_catalog(root: &Path) -> Result<Vec<(Option<String>, String, Value)>> {
+    let mut documents = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let entries = std::fs::read_dir(&dir).map_err(|e| {
+            RpcError::InvalidParameter(format!(
+                "Failed to read schema directory {}: {e}",
+                dir.display()
+            ))
+        })?;
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let path = entry.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if path
+                .file_name()
+                .map_or(false, |name| name == SCHEMA_FILE_NAME)
+            {
+                documents.push((None, path.display().to_string(), read_schema_file(&path)?));
+            }
+        }
+    }
+    Ok(documents)
+}
+
+fn read_schema_file(path: &Path) -> Result<Value> {
+    let contents = std::fs::read(path).map_err(|e| {
+        RpcError::InvalidParameter(format!("Failed to read schema {}: {e}", path.display()))
+    })?;
+    serde_json::from_slice(&contents)
+        .map_err(|e| RpcError::Deser(format!("Schema {} is not valid JSON: {e}", path.display())))
+}
+
+/// Reads every schema in a key value bucket, where each key is the command or event type the schema applies to
+async fn read_bucket(js: &Context, bucket: &str) -> Result<Vec<(Option<String>, String, Value)>> {
+    let store = js
+        .get_key_value(bucket)
+        .await
+        .map_err(|e| RpcError::Nats(format!("Failed to get schema bucket {bucket}: {e}")))?;
+    let keys: Vec<String> = store
+        .keys()
+        .await
+        .map_err(|e| RpcError::Nats(format!("Failed to list schemas in {bucket}: {e}")))?
+        .try_collect()
+        .await
+        .map_err(|e| RpcError::Nats(format!("Failed to list schemas in {bucket}: {e}")))?;
+
+    let mut documents = Vec::new();
+    for key in keys {
+        let origin = format!("{bucket}/{key}");
+        let Some(contents) = store
+            .get(key.as_str())
+            .await
+            .map_err(|e| RpcError::Nats(format!("Failed to read schema {origin}: {e}")))?
+        else {
+            continue;
+        };
+        let document = serde_json::from_slice(&contents)
+            .map_err(|e| RpcError::Deser(format!("Schema {origin} is not valid JSON: {e}")))?;
+        documents.push((Some(key), origin, document));
+    }
+    Ok(documents)
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use serde_json::json;
+
+    use super::{read_catalog, Schemas};
+    use crate::{consumers::RawCommand, eventsourcing::Event as ConcordanceEvent};
+
+    fn bankaccount_schemas() -> Schemas {
+        let catalog =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("../examples/bankaccount/eventcatalog");
+        Schemas::compile(read_catalog(&catalog).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn validates_commands_against_catalog_schemas() {
+        let schemas = bankaccount_schemas();
+        let mut command = RawCommand {
+            command_type: "deposit_funds".to_string(),
+            key: "ACT1".to_string(),
+            data: json!({"accountNumber": "ACT1", "amount": 200, "customerId": "BOB"}),
+            ..Default::default()
+        };
+        schemas.validate_command(&command).unwrap();
+
+        command.data = json!({"accountNumber": "ACT1", "amount": "lots"});
+        let error = schemas.validate_command(&command).unwrap_err().to_string();
+        assert!(error.contains("Command deposit_funds does not match its schema"));
+        assert!(error.contains(r#"/amount: "lots" is not of type "integer""#));
+        assert!(error.contains(r#"/: "customerId" is a required property"#));
+
+        // Commands without a schema aren't validated
+        command.command_type = "close_account".to_string();
+        schemas.validate_command(&command).unwrap();
+    }
+
+    #[test]
+    fn validates_event_payloads() {
+        let schemas = bankaccount_schemas();
+        let event = |payload: serde_json::Value| ConcordanceEvent {
+            event_type: "FundsDeposited".to_string(),
+            stream: "bankaccount".to_string(),
+            payload: serde_json::to_vec(&payload).unwrap(),
+            metadata: None,
+        };
+
+        schemas
+            .validate_event(&event(
+                json!({"accountNumber": "ACT1", "amount": 200, "customerId": "BOB"}),
+            ))
+            .unwrap();
+        assert!(schemas
+            .validate_event(&event(json!({"accountNumber": "ACT1"})))
+            .is_err());
+        assert!(Schemas::default()
+            .validate_event(&event(json!({"accountNumber": "ACT1"})))
+            .is_ok());
+    }
+}
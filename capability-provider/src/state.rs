@@ -1,23 +1,130 @@
-use async_nats::jetstream::{kv::Config as KvConfig, kv::Store, Context};
-use tracing::{error, instrument, trace};
+use async_nats::{
+    jetstream::{
+        kv::{Config as KvConfig, Operation, Store},
+        stream::{DirectGetErrorKind, LastRawMessageErrorKind},
+        Context,
+    },
+    HeaderMap,
+};
+use std::future::IntoFuture;
+use tracing::{debug, error, instrument, trace, warn};
 use wasmbus_rpc::error::RpcError;
 
-use crate::{config::ActorRole, Result};
+use crate::{
+    config::{ActorRole, BucketSettings},
+    metrics::metrics,
+    natsclient::{Namespace, NATS_EXPECTED_LAST_SUBJECT_SEQUENCE},
+    Result,
+};
 
 pub(crate) const STATE_BUCKET_NAME: &str = "CC_STATE";
+pub(crate) const SNAPSHOT_BUCKET_NAME: &str = "CC_SNAPSHOTS";
+
+const KV_OPERATION: &str = "KV-Operation";
+const KV_OPERATION_DELETE: &str = "DEL";
+const KV_OPERATION_PURGE: &str = "PURGE";
+const NATS_ROLLUP: &str = "Nats-Rollup";
+const ROLLUP_SUBJECT: &str = "sub";
+const NATS_SEQUENCE: &str = "Nats-Sequence";
+const LAST_EVENT_SEQUENCE: &str = "Concordance-Last-Event-Sequence";
+const EVENT_COUNT: &str = "Concordance-Event-Count";
+/// Identifies the conditional write that produced an entry, so a write whose acknowledgement was lost can be
+/// recognized
+const WRITE_ID: &str = "Concordance-Write-Id";
 
 #[derive(Clone)]
 pub struct EntityState {
     bucket: Store,
+    snapshots: Store,
+    context: Context,
+}
+
+/// Entity state along with the revision of the bucket entry it was read from. A revision of 0 means the key
+/// has never been written
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VersionedState {
+    pub data: Option<Vec<u8>>,
+    pub revision: u64,
+    /// The last event applied to the state, if it was recorded when the state was written
+    pub position: Option<EventPosition>,
+    /// The conditional write that produced the entry, if it was recorded
+    pub write_id: Option<String>,
+}
+
+/// Identifies the last event applied to an entity's state
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EventPosition {
+    /// Sequence of the event in the event stream
+    pub sequence: u64,
+    /// Number of events applied to the entity's state so far
+    pub count: u64,
+}
+
+impl EventPosition {
+    /// The position after applying the event at the given sequence
+    pub fn next(current: Option<EventPosition>, sequence: u64) -> EventPosition {
+        EventPosition {
+            sequence,
+            count: current.map_or(0, |p| p.count) + 1,
+        }
+    }
+}
+
+/// An immutable copy of an aggregate's state as of a given event, which replays can start from instead of the
+/// beginning of the event log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub state: Vec<u8>,
+    pub position: EventPosition,
+}
+
+/// The outcome of a conditional state write
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConditionalWrite {
+    /// The write succeeded, producing the given revision
+    Written(u64),
+    /// The entry was changed by someone else since it was read, so nothing was written
+    Conflict,
 }
 
 impl EntityState {
-    pub async fn new_from_context(context: &async_nats::jetstream::Context) -> Result<EntityState> {
+    pub async fn new_from_context(
+        context: &async_nats::jetstream::Context,
+        namespace: &Namespace,
+        settings: &BucketSettings,
+        snapshot_settings: &BucketSettings,
+    ) -> Result<EntityState> {
         Ok(EntityState {
-            bucket: get_or_create_bucket(context).await?,
+            bucket: get_or_create_bucket(
+                context,
+                &namespace.state_bucket_name(),
+                "Concordance state for aggregates and process managers",
+                settings,
+            )
+            .await?,
+            snapshots: get_or_create_bucket(
+                context,
+                &namespace.snapshot_bucket_name(),
+                "Concordance aggregate snapshots",
+                snapshot_settings,
+            )
+            .await?,
+            context: context.clone(),
         })
     }
 
+    /// Compares the state and snapshot buckets' configuration against the requested settings, returning a
+    /// description of every difference found
+    pub fn config_drift(
+        &self,
+        settings: &BucketSettings,
+        snapshot_settings: &BucketSettings,
+    ) -> Vec<String> {
+        let mut drift = bucket_config_drift(&self.bucket, settings);
+        drift.extend(bucket_config_drift(&self.snapshots, snapshot_settings));
+        drift
+    }
+
     #[instrument(level = "debug", skip(self, state))]
     pub async fn write_state(
         &self,
@@ -30,8 +137,8 @@ impl EntityState {
 
         let key = state_key(actor_role, entity_name, key);
 
-        self.bucket
-            .put(&key, state.into())
+        metrics()
+            .time_state("write", self.bucket.put(&key, state.into()))
             .await
             .map_err(|err| {
                 let err_msg = format!("Failed to write state @ {key}: {err:?}");
@@ -51,8 +158,8 @@ impl EntityState {
         trace!("Fetching state");
         let key = state_key(actor_role, entity_name, key);
 
-        self.bucket
-            .get(&key)
+        metrics()
+            .time_state("read", self.bucket.get(&key))
             .await
             .map_err(|err| {
                 let err_msg = format!("Failed to fetch state @ {key}: {err:?}");
@@ -62,6 +169,217 @@ impl EntityState {
             .map(|b| b.map(|v| v.to_vec()))
     }
 
+    /// Fetches state along with its revision so that it can later be written back with
+    /// [`write_state_at_revision`](Self::write_state_at_revision) or removed with
+    /// [`remove_state_at_revision`](Self::remove_state_at_revision)
+    #[instrument(level = "debug", skip(self))]
+    pub async fn fetch_versioned_state(
+        &self,
+        actor_role: &ActorRole,
+        entity_name: &str,
+        key: &str,
+    ) -> Result<VersionedState> {
+        trace!("Fetching versioned state");
+        let key = state_key(actor_role, entity_name, key);
+        metrics().time_state("read", self.entry_state(&key)).await
+    }
+
+    /// Writes state only if the entry is still at the given revision, i.e. nobody else has written or removed
+    /// it since it was read. This keeps competing writers (another provider instance or a redelivered message)
+    /// from overwriting newer state with stale state. The position of the last event applied to the state, if
+    /// given, is recorded alongside it
+    #[instrument(level = "debug", skip(self, state))]
+    pub async fn write_state_at_revision(
+        &self,
+        actor_role: &ActorRole,
+        entity_name: &str,
+        key: &str,
+        state: Vec<u8>,
+        revision: u64,
+        position: Option<EventPosition>,
+    ) -> Result<ConditionalWrite> {
+        trace!("Writing state at revision");
+        let key = state_key(actor_role, entity_name, key);
+        // This is the same message the KV client sends for an update, with the event position added to it
+        let mut headers = position_headers(position);
+        headers.insert(
+            NATS_EXPECTED_LAST_SUBJECT_SEQUENCE,
+            revision.to_string().as_str(),
+        );
+        let write_id = uuid::Uuid::new_v4().to_string();
+        headers.insert(WRITE_ID, write_id.as_str());
+        let written = publish_entry(&self.context, &self.bucket, &key, headers, state);
+        match metrics().time_state("write", written).await {
+            Ok(new_revision) => Ok(ConditionalWrite::Written(new_revision)),
+            Err(err) => {
+                self.classify_failed_write(&key, revision, &write_id, err)
+                    .await
+            }
+        }
+    }
+
+    /// Removes state only if the entry is still at the given revision. The position of the event that removed
+    /// the state, if given, is recorded on the removal. See
+    /// [`write_state_at_revision`](Self::write_state_at_revision)
+    #[instrument(level = "debug", skip(self))]
+    pub async fn remove_state_at_revision(
+        &self,
+        actor_role: &ActorRole,
+        entity_name: &str,
+        key: &str,
+        revision: u64,
+        position: Option<EventPosition>,
+    ) -> Result<ConditionalWrite> {
+        let key = state_key(actor_role, entity_name, key);
+        // This is the same purge message the KV client sends, with the addition of the expected revision
+        let mut headers = position_headers(position);
+        headers.insert(KV_OPERATION, KV_OPERATION_PURGE);
+        headers.insert(NATS_ROLLUP, ROLLUP_SUBJECT);
+        headers.insert(
+            NATS_EXPECTED_LAST_SUBJECT_SEQUENCE,
+            revision.to_string().as_str(),
+        );
+        let write_id = uuid::Uuid::new_v4().to_string();
+        headers.insert(WRITE_ID, write_id.as_str());
+        let removed = publish_entry(&self.context, &self.bucket, &key, headers, Vec::new());
+        match metrics().time_state("delete", removed).await {
+            Ok(new_revision) => Ok(ConditionalWrite::Written(new_revision)),
+            Err(err) => {
+                self.classify_failed_write(&key, revision, &write_id, err)
+                    .await
+            }
+        }
+    }
+
+    async fn entry_state(&self, key: &str) -> Result<VersionedState> {
+        read_entry(&self.bucket, key).await
+    }
+
+    /// The server doesn't give the client a distinct error for a revision mismatch, so a failed conditional
+    /// write is treated as a conflict when the entry has moved on from the expected revision. The entry may also
+    /// have moved on because the write itself landed and only its acknowledgement was lost, such as after a
+    /// timeout, in which case the entry carries the write's id and the write counts as written. Buckets that
+    /// don't allow direct gets can't tell the two apart
+    async fn classify_failed_write(
+        &self,
+        key: &str,
+        expected: u64,
+        write_id: &str,
+        err: impl std::fmt::Display,
+    ) -> Result<ConditionalWrite> {
+        let current = self.entry_state(key).await?;
+        if current.write_id.as_deref() == Some(write_id) {
+            debug!(
+                revision = current.revision,
+                error = %err,
+                "Write to state @ {key} landed although it reported an error"
+            );
+            Ok(ConditionalWrite::Written(current.revision))
+        } else if current.revision != expected {
+            debug!(
+                expected,
+                actual = current.revision,
+                "State @ {key} was changed concurrently"
+            );
+            Ok(ConditionalWrite::Conflict)
+        } else {
+            let err_msg = format!("Failed to write state @ {key}: {err}");
+            error!(message = err_msg);
+            Err(RpcError::Nats(err_msg))
+        }
+    }
+
+    /// Stores a snapshot of an aggregate's state. Each snapshot is written under its own key, made of the
+    /// aggregate's key and the sequence of the last event applied, so a snapshot is never overwritten. Once it is
+    /// written, the entity's older snapshots beyond the bucket's history setting are removed
+    #[instrument(level = "debug", skip(self, state))]
+    pub async fn write_snapshot(
+        &self,
+        entity_name: &str,
+        key: &str,
+        state: Vec<u8>,
+        position: EventPosition,
+    ) -> Result<()> {
+        trace!("Writing snapshot");
+        let key = state_key(&ActorRole::Aggregate, entity_name, key);
+        let snapshot_key = format!("{key}.{}", position.sequence);
+        let written = publish_entry(
+            &self.context,
+            &self.snapshots,
+            &snapshot_key,
+            position_headers(Some(position)),
+            state,
+        );
+        metrics()
+            .time_state("write", written)
+            .await
+            .map_err(|err| {
+                let err_msg = format!("Failed to write snapshot @ {snapshot_key}: {err}");
+                error!(message = err_msg);
+                RpcError::Nats(err_msg)
+            })?;
+
+        let keep = self
+            .snapshots
+            .stream
+            .cached_info()
+            .config
+            .max_messages_per_subject
+            .max(1) as u64;
+        let purged = self
+            .snapshots
+            .stream
+            .purge()
+            .filter(self.snapshot_filter(&key))
+            .keep(keep)
+            .into_future();
+        // Keeping older snapshots around only takes up space, so failing to remove them doesn't fail the write
+        if let Err(err) = metrics().time_state("delete", purged).await {
+            warn!("Failed to remove older snapshots @ {key}: {err}");
+        }
+        Ok(())
+    }
+
+    /// Fetches the latest snapshot of an aggregate's state, if there is one
+    #[instrument(level = "debug", skip(self))]
+    pub async fn fetch_snapshot(&self, entity_name: &str, key: &str) -> Result<Option<Snapshot>> {
+        trace!("Fetching snapshot");
+        let key = state_key(&ActorRole::Aggregate, entity_name, key);
+        let filter = self.snapshot_filter(&key);
+        let latest = self
+            .snapshots
+            .stream
+            .get_last_raw_message_by_subject(&filter);
+        let to_error = |err: &dyn std::fmt::Display| {
+            let err_msg = format!("Failed to fetch snapshot @ {key}: {err}");
+            error!(message = err_msg);
+            RpcError::Nats(err_msg)
+        };
+        let message = match metrics().time_state("read", latest).await {
+            Ok(raw) => async_nats::Message::try_from(raw).map_err(|e| to_error(&e))?,
+            Err(e) if e.kind() == LastRawMessageErrorKind::NoMessageFound => return Ok(None),
+            Err(e) => return Err(to_error(&e)),
+        };
+        let parse = |name: &str| {
+            message
+                .headers
+                .as_ref()
+                .and_then(|h| h.get(name))
+                .and_then(|v| v.to_string().parse::<u64>().ok())
+        };
+        Ok(parse(LAST_EVENT_SEQUENCE)
+            .zip(parse(EVENT_COUNT))
+            .map(|(sequence, count)| Snapshot {
+                state: message.payload.to_vec(),
+                position: EventPosition { sequence, count },
+            }))
+    }
+
+    /// A filter matching every snapshot of the entity with the given state key
+    fn snapshot_filter(&self, key: &str) -> String {
+        format!("{}{key}.*", self.snapshots.prefix)
+    }
+
     #[instrument(level = "debug", skip(self))]
     pub async fn remove_state(
         &self,
@@ -71,8 +389,8 @@ impl EntityState {
     ) -> Result<()> {
         let key = state_key(actor_role, entity_name, key);
         // We use a purge here instead of delete because we don't care about maintaining history (that comes from the event log)
-        self.bucket
-            .purge(&key)
+        metrics()
+            .time_state("delete", self.bucket.purge(&key))
             .await
             .map_err(|e| {
                 let err_msg = format!("Failed to delete state @ {key}: {e:?}");
@@ -83,6 +401,32 @@ impl EntityState {
     }
 }
 
+/// Compares a bucket's configuration against the requested settings
+fn bucket_config_drift(bucket: &Store, settings: &BucketSettings) -> Vec<String> {
+    let name = &bucket.name;
+    let actual = &bucket.stream.cached_info().config;
+    let mut drift = Vec::new();
+    if actual.max_messages_per_subject != settings.history {
+        drift.push(format!(
+            "{name}: history requested {} but bucket has {}",
+            settings.history, actual.max_messages_per_subject
+        ));
+    }
+    if actual.num_replicas.max(1) != settings.replicas.max(1) {
+        drift.push(format!(
+            "{name}: replicas requested {} but bucket has {}",
+            settings.replicas, actual.num_replicas
+        ));
+    }
+    if actual.storage != settings.storage {
+        drift.push(format!(
+            "{name}: storage requested {:?} but bucket has {:?}",
+            settings.storage, actual.storage
+        ));
+    }
+    drift
+}
+
 fn state_key(role: &ActorRole, entity_name: &str, key: &str) -> String {
     match role {
         ActorRole::Aggregate => format!("agg.{entity_name}.{key}"),
@@ -94,9 +438,91 @@ fn state_key(role: &ActorRole, entity_name: &str, key: &str) -> String {
     }
 }
 
-async fn get_or_create_bucket(js: &Context) -> Result<Store> {
+/// Reads the latest entry for a key, including the event position recorded in its headers
+async fn read_entry(bucket: &Store, key: &str) -> Result<VersionedState> {
+    let to_error = |err: &dyn std::fmt::Display| {
+        let err_msg = format!("Failed to fetch entry @ {}{key}: {err}", bucket.name);
+        error!(message = err_msg);
+        RpcError::Nats(err_msg)
+    };
+    // Headers can only be read with a direct get. Buckets created by older clients may not allow those, in which
+    // case the entry is read without its position
+    if !bucket.stream.cached_info().config.allow_direct {
+        let entry = bucket.entry(key).await.map_err(|e| to_error(&e))?;
+        return Ok(match entry {
+            Some(entry) => VersionedState {
+                // Deleted and purged entries keep their revision so the next write can be made against it
+                data: (entry.operation == Operation::Put).then(|| entry.value.to_vec()),
+                revision: entry.revision,
+                position: None,
+                write_id: None,
+            },
+            None => VersionedState::default(),
+        });
+    }
+
+    let subject = format!("{}{key}", bucket.prefix);
+    let message = match bucket.stream.direct_get_last_for_subject(&subject).await {
+        Ok(message) => message,
+        Err(e) if e.kind() == DirectGetErrorKind::NotFound => return Ok(VersionedState::default()),
+        Err(e) => return Err(to_error(&e)),
+    };
+    let header = |name: &str| {
+        message
+            .headers
+            .as_ref()
+            .and_then(|h| h.get(name))
+            .map(|v| v.to_string())
+    };
+    let parse = |name: &str| header(name).and_then(|v| v.parse::<u64>().ok());
+    let is_put = !matches!(
+        header(KV_OPERATION).as_deref(),
+        Some(KV_OPERATION_DELETE) | Some(KV_OPERATION_PURGE)
+    );
+    Ok(VersionedState {
+        data: is_put.then(|| message.payload.to_vec()),
+        revision: parse(NATS_SEQUENCE).ok_or_else(|| to_error(&"entry is missing its sequence"))?,
+        position: parse(LAST_EVENT_SEQUENCE)
+            .zip(parse(EVENT_COUNT))
+            .map(|(sequence, count)| EventPosition { sequence, count }),
+        write_id: header(WRITE_ID),
+    })
+}
+
+/// Publishes a value for a key directly to the bucket's stream so that headers can be included, returning the
+/// new revision
+async fn publish_entry(
+    context: &Context,
+    bucket: &Store,
+    key: &str,
+    headers: HeaderMap,
+    value: Vec<u8>,
+) -> std::result::Result<u64, String> {
+    let prefix = bucket.put_prefix.as_ref().unwrap_or(&bucket.prefix);
+    let ack = context
+        .publish_with_headers(format!("{prefix}{key}"), headers, value.into())
+        .await
+        .map_err(|e| e.to_string())?;
+    ack.await.map(|ack| ack.sequence).map_err(|e| e.to_string())
+}
+
+fn position_headers(position: Option<EventPosition>) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if let Some(position) = position {
+        headers.insert(LAST_EVENT_SEQUENCE, position.sequence.to_string().as_str());
+        headers.insert(EVENT_COUNT, position.count.to_string().as_str());
+    }
+    headers
+}
+
+async fn get_or_create_bucket(
+    js: &Context,
+    bucket_name: &str,
+    description: &str,
+    settings: &BucketSettings,
+) -> Result<Store> {
     if let Ok(store) = js
-        .get_key_value(STATE_BUCKET_NAME)
+        .get_key_value(bucket_name)
         .await
         .map_err(|e| RpcError::Nats(e.to_string()))
     {
@@ -104,9 +530,11 @@ async fn get_or_create_bucket(js: &Context) -> Result<Store> {
     } else {
         Ok(js
             .create_key_value(KvConfig {
-                bucket: STATE_BUCKET_NAME.to_string(),
-                description: "Concordance state for aggregates and process managers".to_string(),
-                history: 1,
+                bucket: bucket_name.to_string(),
+                description: description.to_string(),
+                history: settings.history,
+                num_replicas: settings.replicas,
+                storage: settings.storage,
                 ..Default::default()
             })
             .await
@@ -117,9 +545,14 @@ async fn get_or_create_bucket(js: &Context) -> Result<Store> {
 #[cfg(test)]
 mod test {
     use crate::{
-        config::ActorRole,
-        natsclient::test::{clear_streams, create_js_context},
-        state::EntityState,
+        config::{ActorRole, BucketSettings},
+        natsclient::{
+            test::{clear_streams, create_js_context},
+            Namespace,
+        },
+        state::{
+            state_key, ConditionalWrite, EntityState, EventPosition, Snapshot, VersionedState,
+        },
     };
 
     #[tokio::test]
@@ -138,7 +571,14 @@ mod test {
     async fn state_round_trip() {
         let js = create_js_context().await;
         clear_streams(js.clone()).await;
-        let state = EntityState::new_from_context(&js).await.unwrap();
+        let state = EntityState::new_from_context(
+            &js,
+            &Namespace::default(),
+            &BucketSettings::default(),
+            &BucketSettings::default(),
+        )
+        .await
+        .unwrap();
 
         state
             .write_state(
@@ -160,7 +600,14 @@ mod test {
     async fn state_delete_item() {
         let js = create_js_context().await;
         clear_streams(js.clone()).await;
-        let state = EntityState::new_from_context(&js).await.unwrap();
+        let state = EntityState::new_from_context(
+            &js,
+            &Namespace::default(),
+            &BucketSettings::default(),
+            &BucketSettings::default(),
+        )
+        .await
+        .unwrap();
         state
             .write_state(
                 &ActorRole::Aggregate,
@@ -188,12 +635,242 @@ mod test {
             .is_ok());
     }
 
+    #[tokio::test]
+    async fn conditional_writes_detect_conflicts() {
+        let js = create_js_context().await;
+        clear_streams(js.clone()).await;
+        let state = EntityState::new_from_context(
+            &js,
+            &Namespace::default(),
+            &BucketSettings::default(),
+            &BucketSettings::default(),
+        )
+        .await
+        .unwrap();
+        let role = ActorRole::Aggregate;
+
+        let initial = state
+            .fetch_versioned_state(&role, "bankaccount", "ACT123")
+            .await
+            .unwrap();
+        assert_eq!(VersionedState::default(), initial);
+
+        let written = state
+            .write_state_at_revision(&role, "bankaccount", "ACT123", b"v1".to_vec(), 0, None)
+            .await
+            .unwrap();
+        let ConditionalWrite::Written(revision) = written else {
+            panic!("expected the first write to succeed");
+        };
+
+        // A writer still holding the original revision must not clobber the newer state
+        assert_eq!(
+            ConditionalWrite::Conflict,
+            state
+                .write_state_at_revision(&role, "bankaccount", "ACT123", b"stale".to_vec(), 0, None)
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            ConditionalWrite::Conflict,
+            state
+                .remove_state_at_revision(&role, "bankaccount", "ACT123", 0, None)
+                .await
+                .unwrap()
+        );
+        let current = state
+            .fetch_versioned_state(&role, "bankaccount", "ACT123")
+            .await
+            .unwrap();
+        assert_eq!(Some(b"v1".to_vec()), current.data);
+        assert_eq!(revision, current.revision);
+
+        // A write that reported an error but landed anyway, e.g. after a timeout, isn't a conflict
+        let key = state_key(&role, "bankaccount", "ACT123");
+        assert_eq!(
+            ConditionalWrite::Written(revision),
+            state
+                .classify_failed_write(&key, 0, current.write_id.as_deref().unwrap(), "timed out")
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            ConditionalWrite::Conflict,
+            state
+                .classify_failed_write(&key, 0, "someone else", "timed out")
+                .await
+                .unwrap()
+        );
+
+        assert!(matches!(
+            state
+                .remove_state_at_revision(
+                    &role,
+                    "bankaccount",
+                    "ACT123",
+                    revision,
+                    Some(EventPosition {
+                        sequence: 9,
+                        count: 2
+                    })
+                )
+                .await
+                .unwrap(),
+            ConditionalWrite::Written(_)
+        ));
+        let removed = state
+            .fetch_versioned_state(&role, "bankaccount", "ACT123")
+            .await
+            .unwrap();
+        assert!(removed.data.is_none());
+        // The removal records the event that removed the state
+        assert_eq!(
+            Some(EventPosition {
+                sequence: 9,
+                count: 2
+            }),
+            removed.position
+        );
+        // State can be written again on top of the removal
+        assert!(matches!(
+            state
+                .write_state_at_revision(
+                    &role,
+                    "bankaccount",
+                    "ACT123",
+                    b"v2".to_vec(),
+                    removed.revision,
+                    None,
+                )
+                .await
+                .unwrap(),
+            ConditionalWrite::Written(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn records_event_positions_and_snapshots() {
+        let js = create_js_context().await;
+        clear_streams(js.clone()).await;
+        let state = EntityState::new_from_context(
+            &js,
+            &Namespace::default(),
+            &BucketSettings::default(),
+            &BucketSettings::default(),
+        )
+        .await
+        .unwrap();
+        let role = ActorRole::Aggregate;
+
+        let position = EventPosition::next(None, 42);
+        assert_eq!(
+            EventPosition {
+                sequence: 42,
+                count: 1
+            },
+            position
+        );
+        let ConditionalWrite::Written(revision) = state
+            .write_state_at_revision(&role, "rover", "R1", b"v1".to_vec(), 0, Some(position))
+            .await
+            .unwrap()
+        else {
+            panic!("expected the write to succeed");
+        };
+        let current = state
+            .fetch_versioned_state(&role, "rover", "R1")
+            .await
+            .unwrap();
+        assert!(current.write_id.is_some());
+        assert_eq!(
+            VersionedState {
+                data: Some(b"v1".to_vec()),
+                revision,
+                position: Some(position),
+                write_id: current.write_id.clone(),
+            },
+            current
+        );
+        // Values written with positions are still plain values to everything else reading the bucket
+        assert_eq!(
+            Some(b"v1".to_vec()),
+            state.fetch_state(&role, "rover", "R1").await.unwrap()
+        );
+
+        assert!(state.fetch_snapshot("rover", "R1").await.unwrap().is_none());
+        let later = EventPosition::next(Some(position), 50);
+        state
+            .write_snapshot("rover", "R1", b"v1".to_vec(), position)
+            .await
+            .unwrap();
+        state
+            .write_snapshot("rover", "R1", b"v2".to_vec(), later)
+            .await
+            .unwrap();
+        assert_eq!(
+            Some(Snapshot {
+                state: b"v2".to_vec(),
+                position: EventPosition {
+                    sequence: 50,
+                    count: 2
+                },
+            }),
+            state.fetch_snapshot("rover", "R1").await.unwrap()
+        );
+        // Each snapshot has its own key, and only the bucket's history of them is kept
+        let snapshots = js.get_key_value("CC_SNAPSHOTS").await.unwrap();
+        assert!(snapshots.get("agg.rover.R1.42").await.unwrap().is_none());
+        assert_eq!(
+            Some(b"v2".to_vec()),
+            snapshots
+                .get("agg.rover.R1.50")
+                .await
+                .unwrap()
+                .map(|v| v.to_vec())
+        );
+        // Another entity whose key starts with the same characters has snapshots of its own
+        assert!(state.fetch_snapshot("rover", "R").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn bucket_config_drift() {
+        let js = create_js_context().await;
+        clear_streams(js.clone()).await;
+
+        let settings = BucketSettings::default();
+        let state = EntityState::new_from_context(&js, &Namespace::default(), &settings, &settings)
+            .await
+            .unwrap();
+        assert!(state.config_drift(&settings, &settings).is_empty());
+
+        let requested = BucketSettings {
+            history: 10,
+            ..Default::default()
+        };
+        // The existing bucket is reused rather than modified
+        let state =
+            EntityState::new_from_context(&js, &Namespace::default(), &requested, &settings)
+                .await
+                .unwrap();
+        assert_eq!(
+            vec!["CC_STATE: history requested 10 but bucket has 1".to_string()],
+            state.config_drift(&requested, &settings)
+        );
+    }
+
     #[tokio::test]
     async fn query_nonexistent_state() {
         let js = create_js_context().await;
         clear_streams(js.clone()).await;
 
-        let state = EntityState::new_from_context(&js).await.unwrap();
+        let state = EntityState::new_from_context(
+            &js,
+            &Namespace::default(),
+            &BucketSettings::default(),
+            &BucketSettings::default(),
+        )
+        .await
+        .unwrap();
         let data = state
             .fetch_state(
                 &ActorRole::Aggregate,
@@ -1,16 +1,27 @@
 //! # wasmCloud Provider Implementation
 //! This module contains the trait implementation mandatory for building a wasmCloud capability provider
 
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+
 use async_trait::async_trait;
-use tracing::{error, instrument, warn};
+use tracing::{debug, error, info, instrument, warn};
 use wasmbus_rpc::core::{HealthCheckRequest, HealthCheckResponse};
 use wasmbus_rpc::provider::prelude::*;
 
-use crate::config::{ActorRole, BaseConfiguration, InterestConstraint, InterestDeclaration};
-use crate::consumers::{CommandConsumer, ConsumerManager, EventConsumer};
+use crate::admin::AdminService;
+use crate::config::{
+    ActorRole, BaseConfiguration, EventSubjects, InterestConstraint, InterestDeclaration,
+};
+use crate::consumers::{CommandConsumer, ConsumerManager, ConsumerStatus, EventConsumer};
+use crate::deadletter::{DeadLetterQueue, DeadLetterService};
+use crate::gateway::CommandGateway;
+use crate::metrics::MetricsServer;
 use crate::Result;
 
-use crate::natsclient::NatsClient;
+use crate::natsclient::{wait_for_dropped_nacks, Namespace, NatsClient, SEND_TIMEOUT_DURATION};
+use crate::schemas::Schemas;
 use crate::state::EntityState;
 use crate::workers::{
     AggregateCommandWorker, AggregateEventWorker, GeneralEventWorker, ProcessManagerWorker,
@@ -22,6 +33,20 @@ pub struct ConcordanceProvider {
     consumer_manager: ConsumerManager,
     js: async_nats::jetstream::Context,
     state: EntityState,
+    /// Schemas that inbound commands and the events aggregates produce are validated against
+    schemas: Schemas,
+    namespace: Namespace,
+    /// Differences between the requested and actual configuration of pre-existing streams and buckets
+    config_drift: Vec<String>,
+    shutdown_timeout: Duration,
+    /// Task serving requests on the admin subjects
+    admin: Arc<tokio::task::JoinHandle<()>>,
+    /// Task capturing dead letters from max deliveries advisories
+    dead_letters: Arc<tokio::task::JoinHandle<()>>,
+    /// Task accepting commands on the gateway subjects
+    gateway: Arc<tokio::task::JoinHandle<()>>,
+    /// Task serving metrics over HTTP, if a metrics address is configured
+    metrics: Option<Arc<tokio::task::JoinHandle<()>>>,
 }
 
 impl ConcordanceProvider {
@@ -33,19 +58,176 @@ impl ConcordanceProvider {
             async_nats::jetstream::new(nc.clone())
         };
 
-        let client = NatsClient::new(js.clone());
-        let (e, c) = client.ensure_streams().await.unwrap();
+        let namespace = base_config.namespace()?;
+        let client = NatsClient::new(js.clone(), namespace.clone())
+            .with_stream_settings(
+                base_config.events_stream.clone(),
+                base_config.commands_stream.clone(),
+            )
+            .with_topology(
+                base_config.event_sources.clone(),
+                base_config.event_mirror.clone(),
+                base_config.outbound_stream.clone(),
+            )
+            .with_event_subjects(base_config.event_subjects);
+        let (e, c) = client.ensure_streams().await?;
+        client.ensure_dead_letter_stream().await?;
+        let keyed_events = client.uses_keyed_subjects(&e);
+        if keyed_events && !nc.is_server_compatible(2, 11, 0) {
+            // Checking an entity's events against its last known sequence is the point of asking for keyed subjects
+            if base_config.event_subjects == EventSubjects::Keyed {
+                return Err(RpcError::ProviderInit(
+                    "Keyed event subjects require NATS server 2.11 or later".to_string(),
+                ));
+            }
+            warn!("NATS server is older than 2.11, so events for the same key will not be checked against the last known sequence when they are published");
+        }
+        let namespace = namespace.with_keyed_events(keyed_events);
+        let state = EntityState::new_from_context(
+            &js,
+            &namespace,
+            &base_config.state_bucket,
+            &base_config.snapshot_bucket,
+        )
+        .await?;
+
+        let schemas = Schemas::load(&js, &base_config.schemas).await?;
+
+        let mut config_drift = client.config_drift(&e, &c);
+        config_drift
+            .extend(state.config_drift(&base_config.state_bucket, &base_config.snapshot_bucket));
+        for drift in &config_drift {
+            warn!("Existing configuration differs from requested settings and will not be changed - {drift}");
+        }
         let cm = ConsumerManager::new(e, c);
-        let state = EntityState::new_from_context(&js).await?;
+        let dlq = DeadLetterQueue::new(js.clone(), namespace.clone());
+        let admin = AdminService {
+            nc: nc.clone(),
+            js: js.clone(),
+            namespace: namespace.clone(),
+            state: state.clone(),
+            consumer_manager: cm.clone(),
+            dead_letters: dlq.clone(),
+            instance: uuid::Uuid::new_v4().to_string(),
+        }
+        .serve()
+        .await?;
+        let gateway = CommandGateway {
+            nc: nc.clone(),
+            js: js.clone(),
+            namespace: namespace.clone(),
+            consumer_manager: cm.clone(),
+        }
+        .serve()
+        .await?;
+        let dead_letters = DeadLetterService {
+            nc: nc.clone(),
+            js: js.clone(),
+            namespace: namespace.clone(),
+            dead_letters: dlq,
+            consumer_manager: cm.clone(),
+        }
+        .serve()
+        .await?;
+        let metrics = match &base_config.metrics_address {
+            Some(address) => Some(
+                MetricsServer {
+                    address: address.clone(),
+                    consumer_manager: cm.clone(),
+                }
+                .serve()
+                .await?,
+            ),
+            None => None,
+        };
 
         Ok(ConcordanceProvider {
             nc,
             consumer_manager: cm,
             state,
+            schemas,
             js,
+            namespace,
+            config_drift,
+            shutdown_timeout: base_config.shutdown_timeout(),
+            admin: Arc::new(admin),
+            dead_letters: Arc::new(dead_letters),
+            gateway: Arc::new(gateway),
+            metrics: metrics.map(Arc::new),
         })
     }
 
+    /// Stops pulling new messages, waits (up to the configured shutdown timeout) for every worker to
+    /// finish and ack the message it is currently handling, and nacks the messages that won't be
+    /// handled. The NATS connection is flushed last so those acks and nacks reach the server before
+    /// the process exits. The client has no way to close a connection that other clones still hold,
+    /// so the connection itself closes with the process. Calling this more than once is harmless
+    pub async fn drain(&self) {
+        info!("Draining consumers before shutdown");
+        self.admin.abort();
+        self.gateway.abort();
+        self.dead_letters.abort();
+        if let Some(metrics) = &self.metrics {
+            metrics.abort();
+        }
+        self.consumer_manager.shutdown(self.shutdown_timeout).await;
+        wait_for_dropped_nacks(SEND_TIMEOUT_DURATION).await;
+        if let Err(e) = self.nc.flush().await {
+            warn!(error = %e, "Failed to flush NATS connection during shutdown");
+        }
+    }
+
+    /// Checks the NATS connection, the streams and state bucket, and every consumer in the manager. Returns
+    /// whether everything is healthy along with a human-readable line per component checked
+    async fn check_health(&self) -> (bool, Vec<String>) {
+        let mut healthy = true;
+        let mut report = Vec::new();
+
+        let conn_state = self.nc.connection_state();
+        if conn_state != async_nats::connection::State::Connected {
+            healthy = false;
+        }
+        report.push(format!("NATS connection: {conn_state}"));
+
+        for name in [
+            self.namespace.event_stream_name(),
+            self.namespace.command_stream_name(),
+            self.namespace.dead_letter_stream_name(),
+        ] {
+            match self.js.get_stream(&name).await {
+                Ok(_) => report.push(format!("{name}: reachable")),
+                Err(e) => {
+                    healthy = false;
+                    report.push(format!("{name}: unreachable ({e})"));
+                }
+            }
+        }
+        for bucket in [
+            self.namespace.state_bucket_name(),
+            self.namespace.snapshot_bucket_name(),
+        ] {
+            match self.js.get_key_value(&bucket).await {
+                Ok(_) => report.push(format!("{bucket}: reachable")),
+                Err(e) => {
+                    healthy = false;
+                    report.push(format!("{bucket}: unreachable ({e})"));
+                }
+            }
+        }
+
+        // Drift is worth surfacing but doesn't stop the provider from working
+        report.extend(self.config_drift.iter().cloned());
+
+        for status in self.consumer_manager.consumer_statuses().await {
+            if !status.running || status.supervisor.is_failing() {
+                healthy = false;
+            }
+            report.push(describe_consumer(&status));
+        }
+
+        (healthy, report)
+    }
+
     /// Adds a consumer and the appropriate worker to the provider's consumer manager, which will in turn create or
     /// bind to an existing NATS consumer
     async fn add_consumer(&self, decl: &InterestDeclaration) -> RpcResult<bool> {
@@ -116,6 +298,11 @@ impl ConcordanceProvider {
     /// the command. Note that aggregates can't change state during command
     /// application.
     async fn add_aggregate_cmd_consumer(&self, decl: &InterestDeclaration) -> bool {
+        if decl.namespace.keyed_events() && !self.nc.is_server_compatible(2, 11, 0) {
+            warn!(
+                "NATS server is older than 2.11, so {decl} won't detect other commands for the same key publishing events while it handles a command"
+            );
+        }
         if let Err(e) = self
             .consumer_manager
             .add_consumer::<AggregateCommandWorker, CommandConsumer>(
@@ -125,6 +312,7 @@ impl ConcordanceProvider {
                     self.js.clone(),
                     decl.clone(),
                     self.state.clone(),
+                    self.schemas.clone(),
                 ),
             )
             .await
@@ -165,21 +353,68 @@ impl ConcordanceProvider {
     }
 }
 
+fn describe_consumer(status: &ConsumerStatus) -> String {
+    let name = status.interest.consumer_name();
+    let actor_id = &status.interest.actor_id;
+    let supervisor = &status.supervisor;
+    let liveness = if !status.running {
+        "dead"
+    } else if supervisor.restarting {
+        "restarting"
+    } else {
+        "running"
+    };
+    let counts = match (status.pending, status.ack_pending) {
+        (Some(pending), Some(ack_pending)) => {
+            format!("{pending} pending, {ack_pending} ack pending")
+        }
+        _ => format!(
+            "consumer info unavailable {} times in a row",
+            supervisor.consecutive_info_failures
+        ),
+    };
+    let mut description = format!(
+        "{name} ({actor_id}): {liveness}, {counts}, {} restarts",
+        supervisor.restarts
+    );
+    if supervisor.consecutive_failures > 0 {
+        description.push_str(&format!(
+            ", {} consecutive failures",
+            supervisor.consecutive_failures
+        ));
+    }
+    if let Some(error) = &supervisor.last_error {
+        description.push_str(&format!(", last error: {error}"));
+    }
+    description
+}
+
 impl ProviderDispatch for ConcordanceProvider {}
 
 #[async_trait]
 impl ProviderHandler for ConcordanceProvider {
+    /// Reports unhealthy if the NATS connection is down, a stream or the state bucket can't be reached, or any
+    /// consumer has died, is waiting to be restarted, keeps failing soon after each restart, or its info couldn't be
+    /// fetched several times in a row. The message always
+    /// describes the state of every component that was checked
     async fn health_request(&self, _arg: &HealthCheckRequest) -> RpcResult<HealthCheckResponse> {
+        let (healthy, report) = self.check_health().await;
+        if !healthy {
+            warn!("Health check failed: {}", report.join("; "));
+        }
         Ok(HealthCheckResponse {
-            healthy: true,
-            message: None,
+            healthy,
+            message: Some(report.join("; ")),
         })
     }
 
     #[instrument(level = "info", skip(self, ld), fields(actor_id = %ld.actor_id, provider_id = %ld.provider_id, link_name = %ld.link_name))]
     async fn put_link(&self, ld: &LinkDefinition) -> RpcResult<bool> {
         let decls = match InterestDeclaration::from_linkdefinition(ld.clone()) {
-            Ok(decls) => decls,
+            Ok(decls) => decls
+                .into_iter()
+                .map(|d| d.with_namespace(self.namespace.clone()))
+                .collect::<Vec<_>>(),
             Err(e) => {
                 error!("Failed to derive interest declarations from link definition. Aborting due to error: {e}");
                 return Ok(false);
@@ -194,10 +429,18 @@ impl ProviderHandler for ConcordanceProvider {
         Ok(true)
     }
 
-    /// Notify the provider that the link is dropped
-    #[allow(unused_variables)]
+    /// Handle system shutdown message by draining in-flight work
+    async fn shutdown(&self) -> std::result::Result<(), Infallible> {
+        self.drain().await;
+        Ok(())
+    }
+
+    /// Notify the provider that the link is dropped. All consumers belonging to the actor are stopped
+    /// so that no further messages are pulled on its behalf
+    #[instrument(level = "info", skip(self))]
     async fn delete_link(&self, actor_id: &str) {
-        //let _ = self.router.unregister_interest(actor_id);
+        let removed = self.consumer_manager.remove_consumers(actor_id).await;
+        debug!("Removed {} consumer(s) for unlinked actor", removed.len());
     }
 }
 
@@ -206,7 +449,11 @@ pub(crate) mod test {
     use std::collections::HashMap;
 
     use async_nats::jetstream::consumer::AckPolicy;
-    use wasmbus_rpc::{core::LinkDefinition, provider::ProviderHandler, wascap::prelude::KeyPair};
+    use wasmbus_rpc::{
+        core::{HealthCheckRequest, LinkDefinition},
+        provider::ProviderHandler,
+        wascap::prelude::KeyPair,
+    };
 
     use crate::{
         config::BaseConfiguration,
@@ -231,41 +478,36 @@ pub(crate) mod test {
         .await
         .unwrap();
 
-        cp.put_link(&make_link_definition(
+        let projector = make_link_definition(
             "projector",
             "bankaccount",
             "account_opened,account_updated,amount_withdrawn,amount_deposited",
-        ))
-        .await
-        .unwrap();
+        );
+        cp.put_link(&projector).await.unwrap();
 
-        cp.put_link(&make_link_definition(
+        let process_manager = make_link_definition(
             "process_manager",
             "bankaccount",
             r#"{"start": "account_opened", "advance": ["account_updated","amount_withdrawn","amount_deposited"], "stop": ["account_closed"]}"#,
-        ))
-        .await
-        .unwrap();
+        );
+        cp.put_link(&process_manager).await.unwrap();
 
-        cp.put_link(&make_link_definition(
-            "notifier",
-            "bankaccount",
-            "account_opened",
-        ))
-        .await
-        .unwrap();
+        let notifier = make_link_definition("notifier", "bankaccount", "account_opened");
+        cp.put_link(&notifier).await.unwrap();
 
         // Verify that the provider has created the consumers corresponding to the linkdefs
         let stream = js.get_stream("CC_EVENTS").await.unwrap();
-        assert_eq!(
-            stream
-                .consumer_info("NOTIFIER_bankaccount")
-                .await
-                .unwrap()
-                .config
-                .ack_policy,
-            AckPolicy::Explicit
-        );
+        for (ld, prefix) in [
+            (&notifier, "NOTIFIER_bankaccount_"),
+            (&process_manager, "PM_bankaccount_"),
+            (&projector, "PROJ_bankaccount_"),
+        ] {
+            let name = format!("{prefix}{}", &ld.actor_id[..8]);
+            assert_eq!(
+                stream.consumer_info(name).await.unwrap().config.ack_policy,
+                AckPolicy::Explicit
+            );
+        }
         assert_eq!(
             stream
                 .consumer_info("AGG_EVT_bankaccount")
@@ -275,24 +517,6 @@ pub(crate) mod test {
                 .ack_policy,
             AckPolicy::Explicit
         );
-        assert_eq!(
-            stream
-                .consumer_info("PM_bankaccount")
-                .await
-                .unwrap()
-                .config
-                .ack_policy,
-            AckPolicy::Explicit
-        );
-        assert_eq!(
-            stream
-                .consumer_info("PROJ_bankaccount")
-                .await
-                .unwrap()
-                .config
-                .ack_policy,
-            AckPolicy::Explicit
-        );
 
         let stream = js.get_stream("CC_COMMANDS").await.unwrap();
         let info = stream.consumer_info("AGG_CMD_bankaccount").await.unwrap();
@@ -302,6 +526,42 @@ pub(crate) mod test {
         clear_streams(js).await;
     }
 
+    #[tokio::test]
+    async fn test_health_reports_consumers() {
+        let js = create_js_context().await;
+        clear_streams(js.clone()).await;
+
+        let cp = ConcordanceProvider::try_new(BaseConfiguration::default())
+            .await
+            .unwrap();
+        cp.put_link(&make_link_definition(
+            "notifier",
+            "bankaccount",
+            "account_opened",
+        ))
+        .await
+        .unwrap();
+
+        let resp = cp
+            .health_request(&HealthCheckRequest::default())
+            .await
+            .unwrap();
+        assert!(resp.healthy);
+        let message = resp.message.unwrap();
+        assert!(message.contains("CC_EVENTS: reachable"));
+        assert!(message.contains("NOTIFIER_bankaccount_"));
+        assert!(message.contains("0 ack pending"));
+
+        // Losing a stream out from under the provider must be reported
+        clear_streams(js).await;
+        let resp = cp
+            .health_request(&HealthCheckRequest::default())
+            .await
+            .unwrap();
+        assert!(!resp.healthy);
+        assert!(resp.message.unwrap().contains("CC_EVENTS: unreachable"));
+    }
+
     fn make_link_definition(role: &str, entity_name: &str, interest: &str) -> LinkDefinition {
         let mut ld = LinkDefinition::default();
         ld.actor_id = KeyPair::new_module().public_key();
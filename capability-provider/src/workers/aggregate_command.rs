@@ -1,24 +1,39 @@
 use async_nats::jetstream::Context;
-use tracing::{debug, error, instrument, trace};
+use tracing::{debug, info, instrument, trace};
+use wasmbus_rpc::error::RpcError;
 
 use crate::{
     config::InterestDeclaration,
     consumers::WorkError,
-    events::publish_es_event,
+    events::{last_event_sequence, publish_es_event, Causation, ExpectedSequence},
     eventsourcing::{AggregateService, AggregateServiceSender, StatefulCommand},
+    gateway::{CommandReply, EmittedEvent, RejectionReason, REPLY_TO_HEADER},
+    metrics::metrics,
     natsclient::AckableMessage,
-    state::EntityState,
+    otel::rpc_context,
+    rehydrate::rehydrate_entity,
+    schemas::Schemas,
+    state::{EntityState, VersionedState},
 };
 
 use crate::consumers::{RawCommand, WorkResult, Worker};
 
 // TODO: add an AggregateEventWorker
 
+/// How a command turned out once the worker is done with it
+enum CommandOutcome {
+    /// The command was handled and its events were published
+    Handled(Vec<EmittedEvent>),
+    /// The command can never be handled and was either dropped or moved to the dead letter stream
+    Rejected(RejectionReason, String),
+}
+
 pub struct AggregateCommandWorker {
     pub nc: async_nats::Client,
     pub context: Context,
     pub interest: InterestDeclaration,
     pub state: EntityState,
+    pub schemas: Schemas,
 }
 
 impl AggregateCommandWorker {
@@ -27,37 +42,117 @@ impl AggregateCommandWorker {
         context: Context,
         interest: InterestDeclaration,
         state: EntityState,
+        schemas: Schemas,
     ) -> Self {
         AggregateCommandWorker {
             nc,
             context,
             interest,
             state,
+            schemas,
         }
     }
-}
 
-#[async_trait::async_trait]
-impl Worker for AggregateCommandWorker {
-    type Message = RawCommand;
+    /// Moves a command that can never be handled successfully to the dead letter stream instead of retrying it
+    async fn reject(
+        &self,
+        mut message: AckableMessage<RawCommand>,
+        reason: RejectionReason,
+        error: String,
+    ) -> WorkResult<CommandOutcome> {
+        if message.quarantine(&self.interest, error.clone()).await {
+            Ok(CommandOutcome::Rejected(reason, error))
+        } else {
+            Err(WorkError::Other(error))
+        }
+    }
 
-    /// Commands always go to aggregates, and their topic filters are always explicit to one topic, so we
-    /// don't need as much ceremony around interest-based dispatch for commands as we do for events
-    #[instrument(level = "debug", skip_all, fields(entity_name = self.interest.entity_name))]
-    async fn do_work(&self, mut message: AckableMessage<Self::Message>) -> WorkResult<()> {
-        debug!(command = ?message.as_ref(), "Handling received command");
+    /// Drops a command the aggregate refused, since handling it again would only get it refused again. The
+    /// command is moved to the dead letter stream instead when the link asks for refused commands to be kept
+    async fn refuse(
+        &self,
+        mut message: AckableMessage<RawCommand>,
+        error: String,
+    ) -> WorkResult<CommandOutcome> {
+        if self.interest.extract_dead_letter_refusals() {
+            return self.reject(message, RejectionReason::Refused, error).await;
+        }
+        info!(key = message.key, "{error}");
+        message.ack().await.map_err(WorkError::NatsError)?;
+        Ok(CommandOutcome::Rejected(RejectionReason::Refused, error))
+    }
 
-        let state = self
+    /// Hands a command to the aggregate and publishes the events it produces, acking the command once they are
+    /// all published
+    async fn handle_command(
+        &self,
+        mut message: AckableMessage<RawCommand>,
+    ) -> WorkResult<CommandOutcome> {
+        // A command published without an id is identified by its position in the command stream, so that every
+        // attempt at handling it gives its events the same causation
+        if message.id.is_none() {
+            message.id = message.stream_sequence().map(|sequence| {
+                format!(
+                    "{}.{sequence}",
+                    self.interest.namespace.command_stream_name()
+                )
+            });
+        }
+        if let Err(e) = self.schemas.validate_command(&message) {
+            return self
+                .reject(message, RejectionReason::Invalid, e.to_string())
+                .await;
+        }
+
+        // With keyed subjects, note the entity's last event before handling the command so that publishing
+        // fails fast if another command for the same key produced events in the meantime
+        let last_event = if self.interest.namespace.keyed_events() {
+            Some(
+                last_event_sequence(
+                    &self.context,
+                    &self.interest.namespace,
+                    &self.interest.entity_name,
+                    &message.key,
+                )
+                .await
+                .map_err(|e| {
+                    WorkError::NatsError(format!("Failed to load last event: {e}").into())
+                })?,
+            )
+        } else {
+            None
+        };
+        // Only servers from 2.11 on can check a published event against the entity's last event
+        let mut expected = last_event
+            .clone()
+            .filter(|_| self.nc.is_server_compatible(2, 11, 0));
+
+        let current = self
             .state
-            .fetch_state(
+            .fetch_versioned_state(
                 &self.interest.role,
                 &self.interest.entity_name,
                 &message.key,
             )
             .await
             .map_err(|e| WorkError::NatsError(format!("Failed to load state: {e}").into()))?;
+        let needs_rehydration = needs_rehydration(last_event.as_ref(), &current);
+        let mut state = current.data;
         if let Some(ref vec) = state {
             trace!("Loaded pre-existing state - {} bytes", vec.len());
+        } else if needs_rehydration {
+            // Either the aggregate's event consumer hasn't applied the entity's events yet, or the state was
+            // lost. Rebuilding from the events covers both without writing the result, since the event consumer
+            // may still be about to apply some of those events
+            state = rehydrate_entity(&self.context, &self.state, &self.interest, &message.key)
+                .await
+                .map_err(|e| WorkError::Other(format!("Failed to rehydrate state: {e}")))?;
+            if state.is_some() {
+                info!(
+                    key = message.key,
+                    "State was missing for an existing entity and has been rebuilt from its events"
+                );
+            }
         }
 
         // NOTE: you can't invoke `for_actor` without an active provider_main. This will even implicitly attempt
@@ -74,49 +169,223 @@ impl Worker for AggregateCommandWorker {
                 ))
             })?,
         };
-        let ctx = wasmbus_rpc::provider::prelude::Context::default();
+        let ctx = rpc_context();
         trace!(
             "Dispatching command {} to {}",
             cmd.command_type,
             self.interest.actor_id
         );
-        let outbound_events = target.handle_command(&ctx, &cmd).await.map_err(|e| {
-            WorkError::Other(format!(
-                "Aggregate {} ({}) failed to handle command, type '{}', key '{}', ({} bytes): {:?}",
-                self.interest.actor_id,
-                cmd.aggregate,
-                cmd.command_type,
-                cmd.key,
-                cmd.payload.len(),
-                e
-            ))
-        })?;
+        let outbound_events = match metrics()
+            .time_invocation(
+                "aggregate_command",
+                &self.interest.entity_name,
+                target.handle_command(&ctx, &cmd),
+            )
+            .await
+        {
+            Ok(events) => events,
+            Err(e) if is_refusal(&e) => {
+                return self
+                    .refuse(
+                        message,
+                        format!(
+                            "Aggregate {} refused command, type '{}', key '{}': {e}",
+                            self.interest.actor_id, cmd.command_type, cmd.key
+                        ),
+                    )
+                    .await;
+            }
+            Err(e) => {
+                return Err(WorkError::Other(format!(
+                    "Aggregate {} ({}) failed to handle command, type '{}', key '{}', ({} bytes): {:?}",
+                    self.interest.actor_id,
+                    cmd.aggregate,
+                    cmd.command_type,
+                    cmd.key,
+                    cmd.payload.len(),
+                    e
+                )));
+            }
+        };
         trace!("Command handler produced {} events", outbound_events.len());
 
         let cmd_type = cmd.command_type.clone();
+        let causation = Causation::for_command(&message);
+
+        // The events are all checked before any are published, since handling the command again would only
+        // produce the same events
+        if let Some(e) = outbound_events
+            .iter()
+            .find_map(|evt| self.schemas.validate_event(evt).err())
+        {
+            return self
+                .reject(
+                    message,
+                    RejectionReason::Failed,
+                    format!(
+                        "Aggregate {} produced an event that can't be published in response to command {cmd_type}: {e}",
+                        self.interest.actor_id
+                    ),
+                )
+                .await;
+        }
 
         // Reminder that aggregates don't modify their own state when processing commands. That can only
         // happen when handling events.
 
         // TODO: check for lease expiration (skip outbound pub if callee timeout would have already expired) - thanks Victor
 
-        for evt in outbound_events {
+        let mut emitted = Vec::with_capacity(outbound_events.len());
+        for (index, evt) in outbound_events.into_iter().enumerate() {
             let evt_type = evt.event_type.clone();
-            if let Err(_e) = publish_es_event(&self.nc, evt)
-                .await
-                .map_err(|e| WorkError::NatsError(e.into()))
+            let mut event = EmittedEvent {
+                event_type: evt.event_type.clone(),
+                stream: evt.stream.clone(),
+                sequence: 0,
+                payload: serde_json::from_slice(&evt.payload).unwrap_or_default(),
+            };
+            // Only events on the aggregate's own stream are checked against its last sequence
+            let expected_for_event = expected
+                .as_mut()
+                .filter(|_| evt.stream == self.interest.entity_name);
+            match publish_es_event(
+                &self.context,
+                &self.interest.namespace,
+                &self.schemas,
+                evt,
+                &cmd.key,
+                Some((&causation, index)),
+                expected_for_event.as_deref(),
+            )
+            .await
             {
-                error!(
-                    "Failed to publish outbound event {evt_type} in response to command {cmd_type}"
-                );
-                message.nack().await;
-                return Ok(());
+                Ok(sequence) => {
+                    // An event the stream already had keeps its earlier sequence
+                    if let Some(expected) = expected_for_event {
+                        expected.sequence = expected.sequence.max(sequence);
+                    }
+                    event.sequence = sequence;
+                    emitted.push(event);
+                }
+                Err(e) => {
+                    message.nack().await;
+                    return Err(WorkError::Other(format!(
+                        "Failed to publish outbound event {evt_type} in response to command {cmd_type}. The aggregate may have been changed concurrently: {e}"
+                    )));
+                }
             }
         }
 
         // Now that the outbound has been processed, ack the inbound (which deletes the command from the work queue CC_COMMANDS stream)
         message.ack().await.map_err(|e| WorkError::NatsError(e))?;
 
-        Ok(())
+        Ok(CommandOutcome::Handled(emitted))
+    }
+}
+
+/// Tells whether an entity without state has events its state should be rebuilt from. A new entity has none,
+/// and an entity whose state was removed by its last event is up to date without state
+fn needs_rehydration(last_event: Option<&ExpectedSequence>, current: &VersionedState) -> bool {
+    match (last_event, current.position) {
+        (Some(last), Some(applied)) => last.sequence > applied.sequence,
+        (Some(last), None) => last.sequence > 0,
+        // Without keyed subjects, a single entity's events can't be found
+        (None, _) => false,
+    }
+}
+
+/// Tells whether an error means the aggregate will never accept the command. An error the aggregate returned
+/// comes back as the error of the invocation's response, whether its command handler refused the command or it
+/// couldn't decode it. Any other failure, such as a timeout or the invocation not reaching the aggregate, may go
+/// away if the command is handled again
+fn is_refusal(e: &RpcError) -> bool {
+    matches!(e, RpcError::Rpc(_) | RpcError::ActorHandler(_))
+}
+
+#[async_trait::async_trait]
+impl Worker for AggregateCommandWorker {
+    type Message = RawCommand;
+
+    fn partition_key(&self, message: &Self::Message) -> Option<String> {
+        Some(message.key.clone())
+    }
+
+    /// Commands always go to aggregates, and their topic filters are always explicit to one topic, so we
+    /// don't need as much ceremony around interest-based dispatch for commands as we do for events
+    #[instrument(level = "debug", skip_all, fields(entity_name = self.interest.entity_name))]
+    async fn do_work(&self, message: AckableMessage<Self::Message>) -> WorkResult<()> {
+        debug!(command = ?message.as_ref(), "Handling received command");
+
+        let reply_to = message.header(REPLY_TO_HEADER);
+        let final_delivery = message.is_final_delivery();
+        let (reply, res) = match self.handle_command(message).await {
+            Ok(CommandOutcome::Handled(events)) => {
+                (Some(CommandReply::Accepted { events }), Ok(()))
+            }
+            Ok(CommandOutcome::Rejected(reason, error)) => {
+                (Some(CommandReply::rejected(reason, error)), Ok(()))
+            }
+            // Unless this was the last delivery the command is handled again, so its outcome isn't known yet
+            Err(e) => (
+                final_delivery
+                    .then(|| CommandReply::rejected(RejectionReason::Failed, e.to_string())),
+                Err(e),
+            ),
+        };
+        // Only commands sent through the gateway have someone waiting on a reply
+        if let (Some(reply), Some(reply_to)) = (reply, reply_to) {
+            reply.send(&self.nc, reply_to).await;
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use wasmbus_rpc::error::RpcError;
+
+    use super::{is_refusal, needs_rehydration};
+    use crate::{
+        events::ExpectedSequence,
+        state::{EventPosition, VersionedState},
+    };
+
+    #[test]
+    fn only_aggregate_errors_are_refusals() {
+        assert!(is_refusal(&RpcError::Rpc(
+            "actor: insufficient funds".to_string()
+        )));
+        assert!(is_refusal(&RpcError::ActorHandler(
+            "insufficient funds".to_string()
+        )));
+        assert!(!is_refusal(&RpcError::Timeout("1s".to_string())));
+        assert!(!is_refusal(&RpcError::Nats("no responders".to_string())));
+        assert!(!is_refusal(&RpcError::Deser(
+            "invalid type: map, expected a sequence".to_string()
+        )));
+    }
+
+    #[test]
+    fn only_entities_with_unapplied_events_are_rehydrated() {
+        let last_event = |sequence| ExpectedSequence {
+            filter: "cc.events.bankaccount.ACT123.*".to_string(),
+            sequence,
+        };
+        let removed_at = |sequence| VersionedState {
+            revision: 4,
+            position: Some(EventPosition { sequence, count: 3 }),
+            ..Default::default()
+        };
+        let missing = VersionedState::default();
+
+        // A new entity
+        assert!(!needs_rehydration(Some(&last_event(0)), &missing));
+        // Events that haven't been applied yet, or state that was lost
+        assert!(needs_rehydration(Some(&last_event(12)), &missing));
+        // State removed by the entity's last event
+        assert!(!needs_rehydration(Some(&last_event(12)), &removed_at(12)));
+        // State removed by an earlier event
+        assert!(needs_rehydration(Some(&last_event(15)), &removed_at(12)));
+        assert!(!needs_rehydration(None, &missing));
     }
 }
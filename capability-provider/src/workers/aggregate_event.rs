@@ -1,3 +1,5 @@
+use std::future::Future;
+
 use async_nats::jetstream::Context;
 use cloudevents::Event as CloudEvent;
 use tracing::{debug, error, instrument, trace, warn};
@@ -6,16 +8,21 @@ use wasmbus_rpc::error::RpcError;
 use crate::{
     config::InterestDeclaration,
     consumers::WorkError,
+    events::recorded_event,
     eventsourcing::{
         AggregateService, AggregateServiceSender, Event as ConcordanceEvent, EventWithState,
         StateAck,
     },
+    metrics::metrics,
     natsclient::AckableMessage,
-    state::EntityState,
+    otel::rpc_context,
+    state::{ConditionalWrite, EntityState, EventPosition, VersionedState},
 };
 
 use crate::consumers::{WorkResult, Worker};
 
+use super::MAX_STATE_CONFLICT_RETRIES;
+
 pub struct AggregateEventWorker {
     pub nc: async_nats::Client,
     pub context: Context,
@@ -43,99 +50,172 @@ impl AggregateEventWorker {
 impl Worker for AggregateEventWorker {
     type Message = CloudEvent;
 
+    fn partition_key(&self, message: &Self::Message) -> Option<String> {
+        Some(self.interest.extract_key_value_from_event(message)).filter(|k| !k.is_empty())
+    }
+
     #[instrument(level = "debug", skip_all, fields(actor_id = self.interest.actor_id))]
     async fn do_work(&self, mut message: AckableMessage<Self::Message>) -> WorkResult<()> {
         debug!(event = ?message.as_ref(), "Aggregate handling received event");
 
-        let self_id = &self.interest.actor_id;
-        let ce: ConcordanceEvent = message.inner.clone().into();
+        let ce = recorded_event(message.inner.clone(), message.stream_sequence());
         if !self.interest.is_interested_in_event(&ce) {
             trace!(
                 "Aggregate is not interested in event '{}' on stream '{}'. Acking and moving on.",
                 ce.event_type,
                 ce.stream
             );
-            message.ack().await.map_err(|e| WorkError::NatsError(e))?;
+            message.skip().await.map_err(WorkError::NatsError)?;
             return Ok(());
         }
         let evt_payload: serde_json::Value =
             serde_json::from_slice(&ce.payload).unwrap_or_default();
         let key = self.interest.extract_key_value_from_payload(&evt_payload);
-        let state = if !key.is_empty() {
-            self.state
-                .fetch_state(&self.interest.role, &self.interest.entity_name, &key)
-                .await
-                .map_err(|e| {
-                    WorkError::NatsError(
-                        format!("Failed to load state for aggregate {self_id} : {e}").into(),
-                    )
-                })?
-        } else {
+        if key.is_empty() {
             warn!("Key field {} not found on incoming event. This indicates either bad data or potentially side-effectful behavior",
                 &self.interest.key_field);
-            None
-        };
-
-        let ctx = wasmbus_rpc::provider::prelude::Context::default();
-        let target = AggregateServiceSender::for_actor(&self.interest.link_definition);
-        let ews = EventWithState {
-            event: ce.clone(),
-            state: state.clone(),
-        };
-        trace!(
-            "About to apply event {} to target {}",
-            ce.event_type,
-            self.interest.actor_id
-        );
-
-        let state_ack = target.apply_event(&ctx, &ews).await;
-        // Failures will result in a message nack
-        self.adjust_state(&mut message, state_ack, &key).await?;
+        }
 
-        Ok(())
+        let ctx = &rpc_context();
+        let target = &AggregateServiceSender::for_actor(&self.interest.link_definition);
+        self.apply_to_state(&mut message, &ce, &key, |ews| async move {
+            metrics()
+                .time_invocation(
+                    "aggregate_event",
+                    &self.interest.entity_name,
+                    target.apply_event(ctx, &ews),
+                )
+                .await
+        })
+        .await
     }
 }
 
 impl AggregateEventWorker {
+    /// Applies an event to the latest state of its entity with `apply`, applying it again if the state changed
+    /// in the meantime. An event the state already includes, such as a redelivery after a lost ack or an event
+    /// another instance applied first, is acked without being applied again
+    async fn apply_to_state<F, Fut>(
+        &self,
+        message: &mut AckableMessage<CloudEvent>,
+        ce: &ConcordanceEvent,
+        key: &str,
+        apply: F,
+    ) -> WorkResult<()>
+    where
+        F: Fn(EventWithState) -> Fut + Send + Sync,
+        Fut: Future<Output = Result<StateAck, RpcError>> + Send,
+    {
+        let self_id = &self.interest.actor_id;
+        for attempt in 1..=MAX_STATE_CONFLICT_RETRIES {
+            let current = if !key.is_empty() {
+                self.state
+                    .fetch_versioned_state(&self.interest.role, &self.interest.entity_name, key)
+                    .await
+                    .map_err(|e| {
+                        WorkError::NatsError(
+                            format!("Failed to load state for aggregate {self_id} : {e}").into(),
+                        )
+                    })?
+            } else {
+                VersionedState::default()
+            };
+
+            // Read before the message is acked, which consumes its delivery metadata
+            let sequence = message.stream_sequence();
+            if let (Some(applied), Some(sequence)) = (current.position, sequence) {
+                if applied.sequence >= sequence {
+                    debug!(
+                        sequence,
+                        applied = applied.sequence,
+                        "Aggregate state already includes event. Acking and moving on."
+                    );
+                    message.ack().await.map_err(WorkError::NatsError)?;
+                    return Ok(());
+                }
+            }
+            let position = sequence.map(|sequence| EventPosition::next(current.position, sequence));
+            let ews = EventWithState {
+                event: ce.clone(),
+                state: current.data,
+            };
+            trace!(
+                "About to apply event {} to target {}",
+                ce.event_type,
+                self.interest.actor_id
+            );
+
+            let state_ack = apply(ews).await;
+            // Failures will result in a message nack and an error
+            if !self
+                .adjust_state(message, state_ack, key, current.revision, position)
+                .await?
+            {
+                return Ok(());
+            }
+            debug!(
+                attempt,
+                "Aggregate state changed while applying event, applying again to the latest state"
+            );
+        }
+
+        error!(
+            "Gave up applying event after {MAX_STATE_CONFLICT_RETRIES} conflicting state writes"
+        );
+        message.nack().await;
+        Err(WorkError::Other(format!(
+            "State for aggregate {self_id} key {key} kept changing while applying event"
+        )))
+    }
+
+    /// Persists or removes state according to the aggregate's response, acking or nacking the message. Returns
+    /// `true` if the state was changed since it was read, in which case the event should be applied again
     async fn adjust_state(
         &self,
         msg: &mut AckableMessage<CloudEvent>,
         state_ack: Result<StateAck, RpcError>,
         key: &str,
-    ) -> WorkResult<()> {
+        revision: u64,
+        position: Option<EventPosition>,
+    ) -> WorkResult<bool> {
         match state_ack {
             Ok(StateAck {
                 succeeded: true,
                 state: Some(s),
                 ..
-            }) => self.save_state(msg, key, s).await?,
+            }) => self.save_state(msg, key, s, revision, position).await,
             Ok(StateAck {
                 succeeded: true,
                 state: None,
                 ..
-            }) => self.remove_state(msg, key).await?,
+            }) => self.remove_state(msg, key, revision, position).await,
             Ok(StateAck {
                 succeeded: false,
                 error: Some(e),
                 ..
             }) => {
-                error!("Failed to apply event to target actor: {e}");
                 msg.nack().await;
+                Err(WorkError::Other(format!(
+                    "Failed to apply event to target actor: {e}"
+                )))
             }
             Ok(StateAck {
                 succeeded: false,
                 error: None,
                 ..
             }) => {
-                error!("Failed to apply event to target actor: unspecified error");
                 msg.nack().await;
+                Err(WorkError::Other(
+                    "Failed to apply event to target actor: unspecified error".to_string(),
+                ))
             }
             Err(e) => {
-                error!("Failed to apply event to target actor: {e}");
                 msg.nack().await;
+                Err(WorkError::Other(format!(
+                    "Failed to apply event to target actor: {e}"
+                )))
             }
         }
-        Ok(())
     }
 
     async fn save_state(
@@ -143,55 +223,191 @@ impl AggregateEventWorker {
         msg: &mut AckableMessage<CloudEvent>,
         key: &str,
         data: Vec<u8>,
-    ) -> WorkResult<()> {
+        revision: u64,
+        position: Option<EventPosition>,
+    ) -> WorkResult<bool> {
         if key.is_empty() {
-            return Ok(());
+            return Ok(false);
         }
 
         let self_id = &self.interest.actor_id;
+        let snapshot = position
+            .filter(|p| {
+                self.interest
+                    .extract_snapshot_interval()
+                    .map_or(false, |interval| p.count % interval == 0)
+            })
+            .map(|p| (data.clone(), p));
         match self
             .state
-            .write_state(&self.interest.role, &self.interest.entity_name, key, data)
+            .write_state_at_revision(
+                &self.interest.role,
+                &self.interest.entity_name,
+                key,
+                data,
+                revision,
+                position,
+            )
             .await
         {
-            Ok(_) => {
+            Ok(ConditionalWrite::Written(_)) => {
+                if let Some((data, position)) = snapshot {
+                    // Snapshots only speed up replays, so failing to write one doesn't fail the event
+                    if let Err(e) = self
+                        .state
+                        .write_snapshot(&self.interest.entity_name, key, data, position)
+                        .await
+                    {
+                        warn!("Failed to write snapshot of aggregate {self_id} state: {e}");
+                    }
+                }
                 trace!("Aggregate {self_id} state written. Acknowledging event.");
                 msg.ack().await.map_err(|e| WorkError::NatsError(e))?;
             }
+            Ok(ConditionalWrite::Conflict) => return Ok(true),
             Err(e) => {
-                error!(
-                    "Failed to write state after event application, aggregate is now outdated: {e}"
-                );
                 msg.nack().await;
+                return Err(WorkError::NatsError(
+                    format!(
+                        "Failed to write state after event application, aggregate is now outdated: {e}"
+                    )
+                    .into(),
+                ));
             }
         }
 
-        Ok(())
+        Ok(false)
     }
 
     async fn remove_state(
         &self,
         msg: &mut AckableMessage<CloudEvent>,
         key: &str,
-    ) -> WorkResult<()> {
+        revision: u64,
+        position: Option<EventPosition>,
+    ) -> WorkResult<bool> {
         if key.is_empty() {
-            return Ok(());
+            return Ok(false);
         }
         let self_id = &self.interest.actor_id;
         match self
             .state
-            .remove_state(&self.interest.role, &self.interest.entity_name, key)
+            .remove_state_at_revision(
+                &self.interest.role,
+                &self.interest.entity_name,
+                key,
+                revision,
+                position,
+            )
             .await
         {
-            Ok(_) => {
+            Ok(ConditionalWrite::Written(_)) => {
                 trace!("Aggregate {self_id} state deleted.");
                 msg.ack().await.map_err(|e| WorkError::NatsError(e))?;
             }
+            Ok(ConditionalWrite::Conflict) => return Ok(true),
             Err(e) => {
-                error!("Failed to delete aggregate {self_id} state: {e}");
                 msg.nack().await;
+                return Err(WorkError::NatsError(
+                    format!("Failed to delete aggregate {self_id} state: {e}").into(),
+                ));
             }
         }
-        Ok(())
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cloudevents::Event as CloudEvent;
+    use serde_json::json;
+    use wasmbus_rpc::core::LinkDefinition;
+
+    use super::AggregateEventWorker;
+    use crate::{
+        config::{BucketSettings, InterestDeclaration},
+        events::recorded_event,
+        eventsourcing::{Event as ConcordanceEvent, StateAck},
+        natsclient::{
+            test::{clear_streams, create_js_context},
+            AckableMessage, Namespace,
+        },
+        state::{EntityState, EventPosition},
+    };
+
+    #[tokio::test]
+    async fn redelivered_event_is_applied_once() {
+        let nc = async_nats::connect("127.0.0.1").await.unwrap();
+        let js = create_js_context().await;
+        clear_streams(js.clone()).await;
+
+        let state = EntityState::new_from_context(
+            &js,
+            &Namespace::default(),
+            &BucketSettings::default(),
+            &BucketSettings::default(),
+        )
+        .await
+        .unwrap();
+        let interest = InterestDeclaration::aggregate_for_events(
+            "MXBOB",
+            "bankaccount",
+            "account_number",
+            LinkDefinition::default(),
+        );
+        let worker = AggregateEventWorker::new(nc, js.clone(), interest.clone(), state.clone());
+
+        // Counts the events applied to the state
+        let deposit = |sequence: u64| {
+            let event = ConcordanceEvent {
+                event_type: "funds_deposited".to_string(),
+                stream: "bankaccount".to_string(),
+                payload: br#"{"account_number": "ACCT1", "amount": 200}"#.to_vec(),
+                metadata: None,
+            };
+            AckableMessage::<CloudEvent>::detached(event.into(), sequence)
+        };
+        let apply = |ews: crate::eventsourcing::EventWithState| async move {
+            let applied = ews
+                .state
+                .map(|s| {
+                    serde_json::from_slice::<serde_json::Value>(&s).unwrap()["applied"]
+                        .as_u64()
+                        .unwrap()
+                })
+                .unwrap_or_default();
+            Ok(StateAck {
+                succeeded: true,
+                error: None,
+                state: Some(serde_json::to_vec(&json!({ "applied": applied + 1 })).unwrap()),
+            })
+        };
+
+        for sequence in [5, 5, 6, 6] {
+            let mut message = deposit(sequence);
+            let ce = recorded_event(message.inner.clone(), Some(sequence));
+            worker
+                .apply_to_state(&mut message, &ce, "ACCT1", apply)
+                .await
+                .unwrap();
+        }
+
+        let current = state
+            .fetch_versioned_state(&interest.role, &interest.entity_name, "ACCT1")
+            .await
+            .unwrap();
+        assert_eq!(
+            json!({"applied": 2}),
+            serde_json::from_slice::<serde_json::Value>(&current.data.unwrap()).unwrap()
+        );
+        assert_eq!(
+            Some(EventPosition {
+                sequence: 6,
+                count: 2
+            }),
+            current.position
+        );
+
+        clear_streams(js).await;
     }
 }
@@ -1,13 +1,11 @@
 use async_nats::jetstream::Context;
 use cloudevents::Event as CloudEvent;
-use tracing::{debug, error, instrument, trace};
+use tracing::{debug, instrument, trace};
 
-use crate::eventsourcing::{
-    Event as ConcordanceEvent, StatelessEventHandlerService, StatelessEventHandlerServiceSender,
-};
+use crate::eventsourcing::{StatelessEventHandlerService, StatelessEventHandlerServiceSender};
 use crate::{
-    config::InterestDeclaration, consumers::WorkError, natsclient::AckableMessage,
-    state::EntityState,
+    config::InterestDeclaration, consumers::WorkError, events::recorded_event, metrics::metrics,
+    natsclient::AckableMessage, otel::rpc_context, state::EntityState,
 };
 
 use crate::consumers::{WorkResult, Worker};
@@ -39,15 +37,20 @@ impl GeneralEventWorker {
 impl Worker for GeneralEventWorker {
     type Message = CloudEvent;
 
+    fn partition_key(&self, message: &Self::Message) -> Option<String> {
+        Some(self.interest.extract_key_value_from_event(message)).filter(|k| !k.is_empty())
+    }
+
     /// Performs the work necessary to process an incoming concordance event and deliver it to the target actor
-    /// nacks upon actor failure, acks upon actor success. No state is managed by the provider on behalf of
+    /// nacks upon actor failure, acks upon actor success. An actor that reports a permanent failure has the event
+    /// terminated so it goes straight to the dead letter stream. No state is managed by the provider on behalf of
     /// general event workers
     #[instrument(level = "debug", skip_all, fields(actor_id = self.interest.actor_id))]
     async fn do_work(&self, mut message: AckableMessage<Self::Message>) -> WorkResult<()> {
         debug!(event = ?message.as_ref(), "Handling received event");
 
-        // note the 'into' here converts from CloudEvent to ConcordanceEvent
-        let ce: ConcordanceEvent = message.inner.clone().into();
+        // converts from CloudEvent to ConcordanceEvent, along with the event's metadata
+        let ce = recorded_event(message.inner.clone(), message.stream_sequence());
         let self_id = self.interest.actor_id.to_string();
 
         if !self.interest.is_interested_in_event(&ce) {
@@ -56,19 +59,41 @@ impl Worker for GeneralEventWorker {
                 ce.event_type,
                 ce.stream
             );
-            message.ack().await.map_err(|e| WorkError::NatsError(e))?;
+            message.skip().await.map_err(WorkError::NatsError)?;
             return Ok(());
         }
 
-        let ctx = wasmbus_rpc::provider::prelude::Context::default();
+        let ctx = rpc_context();
         let target = StatelessEventHandlerServiceSender::for_actor(&self.interest.link_definition);
-        match target.apply_stateless_event(&ctx, &ce).await {
-            Ok(_) => {
+        let ack = metrics()
+            .time_invocation(
+                "general_event",
+                &self.interest.entity_name,
+                target.apply_stateless_event(&ctx, &ce),
+            )
+            .await;
+        match ack {
+            Ok(ack) if ack.succeeded => {
                 message.ack().await.map_err(|e| WorkError::NatsError(e))?;
             }
+            Ok(ack) => {
+                let reason = ack.error.unwrap_or_else(|| "no error given".to_string());
+                if ack.permanent.unwrap_or(false) {
+                    message.term().await;
+                    return Err(WorkError::Permanent(format!(
+                        "General event handler {self_id} can't apply event: {reason}"
+                    )));
+                }
+                message.nack().await;
+                return Err(WorkError::Other(format!(
+                    "General event handler {self_id} failed to apply event: {reason}"
+                )));
+            }
             Err(e) => {
-                error!("Failed to apply event to general event handler {self_id}: {e}");
                 message.nack().await;
+                return Err(WorkError::Other(format!(
+                    "Failed to apply event to general event handler {self_id}: {e}"
+                )));
             }
         };
 
@@ -5,6 +5,10 @@ mod notifier;
 mod process_manager;
 mod projector;
 
+/// Number of times an event is applied again when the entity's state was changed by someone else between
+/// reading it and writing the result
+const MAX_STATE_CONFLICT_RETRIES: usize = 5;
+
 pub use aggregate_command::AggregateCommandWorker;
 pub use aggregate_event::AggregateEventWorker;
 pub use general_event::GeneralEventWorker;
@@ -5,17 +5,20 @@ use tracing::{debug, error, trace, warn};
 use crate::{
     config::{ActorInterest, InterestDeclaration},
     consumers::{RawCommand, WorkError},
-    events::publish_raw_command,
+    events::{publish_raw_command, recorded_event, Causation},
     eventsourcing::{
-        Event as ConcordanceEvent, EventWithState, ProcessManagerAck, ProcessManagerService,
-        ProcessManagerServiceSender,
+        EventWithState, ProcessManagerAck, ProcessManagerService, ProcessManagerServiceSender,
     },
+    metrics::metrics,
     natsclient::AckableMessage,
-    state::EntityState,
+    otel::rpc_context,
+    state::{ConditionalWrite, EntityState, VersionedState},
 };
 
 use crate::consumers::{WorkResult, Worker};
 
+use super::MAX_STATE_CONFLICT_RETRIES;
+
 pub struct ProcessManagerWorker {
     pub nc: async_nats::Client,
     pub context: Context,
@@ -43,80 +46,113 @@ impl ProcessManagerWorker {
 impl Worker for ProcessManagerWorker {
     type Message = CloudEvent;
 
+    fn partition_key(&self, message: &Self::Message) -> Option<String> {
+        Some(self.interest.extract_key_value_from_event(message)).filter(|k| !k.is_empty())
+    }
+
     /// Ingest a cloud event, dispatch to the target actor, and dispatch the returned list
     /// of commands. State is passed in with the event and state is persisted/removed based
-    /// on the ack from the process manager
+    /// on the ack from the process manager. Commands are sent before the state is persisted, so
+    /// the state never moves past an event whose commands weren't sent. Handling the event again,
+    /// whether it is redelivered or the state was changed concurrently, issues commands with the
+    /// same ids, which the command stream drops as duplicates
     async fn do_work(&self, mut message: AckableMessage<Self::Message>) -> WorkResult<()> {
         debug!(event = ?message.as_ref(), "Process manager handling received event");
 
         let self_id = &self.interest.actor_id;
-        let ce: ConcordanceEvent = message.inner.clone().into();
+        let ce = recorded_event(message.inner.clone(), message.stream_sequence());
         if !self.interest.is_interested_in_event(&ce) {
-            // at the moment we can't declare per-event subscriptions in NATS, so PM consumers listen to
-            // all events, so we should silently ack them
-            message.ack().await.map_err(|e| WorkError::NatsError(e))?;
+            // PM consumers filter by event type on the server, but durables created before filtering was
+            // introduced still receive all events, so we should silently ack the uninteresting ones
+            message.skip().await.map_err(WorkError::NatsError)?;
             return Ok(());
         }
 
         let evt_payload: serde_json::Value =
             serde_json::from_slice(&ce.payload).unwrap_or_default();
         let key = self.interest.extract_key_value_from_payload(&evt_payload);
+        if key.is_empty() {
+            warn!("Key field {} not found on incoming event. This indicates either bad data or potentially side-effectful behavior",
+            &self.interest.key_field);
+        }
+        let starts_new_process = matches!(
+            &self.interest.interest,
+            ActorInterest::ProcessManager(pm_life) if pm_life.event_starts_new_process(&ce.event_type)
+        );
 
-        let state = if !key.is_empty() {
-            match &self.interest.interest {
-                ActorInterest::ProcessManager(pm_life)
-                    if pm_life.event_starts_new_process(&ce.event_type) =>
-                {
-                    // Never deliver state to a process manager on its lifetime-start event
-                    None
-                }
-                _ => self
-                    .state
-                    .fetch_state(&self.interest.role, &self.interest.entity_name, &key)
+        let target = ProcessManagerServiceSender::for_actor(&self.interest.link_definition);
+        let ctx = rpc_context();
+        for attempt in 1..=MAX_STATE_CONFLICT_RETRIES {
+            // The revision is needed even on the lifetime-start event so the new state can be written safely
+            let current = if !key.is_empty() {
+                self.state
+                    .fetch_versioned_state(&self.interest.role, &self.interest.entity_name, &key)
                     .await
                     .map_err(|e| {
                         WorkError::NatsError(format!("Failed to load state: {e}").into())
-                    })?,
+                    })?
+            } else {
+                VersionedState::default()
+            };
+            // Never deliver state to a process manager on its lifetime-start event
+            let state = if starts_new_process {
+                None
+            } else {
+                current.data
+            };
+
+            if let Some(ref vec) = state {
+                trace!("Loaded pre-existing state - {} bytes", vec.len());
+            }
+
+            trace!(
+                "Dispatching event '{}' to process manager '{}'",
+                ce.event_type,
+                self.interest.actor_id
+            );
+            let inbound_event = EventWithState {
+                event: ce.clone(),
+                state,
+            };
+            let pm_ack = metrics()
+                .time_invocation(
+                    "process_manager",
+                    &self.interest.entity_name,
+                    target.handle_event(&ctx, &inbound_event),
+                )
+                .await
+                .map_err(|e| {
+                    WorkError::Other(format!(
+                        "Process manager {} failed to process event {}: {}",
+                        self_id, ce.event_type, e
+                    ))
+                })?;
+
+            // These will nack upon failure and return Err, so the following ack will
+            // never get called
+            self.dispatch_commands(&mut message, &pm_ack).await?;
+            if self
+                .save_state(&mut message, &pm_ack, &key, current.revision)
+                .await?
+            {
+                debug!(
+                    attempt,
+                    "Process manager state changed while handling event, handling again with the latest state"
+                );
+                continue;
             }
-        } else {
-            warn!("Key field {} not found on incoming event. This indicates either bad data or potentially side-effectful behavior",
-            &self.interest.key_field);
-            None
-        };
 
-        if let Some(ref vec) = state {
-            trace!("Loaded pre-existing state - {} bytes", vec.len());
+            message.ack().await.map_err(WorkError::NatsError)?;
+            return Ok(());
         }
 
-        let target = ProcessManagerServiceSender::for_actor(&self.interest.link_definition);
-        let ctx = wasmbus_rpc::provider::prelude::Context::default();
-        trace!(
-            "Dispatching event '{}' to process manager '{}'",
-            ce.event_type,
-            self.interest.actor_id
+        error!(
+            "Gave up handling event after {MAX_STATE_CONFLICT_RETRIES} conflicting state writes"
         );
-        let inbound_event = EventWithState {
-            event: ce.clone(),
-            state,
-        };
-        let pm_ack = target
-            .handle_event(&ctx, &inbound_event)
-            .await
-            .map_err(|e| {
-                WorkError::Other(format!(
-                    "Process manager {} failed to process event {}: {}",
-                    self_id, ce.event_type, e
-                ))
-            })?;
-
-        // These will nack upon failure and return Err, so the following ack will
-        // never get called
-        self.dispatch_commands(&mut message, &pm_ack).await?;
-        self.save_state(&mut message, &pm_ack, &key).await?;
-
-        message.ack().await.map_err(|e| WorkError::NatsError(e))?;
-
-        Ok(())
+        message.nack().await;
+        Err(WorkError::Other(format!(
+            "State for process manager {self_id} key {key} kept changing while handling event"
+        )))
     }
 }
 
@@ -126,13 +162,25 @@ impl ProcessManagerWorker {
         msg: &mut AckableMessage<CloudEvent>,
         ack: &ProcessManagerAck,
     ) -> WorkResult<()> {
-        for cmd in &ack.commands {
+        // Commands carry on the saga of the event that caused them
+        let causation = Causation::for_event(&msg.inner);
+        for (index, cmd) in ack.commands.iter().enumerate() {
             let rawcmd = RawCommand {
                 command_type: cmd.command_type.to_string(),
                 key: cmd.aggregate_key.to_string(),
                 data: serde_json::from_slice(&cmd.json_payload).unwrap_or_default(),
+                id: Some(causation.issued_command_id(index)),
+                correlation_id: Some(causation.correlation_id.clone()),
+                causation_id: Some(causation.causation_id.clone()),
             };
-            if let Err(e) = publish_raw_command(&self.nc, rawcmd, &cmd.aggregate_stream).await {
+            if let Err(e) = publish_raw_command(
+                &self.nc,
+                &self.interest.namespace,
+                rawcmd,
+                &cmd.aggregate_stream,
+            )
+            .await
+            {
                 msg.nack().await;
                 return Err(WorkError::NatsError(e.into()));
             }
@@ -140,45 +188,65 @@ impl ProcessManagerWorker {
         Ok(())
     }
 
+    /// Persists or removes state according to the process manager's ack. Returns `true` if the state was
+    /// changed since it was read, in which case the event should be handled again
     async fn save_state(
         &self,
         msg: &mut AckableMessage<CloudEvent>,
         ack: &ProcessManagerAck,
         key: &str,
-    ) -> WorkResult<()> {
+        revision: u64,
+    ) -> WorkResult<bool> {
         let self_id = self.interest.actor_id.clone();
 
         if let Some(state) = ack.state.clone() {
             match self
                 .state
-                .write_state(&self.interest.role, &self.interest.entity_name, key, state)
+                .write_state_at_revision(
+                    &self.interest.role,
+                    &self.interest.entity_name,
+                    key,
+                    state,
+                    revision,
+                    None,
+                )
                 .await
             {
-                Ok(_) => {
+                Ok(ConditionalWrite::Written(_)) => {
                     trace!("Process manager {self_id} state written.");
                 }
+                Ok(ConditionalWrite::Conflict) => return Ok(true),
                 Err(e) => {
-                    error!("Failed to write state after event application, aggregate is now outdated: {e}");
                     msg.nack().await;
-                    return Err(WorkError::NatsError(e.into()));
+                    return Err(WorkError::NatsError(
+                        format!("Failed to write state after event application, process manager is now outdated: {e}").into(),
+                    ));
                 }
             }
         } else {
             match self
                 .state
-                .remove_state(&self.interest.role, &self.interest.entity_name, key)
+                .remove_state_at_revision(
+                    &self.interest.role,
+                    &self.interest.entity_name,
+                    key,
+                    revision,
+                    None,
+                )
                 .await
             {
-                Ok(_) => {
+                Ok(ConditionalWrite::Written(_)) => {
                     trace!("Process manager {self_id} state deleted on demand.");
                 }
+                Ok(ConditionalWrite::Conflict) => return Ok(true),
                 Err(e) => {
-                    error!("Failed to delete process manager {self_id} state: {e}");
                     msg.nack().await;
-                    return Err(WorkError::NatsError(e.into()));
+                    return Err(WorkError::NatsError(
+                        format!("Failed to delete process manager {self_id} state: {e}").into(),
+                    ));
                 }
             }
         }
-        Ok(())
+        Ok(false)
     }
 }
@@ -77,6 +77,10 @@ pub struct Event {
     #[serde(rename = "eventType")]
     #[serde(default)]
     pub event_type: String,
+    /// Describes the event as it was recorded in the event stream. Set on every event handed to an actor,
+    /// and ignored on the events an aggregate produces
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<EventMetadata>,
     #[serde(with = "serde_bytes")]
     #[serde(default)]
     pub payload: Vec<u8>,
@@ -94,9 +98,15 @@ pub fn encode_event<W: wasmbus_rpc::cbor::Write>(
 where
     <W as wasmbus_rpc::cbor::Write>::Error: std::fmt::Display,
 {
-    e.map(3)?;
+    e.map(4)?;
     e.str("eventType")?;
     e.str(&val.event_type)?;
+    if let Some(val) = val.metadata.as_ref() {
+        e.str("metadata")?;
+        encode_event_metadata(e, val)?;
+    } else {
+        e.null()?;
+    }
     e.str("payload")?;
     e.bytes(&val.payload)?;
     e.str("stream")?;
@@ -109,6 +119,7 @@ where
 pub fn decode_event(d: &mut wasmbus_rpc::cbor::Decoder<'_>) -> Result<Event, RpcError> {
     let __result = {
         let mut event_type: Option<String> = None;
+        let mut metadata: Option<Option<EventMetadata>> = Some(None);
         let mut payload: Option<Vec<u8>> = None;
         let mut stream: Option<String> = None;
 
@@ -126,8 +137,21 @@ pub fn decode_event(d: &mut wasmbus_rpc::cbor::Decoder<'_>) -> Result<Event, Rpc
             for __i in 0..(len as usize) {
                 match __i {
                     0 => event_type = Some(d.str()?.to_string()),
-                    1 => payload = Some(d.bytes()?.to_vec()),
-                    2 => stream = Some(d.str()?.to_string()),
+                    1 => {
+                        metadata = if wasmbus_rpc::cbor::Type::Null == d.datatype()? {
+                            d.skip()?;
+                            Some(None)
+                        } else {
+                            Some(Some(decode_event_metadata(d).map_err(|e| {
+                                format!(
+                                    "decoding 'com.cosmonic.eventsourcing#EventMetadata': {}",
+                                    e
+                                )
+                            })?))
+                        }
+                    }
+                    2 => payload = Some(d.bytes()?.to_vec()),
+                    3 => stream = Some(d.str()?.to_string()),
                     _ => d.skip()?,
                 }
             }
@@ -136,6 +160,19 @@ pub fn decode_event(d: &mut wasmbus_rpc::cbor::Decoder<'_>) -> Result<Event, Rpc
             for __i in 0..(len as usize) {
                 match d.str()? {
                     "eventType" => event_type = Some(d.str()?.to_string()),
+                    "metadata" => {
+                        metadata = if wasmbus_rpc::cbor::Type::Null == d.datatype()? {
+                            d.skip()?;
+                            Some(None)
+                        } else {
+                            Some(Some(decode_event_metadata(d).map_err(|e| {
+                                format!(
+                                    "decoding 'com.cosmonic.eventsourcing#EventMetadata': {}",
+                                    e
+                                )
+                            })?))
+                        }
+                    }
                     "payload" => payload = Some(d.bytes()?.to_vec()),
                     "stream" => stream = Some(d.str()?.to_string()),
                     _ => d.skip()?,
@@ -150,12 +187,13 @@ pub fn decode_event(d: &mut wasmbus_rpc::cbor::Decoder<'_>) -> Result<Event, Rpc
                     "missing field Event.event_type (#0)".to_string(),
                 ));
             },
+            metadata: metadata.unwrap(),
 
             payload: if let Some(__x) = payload {
                 __x
             } else {
                 return Err(RpcError::Deser(
-                    "missing field Event.payload (#1)".to_string(),
+                    "missing field Event.payload (#2)".to_string(),
                 ));
             },
 
@@ -163,7 +201,7 @@ pub fn decode_event(d: &mut wasmbus_rpc::cbor::Decoder<'_>) -> Result<Event, Rpc
                 __x
             } else {
                 return Err(RpcError::Deser(
-                    "missing field Event.stream (#2)".to_string(),
+                    "missing field Event.stream (#3)".to_string(),
                 ));
             },
         }
@@ -220,6 +258,203 @@ pub fn decode_event_list(d: &mut wasmbus_rpc::cbor::Decoder<'_>) -> Result<Event
     Ok(__result)
 }
 #[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct EventMetadata {
+    /// Id of the command that caused the event
+    #[serde(rename = "causationId")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub causation_id: Option<String>,
+    /// Identifies the saga the event belongs to
+    #[serde(rename = "correlationId")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    /// Unique id of the event, suitable for detecting events that were already handled
+    #[serde(default)]
+    pub id: String,
+    /// Sequence of the event in the event stream
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<u64>,
+    /// Where the event came from
+    #[serde(default)]
+    pub source: String,
+    /// When the event was published, as an RFC 3339 timestamp
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time: Option<String>,
+}
+
+// Encode EventMetadata as CBOR and append to output stream
+#[doc(hidden)]
+#[allow(unused_mut)]
+pub fn encode_event_metadata<W: wasmbus_rpc::cbor::Write>(
+    mut e: &mut wasmbus_rpc::cbor::Encoder<W>,
+    val: &EventMetadata,
+) -> RpcResult<()>
+where
+    <W as wasmbus_rpc::cbor::Write>::Error: std::fmt::Display,
+{
+    e.map(6)?;
+    if let Some(val) = val.causation_id.as_ref() {
+        e.str("causationId")?;
+        e.str(val)?;
+    } else {
+        e.null()?;
+    }
+    if let Some(val) = val.correlation_id.as_ref() {
+        e.str("correlationId")?;
+        e.str(val)?;
+    } else {
+        e.null()?;
+    }
+    e.str("id")?;
+    e.str(&val.id)?;
+    if let Some(val) = val.sequence.as_ref() {
+        e.str("sequence")?;
+        e.u64(*val)?;
+    } else {
+        e.null()?;
+    }
+    e.str("source")?;
+    e.str(&val.source)?;
+    if let Some(val) = val.time.as_ref() {
+        e.str("time")?;
+        e.str(val)?;
+    } else {
+        e.null()?;
+    }
+    Ok(())
+}
+
+// Decode EventMetadata from cbor input stream
+#[doc(hidden)]
+pub fn decode_event_metadata(
+    d: &mut wasmbus_rpc::cbor::Decoder<'_>,
+) -> Result<EventMetadata, RpcError> {
+    let __result = {
+        let mut causation_id: Option<Option<String>> = Some(None);
+        let mut correlation_id: Option<Option<String>> = Some(None);
+        let mut id: Option<String> = None;
+        let mut sequence: Option<Option<u64>> = Some(None);
+        let mut source: Option<String> = None;
+        let mut time: Option<Option<String>> = Some(None);
+
+        let is_array = match d.datatype()? {
+            wasmbus_rpc::cbor::Type::Array => true,
+            wasmbus_rpc::cbor::Type::Map => false,
+            _ => {
+                return Err(RpcError::Deser(
+                    "decoding struct EventMetadata, expected array or map".to_string(),
+                ))
+            }
+        };
+        if is_array {
+            let len = d.fixed_array()?;
+            for __i in 0..(len as usize) {
+                match __i {
+                    0 => {
+                        causation_id = if wasmbus_rpc::cbor::Type::Null == d.datatype()? {
+                            d.skip()?;
+                            Some(None)
+                        } else {
+                            Some(Some(d.str()?.to_string()))
+                        }
+                    }
+                    1 => {
+                        correlation_id = if wasmbus_rpc::cbor::Type::Null == d.datatype()? {
+                            d.skip()?;
+                            Some(None)
+                        } else {
+                            Some(Some(d.str()?.to_string()))
+                        }
+                    }
+                    2 => id = Some(d.str()?.to_string()),
+                    3 => {
+                        sequence = if wasmbus_rpc::cbor::Type::Null == d.datatype()? {
+                            d.skip()?;
+                            Some(None)
+                        } else {
+                            Some(Some(d.u64()?))
+                        }
+                    }
+                    4 => source = Some(d.str()?.to_string()),
+                    5 => {
+                        time = if wasmbus_rpc::cbor::Type::Null == d.datatype()? {
+                            d.skip()?;
+                            Some(None)
+                        } else {
+                            Some(Some(d.str()?.to_string()))
+                        }
+                    }
+
+                    _ => d.skip()?,
+                }
+            }
+        } else {
+            let len = d.fixed_map()?;
+            for __i in 0..(len as usize) {
+                match d.str()? {
+                    "causationId" => {
+                        causation_id = if wasmbus_rpc::cbor::Type::Null == d.datatype()? {
+                            d.skip()?;
+                            Some(None)
+                        } else {
+                            Some(Some(d.str()?.to_string()))
+                        }
+                    }
+                    "correlationId" => {
+                        correlation_id = if wasmbus_rpc::cbor::Type::Null == d.datatype()? {
+                            d.skip()?;
+                            Some(None)
+                        } else {
+                            Some(Some(d.str()?.to_string()))
+                        }
+                    }
+                    "id" => id = Some(d.str()?.to_string()),
+                    "sequence" => {
+                        sequence = if wasmbus_rpc::cbor::Type::Null == d.datatype()? {
+                            d.skip()?;
+                            Some(None)
+                        } else {
+                            Some(Some(d.u64()?))
+                        }
+                    }
+                    "source" => source = Some(d.str()?.to_string()),
+                    "time" => {
+                        time = if wasmbus_rpc::cbor::Type::Null == d.datatype()? {
+                            d.skip()?;
+                            Some(None)
+                        } else {
+                            Some(Some(d.str()?.to_string()))
+                        }
+                    }
+                    _ => d.skip()?,
+                }
+            }
+        }
+        EventMetadata {
+            causation_id: causation_id.unwrap(),
+            correlation_id: correlation_id.unwrap(),
+
+            id: if let Some(__x) = id {
+                __x
+            } else {
+                return Err(RpcError::Deser(
+                    "missing field EventMetadata.id (#2)".to_string(),
+                ));
+            },
+            sequence: sequence.unwrap(),
+
+            source: if let Some(__x) = source {
+                __x
+            } else {
+                return Err(RpcError::Deser(
+                    "missing field EventMetadata.source (#4)".to_string(),
+                ));
+            },
+            time: time.unwrap(),
+        }
+    };
+    Ok(__result)
+}
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
 pub struct EventWithState {
     pub event: Event,
     #[serde(with = "serde_bytes")]
@@ -810,6 +1045,10 @@ pub struct StatelessAck {
     /// Optional error message
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub error: Option<String>,
+    /// Set on a failed ack when retrying the event can't help. The event is moved straight to the
+    /// dead letter stream instead of being redelivered
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub permanent: Option<bool>,
     #[serde(default)]
     pub succeeded: bool,
 }
@@ -824,13 +1063,19 @@ pub fn encode_stateless_ack<W: wasmbus_rpc::cbor::Write>(
 where
     <W as wasmbus_rpc::cbor::Write>::Error: std::fmt::Display,
 {
-    e.map(2)?;
+    e.map(3)?;
     if let Some(val) = val.error.as_ref() {
         e.str("error")?;
         e.str(val)?;
     } else {
         e.null()?;
     }
+    if let Some(val) = val.permanent.as_ref() {
+        e.str("permanent")?;
+        e.bool(*val)?;
+    } else {
+        e.null()?;
+    }
     e.str("succeeded")?;
     e.bool(val.succeeded)?;
     Ok(())
@@ -843,6 +1088,7 @@ pub fn decode_stateless_ack(
 ) -> Result<StatelessAck, RpcError> {
     let __result = {
         let mut error: Option<Option<String>> = Some(None);
+        let mut permanent: Option<Option<bool>> = Some(None);
         let mut succeeded: Option<bool> = None;
 
         let is_array = match d.datatype()? {
@@ -866,7 +1112,15 @@ pub fn decode_stateless_ack(
                             Some(Some(d.str()?.to_string()))
                         }
                     }
-                    1 => succeeded = Some(d.bool()?),
+                    1 => {
+                        permanent = if wasmbus_rpc::cbor::Type::Null == d.datatype()? {
+                            d.skip()?;
+                            Some(None)
+                        } else {
+                            Some(Some(d.bool()?))
+                        }
+                    }
+                    2 => succeeded = Some(d.bool()?),
                     _ => d.skip()?,
                 }
             }
@@ -882,6 +1136,14 @@ pub fn decode_stateless_ack(
                             Some(Some(d.str()?.to_string()))
                         }
                     }
+                    "permanent" => {
+                        permanent = if wasmbus_rpc::cbor::Type::Null == d.datatype()? {
+                            d.skip()?;
+                            Some(None)
+                        } else {
+                            Some(Some(d.bool()?))
+                        }
+                    }
                     "succeeded" => succeeded = Some(d.bool()?),
                     _ => d.skip()?,
                 }
@@ -889,12 +1151,13 @@ pub fn decode_stateless_ack(
         }
         StatelessAck {
             error: error.unwrap(),
+            permanent: permanent.unwrap(),
 
             succeeded: if let Some(__x) = succeeded {
                 __x
             } else {
                 return Err(RpcError::Deser(
-                    "missing field StatelessAck.succeeded (#1)".to_string(),
+                    "missing field StatelessAck.succeeded (#2)".to_string(),
                 ));
             },
         }
@@ -59,6 +59,7 @@ impl Event {
             event_type: event_type.to_string(),
             stream: stream.to_string(),
             payload: serde_json::to_vec(&payload).unwrap_or_default(),
+            metadata: None,
         }
     }
 }
@@ -91,14 +92,27 @@ impl StatelessAck {
     pub fn ok() -> Self {
         Self {
             error: None,
+            permanent: None,
             succeeded: true,
         }
     }
 
-    /// Indicates a failure to process an inbound entity by a stateless handler
+    /// Indicates a failure to process an inbound entity by a stateless handler. The entity will be
+    /// delivered again
     pub fn error(msg: String) -> Self {
         Self {
             error: Some(msg),
+            permanent: None,
+            succeeded: false,
+        }
+    }
+
+    /// Indicates a failure that retrying won't fix, such as an event the handler can never apply. The
+    /// entity is moved to the dead letter stream without being delivered again
+    pub fn permanent_error(msg: String) -> Self {
+        Self {
+            error: Some(msg),
+            permanent: Some(true),
             succeeded: false,
         }
     }